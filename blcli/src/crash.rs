@@ -0,0 +1,282 @@
+//! Decode the last crash recorded in the boot message, using the app's ELF
+//!
+//! Panics carry their location as text, which we look up in the DWARF line
+//! tables to find the enclosing function. HardFaults carry the stacked
+//! registers, so PC and LR are symbolized directly.
+
+use std::fs;
+
+use addr2line::{
+    gimli::{EndianRcSlice, RunTimeEndian},
+    object::{File as ObjFile, Object},
+    Context,
+};
+use curacao_host::{image_id, BootState, BootloaderClient, FirmwareImage, ImageError};
+use serde::Serialize;
+
+use crate::error::Error;
+
+type Ctx = Context<EndianRcSlice<RunTimeEndian>>;
+
+#[derive(Debug, Serialize)]
+pub struct Frame {
+    pub function: Option<String>,
+    pub file: Option<String>,
+    pub line: Option<u32>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Symbolized {
+    pub reg: &'static str,
+    pub addr: u32,
+    /// Innermost frame first, any inlined callers follow
+    pub frames: Vec<Frame>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PanicLocation {
+    pub file: String,
+    pub line: u32,
+    pub column: Option<u32>,
+    pub function: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ImageCheck {
+    /// GNU build ID from the ELF, if it was linked with one
+    pub build_id: Option<String>,
+    /// ID of the image described by the ELF
+    pub elf_id: String,
+    /// ID of the same range read back from the device
+    pub flashed_id: Option<String>,
+    pub matches: Option<bool>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CrashReport {
+    pub boot: BootState,
+    pub image: ImageCheck,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub panic_location: Option<PanicLocation>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub registers: Vec<Symbolized>,
+}
+
+impl CrashReport {
+    pub fn print_summary(&self) {
+        let img = &self.image;
+        match img.build_id.as_deref() {
+            Some(id) => println!("ELF build ID: {id}"),
+            None => println!("ELF build ID: (none)"),
+        }
+        println!("ELF image ID:     {}", img.elf_id);
+        match (&img.flashed_id, img.matches) {
+            (Some(id), Some(true)) => println!("Flashed image ID: {id} (matches)"),
+            (Some(id), _) => println!("Flashed image ID: {id} (MISMATCH, results may be wrong!)"),
+            (None, _) => println!("Flashed image ID: (not checked)"),
+        }
+
+        match &self.boot {
+            BootState::AppPanicked { uptime, reason } | BootState::BootPanicked { uptime, reason } => {
+                println!("Panicked after {uptime} ticks:");
+                println!("{}", reason.trim_end());
+            }
+            BootState::AppFaulted { uptime, regs } => {
+                println!("HardFault after {uptime} ticks:");
+                println!(
+                    "  PC={:08X} LR={:08X} SP={:08X} xPSR={:08X}",
+                    regs.pc, regs.lr, regs.sp, regs.xpsr
+                );
+                println!(
+                    "  CFSR={:08X} HFSR={:08X} MMFAR={:08X} BFAR={:08X}",
+                    regs.cfsr, regs.hfsr, regs.mmfar, regs.bfar
+                );
+            }
+            other => println!("No crash recorded ({other:?})"),
+        }
+
+        if let Some(loc) = &self.panic_location {
+            print!("  at {}:{}", loc.file, loc.line);
+            if let Some(col) = loc.column {
+                print!(":{col}");
+            }
+            match &loc.function {
+                Some(f) => println!(" in {f}"),
+                None => println!(),
+            }
+        }
+        for reg in self.registers.iter() {
+            println!("  {} = {:08X}", reg.reg, reg.addr);
+            if reg.frames.is_empty() {
+                println!("      ??");
+            }
+            for f in reg.frames.iter() {
+                println!("      {}", f.function.as_deref().unwrap_or("??"));
+                if let (Some(file), Some(line)) = (&f.file, f.line) {
+                    println!("        at {file}:{line}");
+                }
+            }
+        }
+    }
+}
+
+/// Build a crash report from the device's boot message and the ELF at `path`
+///
+/// Unless `verify` is false, the ELF's image range is read back from the
+/// device to check that the ELF matches what is flashed.
+pub async fn decode(bl: &BootloaderClient, path: &str, verify: bool) -> Result<CrashReport, Error> {
+    let elf = fs::read(path)?;
+    let image = FirmwareImage::from_elf(&elf)?;
+    let obj = ObjFile::parse(&*elf).map_err(|e| ImageError::Parse(e.to_string()))?;
+    let ctx = Context::new(&obj).map_err(|e| ImageError::Parse(format!("bad DWARF: {e}")))?;
+
+    let elf_id = image.image_id();
+    let flashed_id = if verify {
+        let data = bl.read(image.base, image.data.len() as u32).await?;
+        Some(image_id(&data))
+    } else {
+        None
+    };
+    let matches = flashed_id.as_ref().map(|id| *id == elf_id);
+
+    let boot: BootState = bl.boot_msg().await?.into();
+    let mut panic_location = None;
+    let mut registers = vec![];
+    match &boot {
+        BootState::AppPanicked { reason, .. } | BootState::BootPanicked { reason, .. } => {
+            panic_location = parse_panic_location(reason).map(|mut loc| {
+                loc.function = function_at_line(&ctx, &image, &loc.file, loc.line);
+                loc
+            });
+        }
+        BootState::AppFaulted { regs, .. } => {
+            // Clear the thumb bit from the return address
+            for (reg, addr) in [("PC", regs.pc), ("LR", regs.lr & !1)] {
+                registers.push(Symbolized {
+                    reg,
+                    addr,
+                    frames: symbolize(&ctx, &obj, addr),
+                });
+            }
+        }
+        _ => {}
+    }
+
+    Ok(CrashReport {
+        boot,
+        image: ImageCheck {
+            build_id: image.build_id_hex(),
+            elf_id,
+            flashed_id,
+            matches,
+        },
+        panic_location,
+        registers,
+    })
+}
+
+/// Parse the location out of a panic message like
+/// `panicked at src/main.rs:12:5:\nmessage`
+fn parse_panic_location(reason: &str) -> Option<PanicLocation> {
+    let rest = &reason[reason.find("panicked at ")? + "panicked at ".len()..];
+    let loc = rest.lines().next()?.trim_end_matches(':');
+    let mut parts = loc.rsplitn(3, ':');
+    let last = parts.next()?.parse::<u32>().ok()?;
+    let (file, line, column) = match (parts.next(), parts.next()) {
+        (Some(line), Some(file)) => match line.parse::<u32>() {
+            Ok(line) => (file, line, Some(last)),
+            Err(_) => (loc.rsplit_once(':')?.0, last, None),
+        },
+        (Some(file), None) => (file, last, None),
+        _ => return None,
+    };
+    Some(PanicLocation {
+        file: file.to_string(),
+        line,
+        column,
+        function: None,
+    })
+}
+
+/// Find the function containing code for `file:line`, using the line tables
+fn function_at_line(ctx: &Ctx, image: &FirmwareImage, file: &str, line: u32) -> Option<String> {
+    let start = image.base as u64;
+    let end = start + image.data.len() as u64;
+    let addr = ctx
+        .find_location_range(start, end)
+        .ok()?
+        .find(|(_, _, loc)| {
+            loc.line == Some(line) && loc.file.is_some_and(|f| f.ends_with(file))
+        })
+        .map(|(addr, _, _)| addr)?;
+    let mut frames = ctx.find_frames(addr).skip_all_loads().ok()?;
+    let frame = frames.next().ok()??;
+    frame
+        .function
+        .and_then(|f| f.demangle().ok().map(|n| n.into_owned()))
+}
+
+fn symbolize(ctx: &Ctx, obj: &ObjFile<'_>, addr: u32) -> Vec<Frame> {
+    let mut out = vec![];
+    if let Ok(mut frames) = ctx.find_frames(addr as u64).skip_all_loads() {
+        while let Ok(Some(frame)) = frames.next() {
+            out.push(Frame {
+                function: frame
+                    .function
+                    .and_then(|f| f.demangle().ok().map(|n| n.into_owned())),
+                file: frame.location.as_ref().and_then(|l| l.file.map(str::to_string)),
+                line: frame.location.as_ref().and_then(|l| l.line),
+            });
+        }
+    }
+    // No debug info? Fall back to the symbol table
+    if out.is_empty() {
+        if let Some(sym) = obj.symbol_map().get(addr as u64) {
+            out.push(Frame {
+                function: Some(addr2line::demangle_auto(sym.name().into(), None).into_owned()),
+                file: None,
+                line: None,
+            });
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_panic_location;
+
+    fn parse(reason: &str) -> Option<(String, u32, Option<u32>)> {
+        parse_panic_location(reason).map(|l| (l.file, l.line, l.column))
+    }
+
+    #[test]
+    fn panic_locations() {
+        let loc = |file: &str, line, column| Some((file.to_string(), line, column));
+        assert_eq!(
+            parse("panicked at src/main.rs:12:5:\nboom"),
+            loc("src/main.rs", 12, Some(5))
+        );
+        assert_eq!(parse("panicked at src/main.rs:12"), loc("src/main.rs", 12, None));
+        assert_eq!(parse("panicked at src/main.rs:12:\nboom"), loc("src/main.rs", 12, None));
+        // Anything before the location is skipped
+        assert_eq!(
+            parse("app: panicked at /home/me/src/lib.rs:7:9:\nindex out of bounds"),
+            loc("/home/me/src/lib.rs", 7, Some(9))
+        );
+        // Colons in the path don't get taken for the line
+        assert_eq!(
+            parse("panicked at C:\\src\\main.rs:3:1:"),
+            loc("C:\\src\\main.rs", 3, Some(1))
+        );
+        assert_eq!(parse("panicked at C:\\src\\main.rs:3"), loc("C:\\src\\main.rs", 3, None));
+    }
+
+    #[test]
+    fn panics_without_a_location() {
+        assert_eq!(parse("boom"), None);
+        assert_eq!(parse("panicked at "), None);
+        assert_eq!(parse("panicked at src/main.rs\nboom"), None);
+        assert_eq!(parse("panicked at src/main.rs:x\nboom"), None);
+    }
+}
@@ -0,0 +1,88 @@
+use std::fmt;
+
+use curacao_host::{codes, ImageError};
+
+/// Errors reported by blcli
+///
+/// Each variant has a stable `kind` and `code` for machine-readable output,
+/// see [`curacao_host::codes`].
+#[derive(Debug)]
+pub enum Error {
+    /// The bootloader request failed
+    Host(curacao_host::Error),
+    /// The ELF file could not be turned into an image
+    Image(ImageError),
+    /// Local file I/O failed
+    Io(std::io::Error),
+    /// The line editor couldn't be set up
+    Terminal(rustyline::error::ReadlineError),
+    /// The command or its arguments were invalid
+    Usage(String),
+    /// The command was interrupted with Ctrl-C
+    Cancelled,
+}
+
+impl Error {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Error::Host(e) => e.kind(),
+            Error::Image(e) => e.kind(),
+            Error::Io(_) | Error::Terminal(_) => "io",
+            Error::Usage(_) => "usage",
+            Error::Cancelled => "cancelled",
+        }
+    }
+
+    pub fn code(&self) -> u16 {
+        match self {
+            Error::Host(e) => e.code(),
+            Error::Image(e) => e.code(),
+            Error::Io(_) | Error::Terminal(_) => codes::IO,
+            Error::Usage(_) => codes::USAGE,
+            Error::Cancelled => codes::CANCELLED,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Host(e) => write!(f, "{e}"),
+            Error::Image(e) => write!(f, "image error: {e}"),
+            Error::Io(e) => write!(f, "I/O error: {e}"),
+            Error::Terminal(e) => write!(f, "terminal error: {e}"),
+            Error::Usage(s) => write!(f, "{s}"),
+            Error::Cancelled => write!(f, "cancelled"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Host(e) => Some(e),
+            Error::Image(e) => Some(e),
+            Error::Io(e) => Some(e),
+            Error::Terminal(e) => Some(e),
+            Error::Usage(_) | Error::Cancelled => None,
+        }
+    }
+}
+
+impl From<curacao_host::Error> for Error {
+    fn from(e: curacao_host::Error) -> Self {
+        Error::Host(e)
+    }
+}
+
+impl From<ImageError> for Error {
+    fn from(e: ImageError) -> Self {
+        Error::Image(e)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
@@ -1,130 +1,33 @@
 use std::{
-    fmt::Write, fs::{self, File}, io::{Read, Write as _}, num::ParseIntError, str::from_utf8, sync::atomic::{AtomicU32, Ordering}, time::Instant
+    fs::{self, File}, io::Write as _, num::ParseIntError
 };
 
-use bootloader_icd::{
-    scratch::BootMessage, AppPartitionInfo, BootResult, BootloadEndpoint, DataChunk, EraseFlashEndpoint, FlashEraseCommand, FlashReadCommand, FlashWriteCommand, GetAppFlashInfoEndpoint, GetBootMessageEndpoint, ReadFlashEndpoint, RebootReasonEndpoint, WriteFlashEndpoint
-};
-use postcard_rpc::Endpoint;
-use poststation_sdk::{connect, SquadClient};
-use rand::{thread_rng, RngCore};
-use serde::{de::DeserializeOwned, Serialize};
-
-struct Bootloader {
-    serial: u64,
-    client: SquadClient,
-    ctr: AtomicU32,
-}
-
-impl Bootloader {
-    pub fn new(client: SquadClient, serial: u64) -> Self {
-        Self {
-            serial,
-            client,
-            ctr: AtomicU32::new(0),
-        }
-    }
-
-    #[inline(always)]
-    fn ctr(&self) -> u32 {
-        self.ctr.fetch_add(1, Ordering::Relaxed)
-    }
-
-    async fn proxy_ep<E>(&self, req: &E::Request) -> Result<E::Response, String>
-    where
-        E: Endpoint,
-        E::Request: Serialize,
-        E::Response: DeserializeOwned,
-    {
-        self.client
-            .proxy_endpoint::<E>(self.serial, self.ctr(), req)
-            .await
-    }
-
-    async fn partinfo(&self) -> Result<AppPartitionInfo, String> {
-        self.proxy_ep::<GetAppFlashInfoEndpoint>(&()).await
-    }
-
-    async fn read_chunk(&self, start: u32, len: u32) -> Result<DataChunk, String> {
-        self.proxy_ep::<ReadFlashEndpoint>(&FlashReadCommand { start, len })
-            .await?
-            .map_err(|e| format!("Error: '{e:?}'"))
-    }
-
-    async fn erase(&self, start: u32, len: u32) -> Result<(), String> {
-        self.proxy_ep::<EraseFlashEndpoint>(&FlashEraseCommand {
-            start,
-            len,
-            force: false,
-        })
-        .await?
-        .map_err(|e| format!("Error: '{e:?}'"))
-    }
-
-    async fn write(&self, start: u32, data: &[u8]) -> Result<(), String> {
-        for (i, ch) in data.chunks(512).enumerate() {
-            let addr = start + (i as u32 * 512);
-            let res = self
-                .proxy_ep::<WriteFlashEndpoint>(&FlashWriteCommand {
-                    start: addr,
-                    data: ch.to_vec(),
-                    force: false,
-                })
-                .await?;
-            if let Err(e) = res {
-                return Err(format!("Error: '{e:?}'"));
-            }
-        }
-        Ok(())
-    }
-
-    async fn boot_msg(&self) -> Result<Option<BootMessage>, String> {
-        self.proxy_ep::<GetBootMessageEndpoint>(&()).await
-    }
-
-    async fn boot(&self) -> Result<(), String> {
-        self.proxy_ep::<BootloadEndpoint>(&())
-            .await?
-            .map_err(|_| "Err: Failed Sanity Check".into())
-    }
-
-    async fn reboot_reas(&self) -> Result<u32, String> {
-        self.proxy_ep::<RebootReasonEndpoint>(&()).await
-    }
-
-    async fn dumpfmt(&self, start: u32, len: u32, chunk: u32) -> Result<String, String> {
-        let mut out = String::new();
-        let mut addr = start;
-        let end = start + len;
-        while addr < end {
-            let take = (end - addr).min(chunk);
-            let Ok(data) = self.read_chunk(addr, take).await else {
-                return Err("Error getting data".into());
-            };
-            for (i, ch) in data.data.chunks(16).enumerate() {
-                let base = addr + (i as u32 * 16);
-                write!(&mut out, "0x{:08X} |", base).ok();
-                for b in ch {
-                    write!(&mut out, " {b:02X}").ok();
-                }
-                for _ in 0..(16 - ch.len()) {
-                    write!(&mut out, "   ").ok();
-                }
-                write!(&mut out, " | ").ok();
-                for b in ch {
-                    if b.is_ascii() && !b.is_ascii_control() {
-                        write!(&mut out, "{}", *b as char).ok();
-                    } else {
-                        write!(&mut out, "·").ok();
-                    }
-                }
-                out += "\n";
-            }
-
-            addr += take;
-        }
-        Ok(out)
-    }
+use clap::Parser;
+use curacao_host::{reset_causes, BootloaderClient, ConnectOpts, FirmwareImage};
+use error::Error;
+use output::{hexdump, Output, Record};
+use repl::LineReader;
+use stress::StressConfig;
+
+mod crash;
+mod error;
+mod output;
+mod repl;
+mod stress;
+
+#[derive(Parser, Debug)]
+struct Args {
+    /// Emit one JSON record per line instead of human-readable text
+    #[arg(long)]
+    json: bool,
+
+    /// Poststation server as `host:port`, overriding any profile
+    #[arg(long)]
+    server: Option<String>,
+
+    /// Profile from the config file naming the poststation server
+    #[arg(long)]
+    profile: Option<String>,
 }
 
 #[tokio::main]
@@ -133,210 +36,137 @@ async fn main() -> Result<(), String> {
     // const SERIAL: u64 = 0xE58A068274AB2C5F;
     const SERIAL: u64 = 0xBD8B1FDF144D2D81;
 
+    let args = Args::parse();
+    let out = Output { json: args.json };
 
-    let client = connect("localhost:51837").await;
-    let bl = Bootloader::new(client, SERIAL);
-
-    'repl: loop {
-        print!("> ");
-        let _ = std::io::stdout().flush();
+    let conn = ConnectOpts {
+        server: args.server,
+        profile: args.profile,
+    };
+    let client = match conn.connect().await {
+        Ok(client) => client,
+        Err(e) => {
+            out.error(&Error::from(e));
+            std::process::exit(1);
+        }
+    };
+    let bl = BootloaderClient::new(client, SERIAL);
+
+    // Only used for address completion, so don't fail if the device is missing
+    let info = bl.partinfo().await.ok();
+    let mut lines = LineReader::spawn(out.json, info).map_err(|e| e.to_string())?;
+
+    while let Some(line) = lines.next().await {
+        let words = line.split_whitespace().collect::<Vec<_>>();
+        let res = tokio::select! {
+            res = command(&bl, out, words.as_slice()) => res,
+            _ = tokio::signal::ctrl_c() => Err(Error::Cancelled),
+        };
+        if let Err(e) = res {
+            out.error(&e);
+        }
+    }
+    Ok(())
+}
 
-        let line = read_line().await;
-        let tline = line.trim();
-        let words = tline.split_whitespace().collect::<Vec<_>>();
-        match words.as_slice() {
-            ["dump"] => {
-                let Ok(info) = bl.partinfo().await else {
-                    println!("Error getting info");
-                    continue 'repl;
-                };
+async fn command(bl: &BootloaderClient, out: Output, words: &[&str]) -> Result<(), Error> {
+    match words {
+        ["dump"] => {
+            let info = bl.partinfo().await?;
+            if !out.json {
                 println!("Reading...");
-                match bl.dumpfmt(info.start, info.len, info.transfer_chunk).await {
-                    Ok(data) => {
-                        println!("{data}");
-                    }
-                    Err(e) => {
-                        println!("Error: '{e}'");
-                    }
-                }
             }
-            ["dumpto", path] => {
-                let Ok(info) = bl.partinfo().await else {
-                    println!("Error getting info");
-                    continue 'repl;
-                };
-                let _ = fs::remove_file(path);
-                let Ok(mut out) = File::create_new(path) else {
-                    println!("Error opening output file");
-                    continue 'repl;
-                };
+            let data = bl.read(info.start, info.len).await?;
+            out.emit(Record::Dump {
+                start: info.start,
+                len: info.len,
+                data: &data,
+            });
+        }
+        ["dumpto", path] => {
+            let info = bl.partinfo().await?;
+            let _ = fs::remove_file(path);
+            let mut file = File::create_new(path)?;
 
+            if !out.json {
                 println!("Reading...");
-                match bl.dumpfmt(info.start, info.len, info.transfer_chunk).await {
-                    Ok(data) => {
-                        if out.write_all(data.as_bytes()).is_ok() {
-                            println!("Wrote to '{path}'");
-                        } else {
-                            println!("Error writing file");
-                        }
-                    }
-                    Err(e) => {
-                        println!("Error: '{e}'");
-                    }
-                }
             }
-            ["info"] => match bl.partinfo().await {
-                Ok(info) => {
-                    println!("Info:");
-                    println!(
-                        "  * Start: {:08X} ({:0.02}KiB)",
-                        info.start,
-                        info.start as f32 / 1024.0
-                    );
-                    println!(
-                        "  * Len:   {:08X} ({:0.02}KiB)",
-                        info.len,
-                        info.len as f32 / 1024.0
-                    );
-                    println!(
-                        "  * Range: {:08X}..{:08X}",
-                        info.start,
-                        info.start + info.len
-                    );
-                    println!("  * Erase: {}B", info.erase_sz);
-                    println!("  * Write: {}B", info.write_sz);
-                    println!("  * Align: {}B", info.align);
-                    println!("  * Chunk: {}", info.transfer_chunk);
-                }
-                Err(e) => {
-                    println!("Error: '{e}'");
-                }
-            },
-            ["reason"] => {
-                let Ok(n) = bl.reboot_reas().await else {
-                    println!("Error");
-                    continue 'repl;
-                };
-                println!("reason: {n:08X}");
-            }
-            ["erase", from, "to", to] => {
-                let Some(from) = hex_or_dec::<u32>(from) else {
-                    println!("Error: invalid start");
-                    continue 'repl;
-                };
-                let Some(to) = hex_or_dec::<u32>(to) else {
-                    println!("Error: invalid end");
-                    continue 'repl;
-                };
-                let Some(len) = to.checked_sub(from) else {
-                    println!("Error: Invalid range");
-                    continue 'repl;
-                };
-                match bl.erase(from, len).await {
-                    Ok(_) => println!("Erased"),
-                    Err(e) => println!("{e}"),
-                }
-            }
-            ["bootmsg"] => {
-                let Ok(m) = bl.boot_msg().await else {
-                    println!("Error getting boot msg");
-                    continue 'repl;
-                };
-                println!("Boot Message: {m:?}");
-                match m {
-                    Some(BootMessage::AppPanicked { uptime, reason }) => {
-                        println!("App Panicked. ({uptime})");
-                        if let Ok(s) = from_utf8(&reason) {
-                            println!("Reason: {s}");
-                        }
-                    }
-                    Some(BootMessage::BootPanicked { uptime, reason }) => {
-                        println!("Boot Panicked. ({uptime})");
-                        if let Ok(s) = from_utf8(&reason) {
-                            println!("Reason: {s}");
-                        }
-                    }
-                    _ => {}
-                }
-            }
-            ["boot"] => {
-                match bl.boot().await {
-                    Ok(_) => {
-                        println!("Boot accepted. Exiting");
-                        std::process::exit(0);
-                    },
-                    Err(e) => {
-                        println!("{e}");
-                    },
-                }
-            }
-            ["load", path] => {
-                let Ok(mut file) = File::open(path) else {
-                    println!("Error opening file");
-                    continue 'repl;
-                };
-                let mut buf = vec![];
-                let Ok(_) = file.read_to_end(&mut buf) else {
-                    println!("Error reading file");
-                    continue 'repl;
-                };
-                // this is lazy
-                while buf.len() % 4096 != 0 {
-                    buf.push(0xFF);
-                }
-                bl.erase(64 * 1024, buf.len() as u32).await.unwrap();
-                bl.write(64 * 1024, &buf).await.unwrap();
+            let data = bl.read(info.start, info.len).await?;
+            file.write_all(hexdump(info.start, &data).as_bytes())?;
+            out.ok("dumpto", format!("Wrote to '{path}'"));
+        }
+        ["info"] => {
+            let info = bl.partinfo().await?;
+            out.emit(Record::PartitionInfo(&info));
+        }
+        ["reason"] => {
+            let raw = bl.reboot_reason().await?;
+            out.emit(Record::ResetReason {
+                raw,
+                causes: reset_causes(raw),
+            });
+        }
+        ["erase", from, "to", to] => {
+            let from = hex_or_dec::<u32>(from).ok_or(Error::Usage("invalid start".into()))?;
+            let to = hex_or_dec::<u32>(to).ok_or(Error::Usage("invalid end".into()))?;
+            let len = to
+                .checked_sub(from)
+                .ok_or(Error::Usage("invalid range".into()))?;
+            bl.erase(from, len).await?;
+            out.ok("erase", "Erased".to_string());
+        }
+        ["bootmsg"] => {
+            let m = bl.boot_msg().await?;
+            out.emit(Record::BootMessage(m.into()));
+        }
+        ["crash", elf, rest @ ..] => {
+            let verify = match rest {
+                [] => true,
+                ["noverify"] => false,
+                _ => return Err(Error::Usage("usage: crash <elf> [noverify]".into())),
+            };
+            let report = crash::decode(bl, elf, verify).await?;
+            out.emit(Record::Crash(&report));
+        }
+        ["boot"] => {
+            bl.boot().await?;
+            out.ok("boot", "Boot accepted. Exiting".to_string());
+            std::process::exit(0);
+        }
+        ["load", path] => {
+            let info = bl.partinfo().await?;
+            // A raw binary, linked to run from the start of the app partition
+            let image = FirmwareImage {
+                base: info.start,
+                data: fs::read(path)?,
+                build_id: None,
+                vector_table: None,
+            };
+            let buf = image.flash_data(&info)?;
+            let total = buf.len() as u32;
+            bl.erase(info.start, total).await?;
+            for (i, ch) in buf.chunks(info.erase_sz as usize).enumerate() {
+                let offset = i as u32 * info.erase_sz;
+                bl.write(info.start + offset, ch).await?;
+                out.progress("write", offset + ch.len() as u32, total);
             }
-            ["test"] => {
-                let start = Instant::now();
-                let info = bl.partinfo().await.unwrap();
-                println!("({:?}) Erasing full range...", start.elapsed());
-                bl.erase(info.start, info.len).await.unwrap();
-                println!("({:?}) Generating random data...", start.elapsed());
-                let mut data = vec![0u8; info.len as usize];
-                {
-                    let mut rng = thread_rng();
-                    rng.fill_bytes(&mut data);
-                }
-                println!("({:?}) Writing random data...", start.elapsed());
-                bl.write(info.start, &data).await.unwrap();
-                println!("({:?}) Reading back...", start.elapsed());
-                let mut rback = vec![];
-                let mut addr = info.start;
-                let end = info.start + info.len;
-                while addr < end {
-                    let c = bl.read_chunk(addr, 512).await.unwrap();
-                    rback.extend_from_slice(&c.data);
-                    addr += 512;
-                }
-                assert_eq!(rback, data);
-                println!("({:?}) Erasing full range...", start.elapsed());
-                bl.erase(info.start, info.len).await.unwrap();
-                println!("({:?}) Reading back (should be empty)...", start.elapsed());
-                let mut rback = vec![];
-                let mut addr = info.start;
-                let end = info.start + info.len;
-                while addr < end {
-                    let c = bl.read_chunk(addr, 512).await.unwrap();
-                    rback.extend_from_slice(&c.data);
-                    addr += 512;
-                }
-                assert!(rback.iter().all(|b| *b == 0xFF));
-                println!("({:?}) Test passed!", start.elapsed());
+            out.ok("load", format!("Loaded {total} bytes from '{path}'"));
+        }
+        ["test", args @ ..] => {
+            let (cfg, json) = StressConfig::parse(args).map_err(Error::Usage)?;
+            let report = stress::run(bl, out, cfg).await?;
+            out.emit(Record::StressReport(&report));
+            if let Some(path) = json {
+                report.write_json(&path)?;
+                out.ok("test", format!("Wrote report to '{path}'"));
             }
-            other => println!("Error, unknown: '{other:?}'"),
         }
+        ["help"] => repl::print_help(None)?,
+        ["help", name] => repl::print_help(Some(name))?,
+        [] => {}
+        [name, ..] => return Err(repl::usage_error(name)),
     }
-}
-
-async fn read_line() -> String {
-    tokio::task::spawn_blocking(|| {
-        let mut line = String::new();
-        std::io::stdin().read_line(&mut line).unwrap();
-        line
-    })
-    .await
-    .unwrap()
+    Ok(())
 }
 
 pub trait FromStrRadix: Sized {
@@ -0,0 +1,190 @@
+//! Human-readable or JSON output
+//!
+//! Every command reports through [`Output`]. In JSON mode each [`Record`] is
+//! printed as a single line of JSON, so CI can consume the output without
+//! scraping text.
+
+use std::fmt::Write;
+
+use bootloader_icd::AppPartitionInfo;
+use curacao_host::{codes, BootState};
+use serde::{Serialize, Serializer};
+
+use crate::{crash::CrashReport, error::Error, stress::StressReport};
+
+#[derive(Debug, Serialize)]
+pub struct ErrorRecord {
+    pub kind: &'static str,
+    pub code: u16,
+    pub message: String,
+    /// `io::ErrorKind` of the underlying I/O error, if there is one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub io_kind: Option<String>,
+}
+
+impl From<&Error> for ErrorRecord {
+    fn from(e: &Error) -> Self {
+        ErrorRecord {
+            kind: e.kind(),
+            code: e.code(),
+            message: e.to_string(),
+            io_kind: codes::io_kind(e).map(|k| format!("{k:?}")),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "record", rename_all = "snake_case")]
+pub enum Record<'a> {
+    PartitionInfo(&'a AppPartitionInfo),
+    BootMessage(BootState),
+    ResetReason {
+        raw: u32,
+        causes: Vec<&'static str>,
+    },
+    Progress {
+        op: &'static str,
+        done: u32,
+        total: u32,
+    },
+    Dump {
+        start: u32,
+        len: u32,
+        #[serde(serialize_with = "ser_hex")]
+        data: &'a [u8],
+    },
+    StressReport(&'a StressReport),
+    Crash(&'a CrashReport),
+    Ok {
+        op: &'static str,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        detail: Option<String>,
+    },
+    Error(ErrorRecord),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Output {
+    pub json: bool,
+}
+
+impl Output {
+    pub fn emit(&self, rec: Record<'_>) {
+        if self.json {
+            match serde_json::to_string(&rec) {
+                Ok(s) => println!("{s}"),
+                Err(e) => eprintln!("Error serializing record: {e}"),
+            }
+        } else {
+            print_human(rec);
+        }
+    }
+
+    pub fn error(&self, e: &Error) {
+        self.emit(Record::Error(e.into()));
+    }
+
+    pub fn ok(&self, op: &'static str, detail: impl Into<Option<String>>) {
+        self.emit(Record::Ok {
+            op,
+            detail: detail.into(),
+        });
+    }
+
+    pub fn progress(&self, op: &'static str, done: u32, total: u32) {
+        self.emit(Record::Progress { op, done, total });
+    }
+}
+
+fn print_human(rec: Record<'_>) {
+    match rec {
+        Record::PartitionInfo(info) => {
+            println!("Info:");
+            println!(
+                "  * Start: {:08X} ({:0.02}KiB)",
+                info.start,
+                info.start as f32 / 1024.0
+            );
+            println!(
+                "  * Len:   {:08X} ({:0.02}KiB)",
+                info.len,
+                info.len as f32 / 1024.0
+            );
+            println!(
+                "  * Range: {:08X}..{:08X}",
+                info.start,
+                info.start + info.len
+            );
+            println!("  * Erase: {}B", info.erase_sz);
+            println!("  * Write: {}B", info.write_sz);
+            println!("  * Align: {}B", info.align);
+            println!("  * Chunk: {}", info.transfer_chunk);
+        }
+        Record::BootMessage(state) => {
+            println!("Boot Message: {state:?}");
+            match state {
+                BootState::AppPanicked { uptime, reason } => {
+                    println!("App Panicked. ({uptime})");
+                    println!("Reason: {reason}");
+                }
+                BootState::BootPanicked { uptime, reason } => {
+                    println!("Boot Panicked. ({uptime})");
+                    println!("Reason: {reason}");
+                }
+                BootState::AppFaulted { uptime, regs } => {
+                    println!("App HardFaulted. ({uptime})");
+                    println!("PC: {:08X} LR: {:08X} CFSR: {:08X}", regs.pc, regs.lr, regs.cfsr);
+                    println!("Use 'crash <elf>' to symbolize");
+                }
+                _ => {}
+            }
+        }
+        Record::ResetReason { raw, causes } => {
+            println!("reason: {raw:08X} {causes:?}");
+        }
+        Record::Progress { op, done, total } => {
+            println!("{op}: {done}/{total}");
+        }
+        Record::Dump { start, data, .. } => println!("{}", hexdump(start, data)),
+        Record::StressReport(report) => report.print_summary(),
+        Record::Crash(report) => report.print_summary(),
+        Record::Ok { op, detail } => match detail {
+            Some(d) => println!("{op}: {d}"),
+            None => println!("{op}: ok"),
+        },
+        Record::Error(e) => println!("Error: {}", e.message),
+    }
+}
+
+fn ser_hex<S: Serializer>(data: &&[u8], ser: S) -> Result<S::Ok, S::Error> {
+    let mut out = String::with_capacity(data.len() * 2);
+    for b in data.iter() {
+        write!(&mut out, "{b:02X}").ok();
+    }
+    ser.serialize_str(&out)
+}
+
+/// Format `data` as a hex dump, with addresses starting at `start`
+pub fn hexdump(start: u32, data: &[u8]) -> String {
+    let mut out = String::new();
+    for (i, ch) in data.chunks(16).enumerate() {
+        let base = start + (i as u32 * 16);
+        write!(&mut out, "0x{:08X} |", base).ok();
+        for b in ch {
+            write!(&mut out, " {b:02X}").ok();
+        }
+        for _ in 0..(16 - ch.len()) {
+            write!(&mut out, "   ").ok();
+        }
+        write!(&mut out, " | ").ok();
+        for b in ch {
+            if b.is_ascii() && !b.is_ascii_control() {
+                write!(&mut out, "{}", *b as char).ok();
+            } else {
+                write!(&mut out, "·").ok();
+            }
+        }
+        out += "\n";
+    }
+    out
+}
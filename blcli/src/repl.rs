@@ -0,0 +1,319 @@
+//! Line editing for the interactive prompt
+//!
+//! The editor runs on its own thread, since rustyline blocks while reading.
+//! Each call to [`LineReader::next`] asks it for one more line, so the prompt
+//! is only drawn once the previous command has finished.
+
+use std::{borrow::Cow, path::PathBuf, sync::mpsc, thread};
+
+use bootloader_icd::AppPartitionInfo;
+use rustyline::{
+    completion::{Completer, FilenameCompleter, Pair},
+    error::ReadlineError,
+    highlight::Highlighter,
+    hint::Hinter,
+    history::FileHistory,
+    validate::Validator,
+    CompletionType, Config, Context, Editor, Helper,
+};
+
+use crate::error::Error;
+
+/// What an argument is, for completion
+#[derive(Clone, Copy, PartialEq)]
+enum Arg {
+    Addr,
+    Path,
+    Word(&'static str),
+    Other,
+}
+
+pub struct Command {
+    pub name: &'static str,
+    pub usage: &'static str,
+    pub help: &'static str,
+    args: &'static [Arg],
+}
+
+pub const COMMANDS: &[Command] = &[
+    Command {
+        name: "info",
+        usage: "",
+        help: "Show the app partition layout",
+        args: &[],
+    },
+    Command {
+        name: "dump",
+        usage: "",
+        help: "Read the app partition and print it as hex",
+        args: &[],
+    },
+    Command {
+        name: "dumpto",
+        usage: "<path>",
+        help: "Read the app partition and write it as hex to a file",
+        args: &[Arg::Path],
+    },
+    Command {
+        name: "erase",
+        usage: "<start> to <end>",
+        help: "Erase the range start..end, both must be sector aligned",
+        args: &[Arg::Addr, Arg::Word("to"), Arg::Addr],
+    },
+    Command {
+        name: "load",
+        usage: "<path>",
+        help: "Erase and write a flat binary at the start of the app partition",
+        args: &[Arg::Path],
+    },
+    Command {
+        name: "reason",
+        usage: "",
+        help: "Show why the device last reset",
+        args: &[],
+    },
+    Command {
+        name: "bootmsg",
+        usage: "",
+        help: "Show the message the app left for the bootloader",
+        args: &[],
+    },
+    Command {
+        name: "crash",
+        usage: "<elf> [noverify]",
+        help: "Symbolize the last panic or fault using the app's ELF",
+        args: &[Arg::Path, Arg::Word("noverify")],
+    },
+    Command {
+        name: "test",
+        usage: "[pattern=random|walk|0xNN] [seed=N] [iters=N] [sectors=all|A..B|A,B] [json=PATH]",
+        help: "Run the flash stress test",
+        args: &[Arg::Other],
+    },
+    Command {
+        name: "boot",
+        usage: "",
+        help: "Boot the app and exit",
+        args: &[],
+    },
+    Command {
+        name: "help",
+        usage: "[command]",
+        help: "List commands, or show help for one",
+        args: &[Arg::Other],
+    },
+];
+
+/// Print the command list, or the help for a single command
+pub fn print_help(name: Option<&str>) -> Result<(), Error> {
+    match name {
+        None => {
+            for cmd in COMMANDS {
+                println!("  {:<8} {}", cmd.name, cmd.help);
+            }
+            println!("Ctrl-C cancels a running command, Ctrl-D exits.");
+        }
+        Some(name) => {
+            let cmd = COMMANDS
+                .iter()
+                .find(|c| c.name == name)
+                .ok_or_else(|| Error::Usage(format!("unknown command: '{name}'")))?;
+            println!("{} {}", cmd.name, cmd.usage);
+            println!("  {}", cmd.help);
+        }
+    }
+    Ok(())
+}
+
+/// Error for a command that didn't match, showing its usage if it exists
+pub fn usage_error(name: &str) -> Error {
+    match COMMANDS.iter().find(|c| c.name == name) {
+        Some(cmd) => Error::Usage(format!("usage: {} {}", cmd.name, cmd.usage)),
+        None => Error::Usage(format!("unknown command: '{name}', try 'help'")),
+    }
+}
+
+struct ReplHelper {
+    files: FilenameCompleter,
+    info: Option<AppPartitionInfo>,
+}
+
+impl ReplHelper {
+    /// Sector boundaries in the app partition, including its end
+    fn addresses(&self, prefix: &str) -> Vec<Pair> {
+        const MAX_SHOWN: usize = 64;
+
+        // A partition reporting no sector size has no boundaries to offer
+        let Some(info) = self.info.filter(|i| i.erase_sz != 0) else {
+            return vec![];
+        };
+        // In u64, so a partition ending at the top of the address space
+        // doesn't wrap
+        let start = u64::from(info.start);
+        let end = start + u64::from(info.len);
+        let pair = |addr: u64| {
+            let s = format!("0x{addr:08X}");
+            Pair { display: s.clone(), replacement: s }
+        };
+        if prefix.is_empty() {
+            return vec![pair(start), pair(end)];
+        }
+        let prefix = prefix.to_ascii_uppercase().replacen("0X", "0x", 1);
+        (start..=end)
+            .step_by(info.erase_sz as usize)
+            .map(pair)
+            .filter(|p| p.replacement.starts_with(&prefix))
+            .take(MAX_SHOWN)
+            .collect()
+    }
+}
+
+impl Completer for ReplHelper {
+    type Candidate = Pair;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let before = &line[..pos];
+        let start = before.rfind(char::is_whitespace).map_or(0, |i| i + 1);
+        let word = &before[start..];
+        let prior = before[..start].split_whitespace().collect::<Vec<_>>();
+
+        let Some((name, args)) = prior.split_first() else {
+            let cands = COMMANDS
+                .iter()
+                .filter(|c| c.name.starts_with(word))
+                .map(|c| Pair {
+                    display: c.name.to_string(),
+                    replacement: format!("{} ", c.name),
+                })
+                .collect();
+            return Ok((start, cands));
+        };
+        if *name == "help" {
+            let cands = COMMANDS
+                .iter()
+                .filter(|c| args.is_empty() && c.name.starts_with(word))
+                .map(|c| Pair {
+                    display: c.name.to_string(),
+                    replacement: c.name.to_string(),
+                })
+                .collect();
+            return Ok((start, cands));
+        }
+        let Some(cmd) = COMMANDS.iter().find(|c| c.name == *name) else {
+            return Ok((start, vec![]));
+        };
+        match cmd.args.get(args.len()) {
+            Some(Arg::Addr) => Ok((start, self.addresses(word))),
+            Some(Arg::Path) => self.files.complete(line, pos, ctx),
+            Some(Arg::Word(w)) if w.starts_with(word) => Ok((
+                start,
+                vec![Pair {
+                    display: w.to_string(),
+                    replacement: format!("{w} "),
+                }],
+            )),
+            _ => Ok((start, vec![])),
+        }
+    }
+}
+
+impl Hinter for ReplHelper {
+    type Hint = String;
+
+    /// Show the usage of a command once its name has been typed
+    fn hint(&self, line: &str, pos: usize, _ctx: &Context<'_>) -> Option<String> {
+        if pos < line.len() {
+            return None;
+        }
+        let name = line.strip_suffix(' ').unwrap_or(line);
+        let cmd = COMMANDS.iter().find(|c| c.name == name)?;
+        if cmd.usage.is_empty() {
+            return None;
+        }
+        let sep = if line.ends_with(' ') { "" } else { " " };
+        Some(format!("{sep}{}", cmd.usage))
+    }
+}
+
+impl Highlighter for ReplHelper {
+    fn highlight_hint<'h>(&self, hint: &'h str) -> Cow<'h, str> {
+        Cow::Owned(format!("\x1b[2m{hint}\x1b[0m"))
+    }
+}
+
+impl Validator for ReplHelper {}
+
+impl Helper for ReplHelper {}
+
+/// Reads lines from the terminal on a background thread
+pub struct LineReader {
+    ready: mpsc::Sender<()>,
+    lines: tokio::sync::mpsc::UnboundedReceiver<String>,
+}
+
+impl LineReader {
+    /// Start the editor. History is kept in `~/.blcli_history`, except in
+    /// JSON mode, where input is usually a script.
+    pub fn spawn(json: bool, info: Option<AppPartitionInfo>) -> Result<Self, Error> {
+        let config = Config::builder()
+            .completion_type(CompletionType::List)
+            .auto_add_history(false)
+            .build();
+        let mut rl = Editor::<ReplHelper, FileHistory>::with_config(config)
+            .map_err(Error::Terminal)?;
+        rl.set_helper(Some(ReplHelper {
+            files: FilenameCompleter::new(),
+            info,
+        }));
+
+        let history = (!json).then(history_path).flatten();
+        if let Some(path) = history.as_ref() {
+            // Missing on first run
+            let _ = rl.load_history(path);
+        }
+        let prompt = if json { "" } else { "> " };
+
+        let (ready, ready_rx) = mpsc::channel::<()>();
+        let (lines_tx, lines) = tokio::sync::mpsc::unbounded_channel();
+        thread::spawn(move || {
+            while ready_rx.recv().is_ok() {
+                let line = match rl.readline(prompt) {
+                    Ok(line) => line,
+                    // Ctrl-C at the prompt just clears the line
+                    Err(ReadlineError::Interrupted) => String::new(),
+                    Err(ReadlineError::Eof) => break,
+                    Err(e) => {
+                        eprintln!("Error reading input: {e}");
+                        break;
+                    }
+                };
+                if !line.trim().is_empty() {
+                    let _ = rl.add_history_entry(line.as_str());
+                    if let Some(path) = history.as_ref() {
+                        let _ = rl.save_history(path);
+                    }
+                }
+                if lines_tx.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+
+        Ok(Self { ready, lines })
+    }
+
+    /// The next line of input, or `None` once input has ended
+    pub async fn next(&mut self) -> Option<String> {
+        self.ready.send(()).ok()?;
+        self.lines.recv().await
+    }
+}
+
+fn history_path() -> Option<PathBuf> {
+    std::env::var_os("HOME").map(|h| PathBuf::from(h).join(".blcli_history"))
+}
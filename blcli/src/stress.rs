@@ -0,0 +1,464 @@
+//! Flash soak/stress testing for the bootloader's app partition
+//!
+//! Each iteration walks the selected erase sectors and for each one:
+//! erases it, checks that it reads back blank, writes the test pattern, and
+//! reads it back again. Failures are recorded per-sector and the run keeps
+//! going, so one bad sector doesn't hide problems elsewhere.
+
+use std::{
+    collections::HashSet,
+    fs::File,
+    io::Write as _,
+    time::{Duration, Instant},
+};
+
+use bootloader_icd::AppPartitionInfo;
+use rand::{rngs::StdRng, RngCore, SeedableRng};
+use serde::Serialize;
+
+use curacao_host::BootloaderClient;
+
+use crate::{error::Error, hex_or_dec, output::Output};
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Pattern {
+    /// Random data, reproducible from the seed. Each iteration uses `seed + iteration`
+    Random { seed: u64 },
+    /// A single set bit, shifting by one position per byte
+    WalkingOnes,
+    /// Every byte set to the same value, e.g. 0x00, 0x55, 0xAA
+    Fill(u8),
+}
+
+impl Pattern {
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "random" => Some(Pattern::Random { seed: 0 }),
+            "walk" | "walking" | "ones" => Some(Pattern::WalkingOnes),
+            other => hex_or_dec::<u8>(other).map(Pattern::Fill),
+        }
+    }
+
+    /// Fill `buf` with the expected contents of the whole test region for `iter`
+    fn fill(&self, iter: u32, buf: &mut [u8]) {
+        match self {
+            Pattern::Random { seed } => {
+                let mut rng = StdRng::seed_from_u64(seed.wrapping_add(iter as u64));
+                rng.fill_bytes(buf);
+            }
+            Pattern::WalkingOnes => {
+                buf.iter_mut().enumerate().for_each(|(i, b)| {
+                    *b = 1 << ((i + iter as usize) % 8);
+                });
+            }
+            Pattern::Fill(val) => buf.fill(*val),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StressConfig {
+    pub pattern: Pattern,
+    pub iterations: u32,
+    /// Sector indexes (relative to the partition start). `None` means all sectors
+    pub sectors: Option<Vec<u32>>,
+}
+
+impl Default for StressConfig {
+    fn default() -> Self {
+        Self {
+            pattern: Pattern::Random { seed: rand::random() },
+            iterations: 1,
+            sectors: None,
+        }
+    }
+}
+
+impl StressConfig {
+    /// Parse the arguments of the `test` command
+    ///
+    /// Returns the config, and an optional path to write the JSON report to.
+    ///
+    /// ```text
+    /// test [pattern=random|walk|0x00|0x55|0xAA] [seed=N] [iters=N]
+    ///      [sectors=all|A..B|A,B,C] [json=PATH]
+    /// ```
+    pub fn parse(args: &[&str]) -> Result<(Self, Option<String>), String> {
+        let mut cfg = StressConfig::default();
+        let mut seed = None;
+        let mut json = None;
+
+        for arg in args {
+            let Some((key, val)) = arg.split_once('=') else {
+                return Err(format!("expected key=value, got '{arg}'"));
+            };
+            match key {
+                "pattern" => {
+                    cfg.pattern =
+                        Pattern::parse(val).ok_or_else(|| format!("invalid pattern '{val}'"))?;
+                }
+                "seed" => {
+                    seed = Some(hex_or_dec::<u64>(val).ok_or("invalid seed")?);
+                }
+                "iters" => {
+                    cfg.iterations = hex_or_dec::<u32>(val)
+                        .filter(|n| *n > 0)
+                        .ok_or("iteration count must be at least 1")?;
+                }
+                "sectors" => cfg.sectors = parse_sectors(val)?,
+                "json" => json = Some(val.to_string()),
+                other => return Err(format!("unknown option '{other}'")),
+            }
+        }
+
+        match (&mut cfg.pattern, seed) {
+            (Pattern::Random { seed }, Some(s)) => *seed = s,
+            (Pattern::Random { seed }, None) => *seed = rand::random(),
+            (_, Some(_)) => return Err("seed is only used with pattern=random".into()),
+            (_, None) => {}
+        }
+
+        Ok((cfg, json))
+    }
+}
+
+/// More sectors than any partition we flash has. Sectors past the end of the
+/// actual partition are refused by [`run`], this only keeps `A..B` from
+/// asking for billions of them
+const MAX_SECTORS: u32 = 1 << 16;
+
+fn parse_sectors(s: &str) -> Result<Option<Vec<u32>>, String> {
+    if s == "all" {
+        return Ok(None);
+    }
+    if s.is_empty() {
+        return Err("no sectors given".into());
+    }
+    let sectors: Vec<u32> = if let Some((from, to)) = s.split_once("..") {
+        let from = hex_or_dec::<u32>(from).ok_or("invalid sector range start")?;
+        let to = hex_or_dec::<u32>(to).ok_or("invalid sector range end")?;
+        if to <= from {
+            return Err(format!("sector range {from}..{to} is empty"));
+        }
+        if to > MAX_SECTORS {
+            return Err(format!("sector {} out of range", to - 1));
+        }
+        (from..to).collect()
+    } else {
+        s.split(',')
+            .map(|n| hex_or_dec::<u32>(n).ok_or_else(|| format!("invalid sector '{n}'")))
+            .collect::<Result<_, _>>()?
+    };
+
+    let mut seen = HashSet::new();
+    for &n in sectors.iter() {
+        if n >= MAX_SECTORS {
+            return Err(format!("sector {n} out of range"));
+        }
+        if !seen.insert(n) {
+            return Err(format!("sector {n} given more than once"));
+        }
+    }
+    Ok(Some(sectors))
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Stage {
+    Erase,
+    BlankCheck,
+    Write,
+    Verify,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SectorFailure {
+    pub iteration: u32,
+    pub sector: u32,
+    pub addr: u32,
+    pub stage: Stage,
+    pub detail: String,
+    /// Number of mismatched bytes, for readback stages
+    pub mismatches: u32,
+    /// Address of the first mismatched byte, for readback stages
+    pub first_mismatch: Option<u32>,
+}
+
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct Throughput {
+    pub bytes: u64,
+    pub millis: u64,
+    pub kib_per_sec: f64,
+}
+
+impl Throughput {
+    fn add(&mut self, bytes: usize, dur: Duration) {
+        self.bytes += bytes as u64;
+        self.millis += dur.as_millis() as u64;
+        if self.millis != 0 {
+            self.kib_per_sec = (self.bytes as f64 / 1024.0) / (self.millis as f64 / 1000.0);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StressReport {
+    pub config: StressConfig,
+    pub partition_start: u32,
+    pub sector_size: u32,
+    pub sectors_tested: u32,
+    pub iterations_run: u32,
+    pub erase: Throughput,
+    pub write: Throughput,
+    pub read: Throughput,
+    pub elapsed_ms: u64,
+    pub failures: Vec<SectorFailure>,
+}
+
+impl StressReport {
+    pub fn passed(&self) -> bool {
+        self.failures.is_empty()
+    }
+
+    pub fn write_json(&self, path: &str) -> Result<(), Error> {
+        let mut file = File::create(path)?;
+        serde_json::to_writer_pretty(&mut file, self).map_err(std::io::Error::from)?;
+        file.write_all(b"\n")?;
+        Ok(())
+    }
+
+    pub fn print_summary(&self) {
+        println!(
+            "Tested {} sector(s) x {} iteration(s) in {:?}",
+            self.sectors_tested,
+            self.iterations_run,
+            Duration::from_millis(self.elapsed_ms)
+        );
+        for (name, tp) in [("Erase", &self.erase), ("Write", &self.write), ("Read", &self.read)] {
+            println!(
+                "  * {name}: {:0.02}KiB in {}ms ({:0.02}KiB/s)",
+                tp.bytes as f64 / 1024.0,
+                tp.millis,
+                tp.kib_per_sec
+            );
+        }
+        if self.passed() {
+            println!("Test passed!");
+            return;
+        }
+        println!("Test FAILED, {} failure(s):", self.failures.len());
+        for f in self.failures.iter() {
+            print!(
+                "  * iter {} sector {} (0x{:08X}) {:?}: {}",
+                f.iteration, f.sector, f.addr, f.stage, f.detail
+            );
+            if let Some(first) = f.first_mismatch {
+                print!(" ({} bytes differ, first at 0x{first:08X})", f.mismatches);
+            }
+            println!();
+        }
+    }
+}
+
+/// Run the stress suite described by `cfg` against the bootloader
+pub async fn run(bl: &BootloaderClient, out: Output, cfg: StressConfig) -> Result<StressReport, Error> {
+    let start = Instant::now();
+    let info = bl.partinfo().await?;
+    let sector_ct = info.len / info.erase_sz;
+
+    let sectors = match cfg.sectors.as_ref() {
+        Some(s) => {
+            if let Some(bad) = s.iter().find(|n| **n >= sector_ct) {
+                return Err(Error::Usage(format!(
+                    "sector {bad} out of range, partition has {sector_ct} sectors"
+                )));
+            }
+            s.clone()
+        }
+        None => (0..sector_ct).collect(),
+    };
+
+    let mut report = StressReport {
+        config: cfg.clone(),
+        partition_start: info.start,
+        sector_size: info.erase_sz,
+        sectors_tested: sectors.len() as u32,
+        iterations_run: 0,
+        erase: Throughput::default(),
+        write: Throughput::default(),
+        read: Throughput::default(),
+        elapsed_ms: 0,
+        failures: vec![],
+    };
+
+    // Pattern data is generated over the whole partition, so a given sector
+    // sees the same data regardless of which subset is selected
+    let mut expected = vec![0u8; info.len as usize];
+    let blank = vec![0xFFu8; info.erase_sz as usize];
+
+    for iter in 0..cfg.iterations {
+        cfg.pattern.fill(iter, &mut expected);
+        out.progress("test", iter, cfg.iterations);
+
+        for &sector in sectors.iter() {
+            let offset = (sector * info.erase_sz) as usize;
+            let addr = info.start + (sector * info.erase_sz);
+            let data = &expected[offset..][..info.erase_sz as usize];
+            let fail = |stage, detail: String| SectorFailure {
+                iteration: iter,
+                sector,
+                addr,
+                stage,
+                detail,
+                mismatches: 0,
+                first_mismatch: None,
+            };
+
+            let now = Instant::now();
+            if let Err(e) = bl.erase(addr, info.erase_sz).await {
+                report.failures.push(fail(Stage::Erase, e.to_string()));
+                continue;
+            }
+            report.erase.add(blank.len(), now.elapsed());
+
+            match readback(bl, &info, addr, &mut report.read).await {
+                Ok(rback) => {
+                    if let Some((mismatches, first)) = compare(&blank, &rback, addr) {
+                        report.failures.push(SectorFailure {
+                            mismatches,
+                            first_mismatch: Some(first),
+                            ..fail(Stage::BlankCheck, "not blank after erase".into())
+                        });
+                        continue;
+                    }
+                }
+                Err(e) => {
+                    report.failures.push(fail(Stage::BlankCheck, e.to_string()));
+                    continue;
+                }
+            }
+
+            let now = Instant::now();
+            if let Err(e) = bl.write(addr, data).await {
+                report.failures.push(fail(Stage::Write, e.to_string()));
+                continue;
+            }
+            report.write.add(data.len(), now.elapsed());
+
+            match readback(bl, &info, addr, &mut report.read).await {
+                Ok(rback) => {
+                    if let Some((mismatches, first)) = compare(data, &rback, addr) {
+                        report.failures.push(SectorFailure {
+                            mismatches,
+                            first_mismatch: Some(first),
+                            ..fail(Stage::Verify, "readback mismatch".into())
+                        });
+                    }
+                }
+                Err(e) => report.failures.push(fail(Stage::Verify, e.to_string())),
+            }
+        }
+        report.iterations_run += 1;
+    }
+    out.progress("test", cfg.iterations, cfg.iterations);
+
+    // Leave the tested sectors blank
+    for &sector in sectors.iter() {
+        let addr = info.start + (sector * info.erase_sz);
+        let _ = bl.erase(addr, info.erase_sz).await;
+    }
+
+    report.elapsed_ms = start.elapsed().as_millis() as u64;
+    Ok(report)
+}
+
+/// Read back one erase sector
+async fn readback(
+    bl: &BootloaderClient,
+    info: &AppPartitionInfo,
+    addr: u32,
+    tp: &mut Throughput,
+) -> Result<Vec<u8>, Error> {
+    let now = Instant::now();
+    let out = bl.read(addr, info.erase_sz).await?;
+    tp.add(out.len(), now.elapsed());
+    Ok(out)
+}
+
+/// Compare expected and actual data, returning the number of mismatched
+/// bytes and the address of the first one if they differ
+fn compare(expected: &[u8], actual: &[u8], addr: u32) -> Option<(u32, u32)> {
+    if expected == actual {
+        return None;
+    }
+    // A short read counts every missing byte as a mismatch
+    let mut mismatches = expected.len().abs_diff(actual.len()) as u32;
+    let mut first = None;
+    for (i, (e, a)) in expected.iter().zip(actual.iter()).enumerate() {
+        if e != a {
+            mismatches += 1;
+            first.get_or_insert(addr + i as u32);
+        }
+    }
+    let first = first.unwrap_or(addr + expected.len().min(actual.len()) as u32);
+    Some((mismatches, first))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_sectors, Pattern, MAX_SECTORS};
+
+    #[test]
+    fn sector_lists() {
+        assert_eq!(parse_sectors("all"), Ok(None));
+        assert_eq!(parse_sectors("2..5"), Ok(Some(vec![2, 3, 4])));
+        assert_eq!(parse_sectors("0x10..0x12"), Ok(Some(vec![16, 17])));
+        assert_eq!(parse_sectors("7"), Ok(Some(vec![7])));
+        assert_eq!(parse_sectors("3,1,0x2"), Ok(Some(vec![3, 1, 2])));
+        let last = MAX_SECTORS - 1;
+        assert_eq!(parse_sectors(&format!("{last}..{MAX_SECTORS}")), Ok(Some(vec![last])));
+    }
+
+    #[test]
+    fn bad_sector_lists() {
+        let bad = [
+            // Reversed and empty ranges
+            "5..2",
+            "3..3",
+            // Out of range
+            "0..4000000000",
+            "70000",
+            "1,99999",
+            "99999999999",
+            // Duplicates
+            "1,2,1",
+            "4,4",
+            // Empty lists and entries
+            "",
+            "1,,2",
+            "1,",
+            // Not numbers
+            "x..3",
+            "1..y",
+            "one",
+        ];
+        for s in bad {
+            assert!(parse_sectors(s).is_err(), "'{s}' was accepted");
+        }
+    }
+
+    #[test]
+    fn patterns() {
+        assert!(matches!(Pattern::parse("random"), Some(Pattern::Random { .. })));
+        for s in ["walk", "walking", "ones"] {
+            assert!(matches!(Pattern::parse(s), Some(Pattern::WalkingOnes)));
+        }
+        assert!(matches!(Pattern::parse("0xA5"), Some(Pattern::Fill(0xA5))));
+        assert!(matches!(Pattern::parse("55h"), Some(Pattern::Fill(0x55))));
+        assert!(matches!(Pattern::parse("255"), Some(Pattern::Fill(255))));
+        for s in ["256", "0x100", "", "zeros", "-1"] {
+            assert!(Pattern::parse(s).is_none(), "'{s}' was accepted");
+        }
+    }
+}
@@ -6,7 +6,7 @@ use scratch::BootMessage;
 use serde::{Deserialize, Serialize};
 pub mod scratch;
 
-#[derive(Debug, Serialize, Deserialize, Schema)]
+#[derive(Debug, Serialize, Deserialize, Schema, Clone, Copy, PartialEq)]
 pub struct AppPartitionInfo {
     pub start: u32,
     pub len: u32,
@@ -16,6 +16,66 @@ pub struct AppPartitionInfo {
     pub align: u32,
 }
 
+/// A bootloader version, compared field by field
+#[derive(Debug, Serialize, Deserialize, Schema, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Version {
+    pub major: u16,
+    pub minor: u16,
+    pub patch: u16,
+}
+
+impl Version {
+    /// Parse one number of a version, for [`pkg_version!`]
+    #[doc(hidden)]
+    pub const fn parse_part(s: &str) -> u16 {
+        let s = s.as_bytes();
+        assert!(!s.is_empty(), "empty version number");
+        let mut out: u16 = 0;
+        let mut i = 0;
+        while i < s.len() {
+            assert!(s[i].is_ascii_digit(), "version number isn't a number");
+            out = out * 10 + (s[i] - b'0') as u16;
+            i += 1;
+        }
+        out
+    }
+}
+
+/// The [`Version`] from the Cargo.toml of the crate this is used in,
+/// checked when it's built
+#[macro_export]
+macro_rules! pkg_version {
+    () => {
+        $crate::Version {
+            major: const { $crate::Version::parse_part(env!("CARGO_PKG_VERSION_MAJOR")) },
+            minor: const { $crate::Version::parse_part(env!("CARGO_PKG_VERSION_MINOR")) },
+            patch: const { $crate::Version::parse_part(env!("CARGO_PKG_VERSION_PATCH")) },
+        }
+    };
+}
+
+impl core::fmt::Display for Version {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+#[cfg(not(feature = "use-std"))]
+#[derive(Debug, Serialize, Deserialize, Schema)]
+pub struct BootloaderInfo<'a> {
+    pub version: Version,
+    /// Board the bootloader was built for, firmware bundles must match it
+    pub board: &'a str,
+}
+
+#[cfg(feature = "use-std")]
+#[derive(Debug, Serialize, Deserialize, Schema, Clone, PartialEq)]
+pub struct BootloaderInfo {
+    pub version: Version,
+    /// Board the bootloader was built for, firmware bundles must match it
+    pub board: String,
+}
+
 #[derive(Debug, Serialize, Deserialize, Schema)]
 pub struct FlashReadCommand {
     pub start: u32,
@@ -88,12 +148,87 @@ pub enum WriteError {
     HardwareError,
 }
 
+impl ReadError {
+    /// Stable numeric code for this error, for machine-readable output
+    pub const fn code(&self) -> u16 {
+        match self {
+            ReadError::OutOfRange { .. } => 0x0101,
+            ReadError::TooLarge { .. } => 0x0102,
+        }
+    }
+}
+
+impl core::fmt::Display for ReadError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ReadError::OutOfRange { req_start, req_end, mem_start, mem_end } => write!(
+                f,
+                "read range {req_start:08X}..{req_end:08X} outside of {mem_start:08X}..{mem_end:08X}"
+            ),
+            ReadError::TooLarge { req_len, max_len } => {
+                write!(f, "read of {req_len} bytes larger than max of {max_len}")
+            }
+        }
+    }
+}
+
+impl EraseError {
+    /// Stable numeric code for this error, for machine-readable output
+    pub const fn code(&self) -> u16 {
+        match self {
+            EraseError::OutOfRange => 0x0201,
+            EraseError::StartNotAligned => 0x0202,
+            EraseError::LenNotAligned => 0x0203,
+            EraseError::HardwareError => 0x0204,
+        }
+    }
+}
+
+impl core::fmt::Display for EraseError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(match self {
+            EraseError::OutOfRange => "erase range outside of app partition",
+            EraseError::StartNotAligned => "erase start not aligned to erase size",
+            EraseError::LenNotAligned => "erase length not a multiple of erase size",
+            EraseError::HardwareError => "flash hardware error during erase",
+        })
+    }
+}
+
+impl WriteError {
+    /// Stable numeric code for this error, for machine-readable output
+    pub const fn code(&self) -> u16 {
+        match self {
+            WriteError::OutOfRange => 0x0301,
+            WriteError::StartNotAligned => 0x0302,
+            WriteError::LenNotAligned => 0x0303,
+            WriteError::NeedsErase => 0x0304,
+            WriteError::HardwareError => 0x0305,
+        }
+    }
+}
+
+impl core::fmt::Display for WriteError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(match self {
+            WriteError::OutOfRange => "write range outside of app partition",
+            WriteError::StartNotAligned => "write start not aligned to write size",
+            WriteError::LenNotAligned => "write length not a multiple of write size",
+            WriteError::NeedsErase => "write target is not erased",
+            WriteError::HardwareError => "flash hardware error during write",
+        })
+    }
+}
+
 #[cfg(not(feature = "use-std"))]
 pub type ReadResult<'a> = Result<DataChunk<'a>, ReadError>;
 
 #[cfg(feature = "use-std")]
 pub type ReadResult = Result<DataChunk, ReadError>;
 
+/// CRC-32/ISO-HDLC of a flash range, as computed by `crc::CRC_32_ISO_HDLC`
+pub type HashResult = Result<u32, ReadError>;
+
 pub type EraseResult = Result<(), EraseError>;
 pub type WriteResult = Result<(), WriteError>;
 
@@ -106,6 +241,19 @@ pub type OptBootMessage = Option<BootMessage>;
 #[derive(Debug, Serialize, Deserialize, Schema)]
 pub struct FailedSanityCheck;
 
+impl FailedSanityCheck {
+    /// Stable numeric code for this error, for machine-readable output
+    pub const fn code(&self) -> u16 {
+        0x0401
+    }
+}
+
+impl core::fmt::Display for FailedSanityCheck {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str("app image failed the bootloader sanity check")
+    }
+}
+
 pub type BootResult = Result<(), FailedSanityCheck>;
 
 // ---
@@ -123,6 +271,9 @@ endpoints! {
     | ReadFlashEndpoint         | FlashReadCommand      | ReadResult<'a>        | "bootloader/flash/read"       | cfg(not(feature = "use-std")) |
     | ReadFlashEndpoint         | FlashReadCommand      | ReadResult            | "bootloader/flash/read"       | cfg(feature = "use-std")      |
     | GetAppFlashInfoEndpoint   | ()                    | AppPartitionInfo      | "bootloader/flash/info"       |                               |
+    | GetBootloaderInfoEndpoint | ()                    | BootloaderInfo<'a>    | "bootloader/info"             | cfg(not(feature = "use-std")) |
+    | GetBootloaderInfoEndpoint | ()                    | BootloaderInfo        | "bootloader/info"             | cfg(feature = "use-std")      |
+    | HashFlashEndpoint         | FlashReadCommand      | HashResult            | "bootloader/flash/crc32"      |                               |
     | EraseFlashEndpoint        | FlashEraseCommand     | EraseResult           | "bootloader/flash/erase"      |                               |
     | WriteFlashEndpoint        | FlashWriteCommand<'a> | WriteResult           | "bootloader/flash/write"      | cfg(not(feature = "use-std")) |
     | WriteFlashEndpoint        | FlashWriteCommand     | WriteResult           | "bootloader/flash/write"      | cfg(feature = "use-std")      |
@@ -4,6 +4,23 @@ use serde::{Deserialize, Serialize};
 
 pub const BOOT_KEY: Key = Key::for_path::<BootMessage>("boot message");
 
+/// Register state captured by the app's HardFault handler
+#[derive(Debug, Serialize, Deserialize, Schema, Clone, Copy, PartialEq)]
+pub struct FaultRegs {
+    pub pc: u32,
+    pub lr: u32,
+    pub sp: u32,
+    pub xpsr: u32,
+    /// Configurable Fault Status Register
+    pub cfsr: u32,
+    /// HardFault Status Register
+    pub hfsr: u32,
+    /// MemManage Fault Address Register
+    pub mmfar: u32,
+    /// BusFault Address Register
+    pub bfar: u32,
+}
+
 #[cfg(not(feature = "use-std"))]
 #[derive(Debug, Serialize, Deserialize, Schema, Clone)]
 pub enum BootMessage<'a> {
@@ -17,7 +34,11 @@ pub enum BootMessage<'a> {
     BootPanicked {
         uptime: u64,
         reason: &'a [u8],
-    }
+    },
+    AppFaulted {
+        uptime: u64,
+        regs: FaultRegs,
+    },
 }
 
 #[cfg(feature = "use-std")]
@@ -33,5 +54,9 @@ pub enum BootMessage {
     BootPanicked {
         uptime: u64,
         reason: Vec<u8>,
-    }
+    },
+    AppFaulted {
+        uptime: u64,
+        regs: FaultRegs,
+    },
 }
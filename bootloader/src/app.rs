@@ -1,11 +1,11 @@
 //! A basic postcard-rpc/poststation-compatible application
 
 use crate::handlers::{
-    erase_flash, get_boot_message, get_info, go_boot, read_flash, unique_id, write_flash, reboot_reason
+    erase_flash, get_boot_message, get_bootloader_info, get_info, go_boot, hash_flash, read_flash, unique_id, write_flash, reboot_reason
 };
 use bootloader_icd::{
     scratch::BootMessage, BootloadEndpoint, EraseFlashEndpoint, GetAppFlashInfoEndpoint,
-    GetBootMessageEndpoint, GetUniqueIdEndpoint, ReadFlashEndpoint, WriteFlashEndpoint, RebootReasonEndpoint
+    GetBootMessageEndpoint, GetBootloaderInfoEndpoint, GetUniqueIdEndpoint, HashFlashEndpoint, ReadFlashEndpoint, WriteFlashEndpoint, RebootReasonEndpoint
 };
 use bootloader_icd::{ENDPOINT_LIST, TOPICS_IN_LIST, TOPICS_OUT_LIST};
 use embassy_nrf::{
@@ -121,6 +121,8 @@ define_dispatch! {
         | GetUniqueIdEndpoint       | blocking  | unique_id                     |
         | ReadFlashEndpoint         | blocking  | read_flash                    |
         | GetAppFlashInfoEndpoint   | blocking  | get_info                      |
+        | GetBootloaderInfoEndpoint | blocking  | get_bootloader_info           |
+        | HashFlashEndpoint         | blocking  | hash_flash                    |
         | EraseFlashEndpoint        | async     | erase_flash                   |
         | WriteFlashEndpoint        | blocking  | write_flash                   |
         | GetBootMessageEndpoint    | blocking  | get_boot_message              |
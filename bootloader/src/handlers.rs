@@ -5,12 +5,18 @@ use embassy_nrf::{nvmc::Nvmc, pac::POWER};
 use embassy_time::Timer;
 use embedded_storage::nor_flash::NorFlash;
 use postcard_rpc::{header::VarHeader, server::Sender};
-use bootloader_icd::{scratch::BootMessage, AppPartitionInfo, BootloadEndpoint, DataChunk, EraseError, EraseResult, FailedSanityCheck, FlashEraseCommand, FlashReadCommand, FlashWriteCommand, ReadError, ReadResult, WriteError, WriteResult};
+use bootloader_icd::{pkg_version, scratch::BootMessage, AppPartitionInfo, BootloadEndpoint, BootloaderInfo, DataChunk, EraseError, EraseResult, FailedSanityCheck, FlashEraseCommand, FlashReadCommand, FlashWriteCommand, HashResult, ReadError, ReadResult, WriteError, WriteResult};
+use crc::{Crc, CRC_32_ISO_HDLC};
 
 use crate::{app::{AppTx, Context, TaskContext}, storage::{app_sanity_check, write_message, APP_FLASH}};
 
 const CHUNK_LIMIT: usize = 512;
 
+/// Reported to the host, so firmware bundles built for another board are refused
+const BOARD: &str = "nrf52840";
+
+static CRC32: Crc<u32> = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+
 /// This is an example of a BLOCKING handler.
 pub fn unique_id(context: &mut Context, _header: VarHeader, _arg: ()) -> u64 {
     context.unique_id
@@ -28,6 +34,13 @@ pub fn get_info(context: &mut Context, _header: VarHeader, _arg: ()) -> AppParti
     }
 }
 
+pub fn get_bootloader_info(_context: &mut Context, _header: VarHeader, _arg: ()) -> BootloaderInfo<'static> {
+    BootloaderInfo {
+        version: pkg_version!(),
+        board: BOARD,
+    }
+}
+
 fn is_inbounds(addr: u32, len: u32) -> bool {
     let rstart = addr as usize;
     let rlen = len as usize;
@@ -89,6 +102,29 @@ pub fn read_flash(context: &mut Context, _header: VarHeader, arg: FlashReadComma
     Ok(DataChunk { data: bout })
 }
 
+/// Like read_flash, but returns a CRC of the range instead of its contents,
+/// so the host can check whether a whole sector needs rewriting
+pub fn hash_flash(_context: &mut Context, _header: VarHeader, arg: FlashReadCommand) -> HashResult {
+    let (ptr, _flen) = APP_FLASH.get_ptr_len();
+    let FlashReadCommand { start, len } = arg;
+
+    if !is_inbounds(start, len) {
+        let (mem_start, mem_end) = frange();
+        return Err(ReadError::OutOfRange {
+            req_start: start,
+            req_end: start.saturating_add(len),
+            mem_start,
+            mem_end,
+        });
+    }
+
+    let offset = start as usize - ptr as usize;
+    compiler_fence(Ordering::SeqCst);
+    // We checked all the ranges and stuff above
+    let sli = unsafe { slice::from_raw_parts(ptr.add(offset), len as usize) };
+    Ok(CRC32.checksum(sli))
+}
+
 pub async fn erase_flash(context: &mut Context, _header: VarHeader, arg: FlashEraseCommand) -> EraseResult {
     let FlashEraseCommand { start, len, force } = arg;
 
@@ -76,6 +76,7 @@ async fn main(spawner: Spawner) {
             BootMessage::BootAttempted => {}
             BootMessage::AppPanicked { .. } => {}
             BootMessage::BootPanicked { .. } => {}
+            BootMessage::AppFaulted { .. } => {}
         },
         None => {
             // Does the app look reasonable?
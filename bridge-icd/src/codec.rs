@@ -0,0 +1,179 @@
+//! Encoding and decoding postcard-rpc frames
+//!
+//! A frame is a [`VarHeader`] followed by one postcard-encoded message. The
+//! header's key says what the message is: a topic's message, or an
+//! endpoint's request or response, hashed down to 1, 2, 4 or 8 bytes.
+//!
+//! Encoders take the key size to use. Decoders accept any key size, and
+//! check it against the expected key shrunk to the same size. The `take_*`
+//! decoders leave anything after the message in [`Frame::remain`], as the
+//! radio link does with proxy fragments, while the `decode_*` decoders
+//! insist the frame is nothing but the message.
+
+use postcard_rpc::{
+    header::{VarHeader, VarKey, VarKeyKind, VarSeq},
+    Endpoint, Key, Topic,
+};
+use serde::{Deserialize, Serialize};
+
+/// A decoded message, with its header and anything that followed it
+#[derive(Debug)]
+pub struct Frame<'a, T> {
+    pub msg: T,
+    pub hdr: VarHeader,
+    pub remain: &'a [u8],
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum DecodeError {
+    /// The frame doesn't start with a whole header
+    BadHeader,
+    /// The header is for some other message
+    KeyMismatch,
+    /// The message after the header doesn't deserialize
+    Deserialize,
+    /// This many bytes were left over after the message
+    TrailingBytes(usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum EncodeError {
+    /// The header and message don't fit in the buffer
+    BufferFull,
+    /// The message can't be serialized
+    Serialize,
+}
+
+/// Write a frame carrying `msg` as `key`, shrunk to `kind`, returning how
+/// many bytes of `buf` it took
+pub fn encode<M>(
+    key: Key,
+    kind: VarKeyKind,
+    seq_no: VarSeq,
+    msg: &M,
+    buf: &mut [u8],
+) -> Result<usize, EncodeError>
+where
+    M: Serialize + ?Sized,
+{
+    let mut key = VarKey::Key8(key);
+    key.shrink_to(kind);
+    let hdr = VarHeader { key, seq_no };
+    let (hdr, remain) = hdr.write_to_slice(buf).ok_or(EncodeError::BufferFull)?;
+    let used = postcard::to_slice(msg, remain).map_err(|e| match e {
+        postcard::Error::SerializeBufferFull => EncodeError::BufferFull,
+        _ => EncodeError::Serialize,
+    })?;
+    Ok(hdr.len() + used.len())
+}
+
+/// Read a frame carrying a message sent as `key`, leaving anything after it
+/// in [`Frame::remain`]
+pub fn take<'a, M>(key: Key, data: &'a [u8]) -> Result<Frame<'a, M>, DecodeError>
+where
+    M: Deserialize<'a>,
+{
+    let (hdr, remain) = VarHeader::take_from_slice(data).ok_or(DecodeError::BadHeader)?;
+    let mut expected = VarKey::Key8(key);
+    expected.shrink_to(kind_of(&hdr.key));
+    if hdr.key != expected {
+        return Err(DecodeError::KeyMismatch);
+    }
+    let (msg, remain) =
+        postcard::take_from_bytes::<M>(remain).map_err(|_| DecodeError::Deserialize)?;
+    Ok(Frame { msg, hdr, remain })
+}
+
+/// Read a frame that holds exactly one message sent as `key`
+pub fn decode<'a, M>(key: Key, data: &'a [u8]) -> Result<(VarHeader, M), DecodeError>
+where
+    M: Deserialize<'a>,
+{
+    let frame = take(key, data)?;
+    match frame.remain.len() {
+        0 => Ok((frame.hdr, frame.msg)),
+        n => Err(DecodeError::TrailingBytes(n)),
+    }
+}
+
+pub fn encode_topic<T>(
+    kind: VarKeyKind,
+    seq_no: VarSeq,
+    msg: &T::Message,
+    buf: &mut [u8],
+) -> Result<usize, EncodeError>
+where
+    T: Topic,
+    T::Message: Serialize,
+{
+    encode(T::TOPIC_KEY, kind, seq_no, msg, buf)
+}
+
+pub fn take_topic<'a, T>(data: &'a [u8]) -> Result<Frame<'a, T::Message>, DecodeError>
+where
+    T: Topic,
+    T::Message: Deserialize<'a>,
+{
+    take(T::TOPIC_KEY, data)
+}
+
+pub fn decode_topic<'a, T>(data: &'a [u8]) -> Result<(VarHeader, T::Message), DecodeError>
+where
+    T: Topic,
+    T::Message: Deserialize<'a>,
+{
+    decode(T::TOPIC_KEY, data)
+}
+
+pub fn encode_request<E>(
+    kind: VarKeyKind,
+    seq_no: VarSeq,
+    msg: &E::Request,
+    buf: &mut [u8],
+) -> Result<usize, EncodeError>
+where
+    E: Endpoint,
+    E::Request: Serialize,
+{
+    encode(E::REQ_KEY, kind, seq_no, msg, buf)
+}
+
+pub fn decode_request<'a, E>(data: &'a [u8]) -> Result<(VarHeader, E::Request), DecodeError>
+where
+    E: Endpoint,
+    E::Request: Deserialize<'a>,
+{
+    decode(E::REQ_KEY, data)
+}
+
+pub fn encode_response<E>(
+    kind: VarKeyKind,
+    seq_no: VarSeq,
+    msg: &E::Response,
+    buf: &mut [u8],
+) -> Result<usize, EncodeError>
+where
+    E: Endpoint,
+    E::Response: Serialize,
+{
+    encode(E::RESP_KEY, kind, seq_no, msg, buf)
+}
+
+pub fn decode_response<'a, E>(data: &'a [u8]) -> Result<(VarHeader, E::Response), DecodeError>
+where
+    E: Endpoint,
+    E::Response: Deserialize<'a>,
+{
+    decode(E::RESP_KEY, data)
+}
+
+fn kind_of(key: &VarKey) -> VarKeyKind {
+    match key {
+        VarKey::Key1(_) => VarKeyKind::Key1,
+        VarKey::Key2(_) => VarKeyKind::Key2,
+        VarKey::Key4(_) => VarKeyKind::Key4,
+        VarKey::Key8(_) => VarKeyKind::Key8,
+    }
+}
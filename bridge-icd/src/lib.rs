@@ -3,11 +3,16 @@
 pub use postcard_rpc;
 use postcard_rpc::{
     endpoints,
-    header::{VarHeader, VarKey, VarSeq},
-    topic, topics, Topic, TopicDirection,
+    header::{VarKeyKind, VarSeq},
+    topic, topics, TopicDirection,
 };
+use crc::{Crc, CRC_32_ISO_HDLC};
 use postcard_schema::Schema;
-use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde::{Deserialize, Serialize};
+
+pub mod codec;
+pub mod link;
+pub mod time;
 
 #[derive(Debug, Serialize, Deserialize, Schema)]
 pub struct SleepMillis {
@@ -32,6 +37,37 @@ pub struct Dummy {
 
 pub use poststation_fw_icd::bridging::{BridgeTable, ProxyMessage, ProxyResult, ProxyError};
 
+/// Why a message from the host wasn't delivered to a node
+///
+/// `poststation_fw_icd`'s [`ProxyError`] can only say the node is unknown, and
+/// is part of the `Host2BridgeEndpoint` key poststation expects, so the other
+/// outcomes are reported on [`ProxyOutcomeTopic`] instead
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Schema)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum DeliveryError {
+    /// No node with this serial has joined
+    UnknownDevice,
+    /// The node can't reassemble a message this long
+    TooLarge(TooLarge),
+    /// The node didn't acknowledge every fragment, even after
+    /// [`PROXY_RETRIES`] resends
+    NoAck,
+}
+
+pub type DeliveryResult = Result<(), DeliveryError>;
+
+/// How delivering a message sent with `Host2BridgeEndpoint` went
+///
+/// The endpoint replies as soon as the bridge has taken the message, this
+/// follows once the node has acknowledged it or the bridge gave up
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Schema)]
+pub struct ProxyOutcome {
+    pub serial: [u8; 8],
+    /// Sequence number of the `Host2BridgeEndpoint` request
+    pub seq_no: u32,
+    pub result: DeliveryResult,
+}
+
 // ---
 
 // Endpoints spoken by our device
@@ -39,15 +75,26 @@ pub use poststation_fw_icd::bridging::{BridgeTable, ProxyMessage, ProxyResult, P
 // GetUniqueIdEndpoint is mandatory, the others are examples
 endpoints! {
     list = ENDPOINT_LIST;
-    | EndpointTy                | RequestTy         | ResponseTy            | Path                          | Cfg                               |
-    | ----------                | ---------         | ----------            | ----                          | ---                               |
-    | GetUniqueIdEndpoint       | ()                | u64                   | "poststation/unique_id/get"   |                                   |
-    | Host2BridgeEndpoint       | ProxyMessage<'a>  | ProxyResult           | "poststation/host/to/bridge"  | cfg(not(feature = "use-std"))     |
-    | Host2BridgeEndpoint       | ProxyMessage      | ProxyResult           | "poststation/host/to/bridge"  | cfg(feature = "use-std")          |
-    | SleepEndpoint             | SleepMillis       | SleptMillis           | "template/sleep"              |                                   |
-    | SetLedEndpoint            | LedState          | ()                    | "template/led/set"            |                                   |
-    | GetLedEndpoint            | ()                | LedState              | "template/led/get"            |                                   |
-    | RebootToBootloader        | ()                | ()                    | "curacao/postboot/reset"      |                                   |
+    | EndpointTy                | RequestTy         | ResponseTy            | Path                              | Cfg                               |
+    | ----------                | ---------         | ----------            | ----                              | ---                               |
+    | GetUniqueIdEndpoint       | ()                | u64                   | "poststation/unique_id/get"       |                                   |
+    | Host2BridgeEndpoint       | ProxyMessage<'a>  | ProxyResult           | "poststation/host/to/bridge"      | cfg(not(feature = "use-std"))     |
+    | Host2BridgeEndpoint       | ProxyMessage      | ProxyResult           | "poststation/host/to/bridge"      | cfg(feature = "use-std")          |
+    | SleepEndpoint             | SleepMillis       | SleptMillis           | "template/sleep"                  |                                   |
+    | SetLedEndpoint            | LedState          | ()                    | "template/led/set"                |                                   |
+    | GetLedEndpoint            | ()                | LedState              | "template/led/get"                |                                   |
+    | RebootToBootloader        | ()                | ()                    | "curacao/postboot/reset"          |                                   |
+    | GetNodeLinksEndpoint      | ()                | NodeLinks             | "poststation/bridge/links"        |                                   |
+    | AllowNodeEndpoint         | [u8; 8]           | AllowResult           | "poststation/bridge/allow/add"    |                                   |
+    | DisallowNodeEndpoint      | [u8; 8]           | AllowResult           | "poststation/bridge/allow/remove" |                                   |
+    | GetAllowListEndpoint      | ()                | AllowList             | "poststation/bridge/allow/list"   |                                   |
+    | OpenPairingEndpoint       | PairingWindow     | ()                    | "poststation/bridge/pairing"      |                                   |
+    | GetLinkStatsEndpoint      | [u8; 8]           | Option<LinkStats>     | "poststation/bridge/link/stats"   |                                   |
+    | Host2GroupEndpoint        | GroupMessage      | GroupDelivery         | "poststation/host/to/group"       |                                   |
+    | JoinGroupEndpoint         | GroupMember       | GroupResult           | "poststation/bridge/group/add"    |                                   |
+    | LeaveGroupEndpoint        | GroupMember       | GroupResult           | "poststation/bridge/group/remove" |                                   |
+    | GetGroupsEndpoint         | ()                | GroupList             | "poststation/bridge/groups"       |                                   |
+    | SetTimeEndpoint           | WallTime          | ()                    | "poststation/bridge/time/set"     |                                   |
 }
 
 // incoming topics handled by our device
@@ -63,20 +110,364 @@ topics! {
 topics! {
     list = TOPICS_OUT_LIST;
     direction = TopicDirection::ToClient;
-    | TopicTy                   | MessageTy         | Path                          | Cfg                               |
-    | -------                   | ---------         | ----                          | ---                               |
-    | Bridge2HostTopic          | ProxyMessage<'a>  | "poststation/bridge/to/host"  | cfg(not(feature = "use-std"))     |
-    | Bridge2HostTopic          | ProxyMessage      | "poststation/bridge/to/host"  | cfg(feature = "use-std")          |
-    | BridgeTableTopic          | BridgeTable       | "poststation/bridge/table"    |                                   |
+    | TopicTy                   | MessageTy         | Path                                 | Cfg                               |
+    | -------                   | ---------         | ----                                 | ---                               |
+    | Bridge2HostTopic          | ProxyMessage<'a>  | "poststation/bridge/to/host"         | cfg(not(feature = "use-std"))     |
+    | Bridge2HostTopic          | ProxyMessage      | "poststation/bridge/to/host"         | cfg(feature = "use-std")          |
+    | BridgeTableTopic          | BridgeTable       | "poststation/bridge/table"           |                                   |
+    | JoinRejectedTopic         | JoinRejected      | "poststation/bridge/rejected"        |                                   |
+    | LinkStatsTopic            | LinkStats         | "poststation/bridge/link"            |                                   |
+    | ProxyOutcomeTopic         | ProxyOutcome      | "poststation/bridge/proxy/outcome"   |                                   |
+}
+
+// ---
+// Join handshake
+
+/// Version of the node <-> bridge protocol. Bumped whenever the messages
+/// below change incompatibly, nodes and bridges must agree on it exactly
+pub const PROTOCOL_VERSION: u16 = 7;
+
+/// Largest packet payload the ESB radios carry
+pub const ESB_MAX_PAYLOAD: u8 = 252;
+
+/// Most bytes a proxy fragment's framing can take: a [`LINK_KEY`]/Seq2
+/// header (5), a `Proxy` message with every varint at its longest (11), and the
+/// session seal around it ([`link::SEAL_OVERHEAD`])
+pub const PROXY_OVERHEAD: usize = 16 + link::SEAL_OVERHEAD;
+
+/// The largest fragment payload that still fits in one ESB packet
+pub const MAX_FRAG_SIZE: usize = ESB_MAX_PAYLOAD as usize - PROXY_OVERHEAD;
+
+/// Smallest fragment payload worth sending
+pub const MIN_FRAG_SIZE: u8 = 16;
+
+/// Version of the firmware on either end of a link, the same type the
+/// bootloader reports its version with
+pub use bootloader_icd::{pkg_version, Version as FirmwareVersion};
+
+/// Optional protocol features, as a set of bit flags
+///
+/// Each side offers what it supports, and only the features both support
+/// are used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Schema)]
+pub struct Capabilities(pub u32);
+
+impl Capabilities {
+    pub const NONE: Self = Self(0);
+
+    pub const fn contains(&self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub const fn intersect(&self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+
+    pub const fn union(&self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+}
+
+/// What one side of the link supports, sent by the node when joining
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Schema)]
+pub struct JoinParams {
+    pub protocol: u16,
+    pub firmware: FirmwareVersion,
+    /// Largest fragment payload this side can send and receive
+    pub max_frag: u8,
+    /// Size of this side's reassembly buffer, the largest message it can receive
+    pub frag_capacity: u16,
+    pub caps: Capabilities,
+}
+
+/// The parameters both sides use once a node has joined
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Schema)]
+pub struct LinkParams {
+    pub max_frag: u8,
+    /// Largest message either side can reassemble
+    pub frag_capacity: u16,
+    pub caps: Capabilities,
+}
+
+/// Why a bridge refused to let a node join
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Schema)]
+pub enum JoinReject {
+    /// The node speaks a different protocol version
+    Protocol { bridge: u16, node: u16 },
+    /// Fragments would be smaller than [`MIN_FRAG_SIZE`]
+    FragSize { max_frag: u8 },
+    /// One side can't reassemble even one fragment
+    FragCapacity { frag_capacity: u16 },
+    /// Every pipe is in use
+    TableFull,
+    /// The bridge isn't pairing and the node isn't on its allowlist
+    NotAllowed,
+}
+
+impl JoinParams {
+    /// Negotiate the parameters to use between a bridge (`self`) and a node
+    pub fn negotiate(&self, node: &JoinParams) -> Result<LinkParams, JoinReject> {
+        if node.protocol != self.protocol {
+            return Err(JoinReject::Protocol {
+                bridge: self.protocol,
+                node: node.protocol,
+            });
+        }
+        let max_frag = self.max_frag.min(node.max_frag);
+        if max_frag < MIN_FRAG_SIZE {
+            return Err(JoinReject::FragSize { max_frag });
+        }
+        let frag_capacity = self.frag_capacity.min(node.frag_capacity);
+        if frag_capacity < max_frag as u16 {
+            return Err(JoinReject::FragCapacity { frag_capacity });
+        }
+        // A message can't be split into more parts than an ack can describe
+        let frag_capacity = frag_capacity.min(max_frag as u16 * MAX_FRAGS as u16);
+        Ok(LinkParams {
+            max_frag,
+            frag_capacity,
+            caps: self.caps.intersect(node.caps),
+        })
+    }
+}
+
+/// A message longer than the other side can reassemble
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Schema)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct TooLarge {
+    pub len: usize,
+    pub max: usize,
+}
+
+impl LinkParams {
+    /// How many fragments a `len` byte message is sent as
+    pub fn frag_count(&self, len: usize) -> Result<u8, TooLarge> {
+        let max = self.frag_capacity as usize;
+        if len > max {
+            return Err(TooLarge { len, max });
+        }
+        // `negotiate` keeps `frag_capacity` within MAX_FRAGS fragments
+        Ok(len.div_ceil(self.max_frag as usize) as u8)
+    }
+}
+
+/// A joined node, as reported to the host
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Schema)]
+pub struct NodeLink {
+    pub serial: [u8; 8],
+    pub pipe: u8,
+    pub firmware: FirmwareVersion,
+    pub params: LinkParams,
+}
+
+#[derive(Debug, Serialize, Deserialize, Schema)]
+pub struct NodeLinks {
+    pub nodes: heapless::Vec<NodeLink, 7>,
+}
+
+// ---
+// Pairing
+
+/// Most serials the bridge's allowlist holds
+pub const MAX_ALLOWED: usize = 32;
+
+/// How long the bridge button opens the pairing window for
+pub const BUTTON_PAIRING_SECS: u16 = 60;
+
+/// Serials allowed to join the bridge, kept in its flash
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Schema)]
+pub struct AllowList {
+    pub serials: heapless::Vec<[u8; 8], MAX_ALLOWED>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Schema)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum AllowError {
+    /// Already holding [`MAX_ALLOWED`] serials
+    Full,
+    /// The serial was never on the list
+    NotFound,
+    /// Writing the list to flash failed, it is unchanged
+    Storage,
+}
+
+pub type AllowResult = Result<(), AllowError>;
+
+/// Enroll every unknown node that joins in the next `secs` seconds,
+/// zero closes the window
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Schema)]
+pub struct PairingWindow {
+    pub secs: u16,
+}
+
+/// Why a join was refused, as reported to the host
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Schema)]
+pub enum JoinRefusal {
+    /// The request wasn't sealed with the key for its serial, it is from
+    /// another network or a forgery
+    BadSeal,
+    /// The node was told why, see [`JoinReject`]
+    Rejected(JoinReject),
+}
+
+/// A node that tried to join and was turned away
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Schema)]
+pub struct JoinRejected {
+    pub serial: [u8; 8],
+    pub reason: JoinRefusal,
+}
+
+// ---
+// Time
+
+/// Wall clock time, in microseconds since the Unix epoch
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Schema)]
+pub struct WallTime {
+    pub unix_us: u64,
+}
+
+// ---
+// Groups
+
+/// Most groups the bridge keeps
+pub const MAX_GROUPS: usize = 8;
+
+/// Most nodes in one group, joined or not
+pub const MAX_GROUP_MEMBERS: usize = 16;
+
+/// Longest group name, in bytes
+pub const MAX_GROUP_NAME: usize = 16;
+
+pub type GroupName = heapless::String<MAX_GROUP_NAME>;
+
+/// Who a [`GroupMessage`] goes to
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Schema)]
+pub enum Recipients {
+    /// Every node joined right now
+    All,
+    /// Every member of the group, which need not be joined
+    Group(GroupName),
+}
+
+/// A message from the host for several nodes at once
+///
+/// Owned, unlike `ProxyMessage`, so the bridge can deliver it from a task
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Schema)]
+pub struct GroupMessage {
+    pub to: Recipients,
+    pub msg: heapless::Vec<u8, FRAG_BUF_SIZE>,
+}
+
+/// How delivering a [`GroupMessage`] to one node went
+#[derive(Debug, Serialize, Deserialize, Schema)]
+pub struct Delivery {
+    pub serial: [u8; 8],
+    pub result: DeliveryResult,
+}
+
+/// One [`Delivery`] per recipient
+#[derive(Debug, Serialize, Deserialize, Schema)]
+pub struct GroupDelivery {
+    pub nodes: heapless::Vec<Delivery, MAX_GROUP_MEMBERS>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Schema)]
+pub struct GroupMember {
+    pub group: GroupName,
+    pub serial: [u8; 8],
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Schema)]
+pub enum GroupError {
+    /// Already keeping [`MAX_GROUPS`] groups
+    TooManyGroups,
+    /// The group already has [`MAX_GROUP_MEMBERS`] members
+    GroupFull,
+    /// No such group, or the node isn't in it
+    NotFound,
+}
+
+pub type GroupResult = Result<(), GroupError>;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Schema)]
+pub struct Group {
+    pub name: GroupName,
+    pub members: heapless::Vec<[u8; 8], MAX_GROUP_MEMBERS>,
+}
+
+/// The groups the bridge keeps. They are held in RAM, so the host has to set
+/// them up again after the bridge restarts
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Schema)]
+pub struct GroupList {
+    pub groups: heapless::Vec<Group, MAX_GROUPS>,
+}
+
+// ---
+// Link statistics
+
+/// What a node counts about its own radio since it started, sent in every
+/// keepalive
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Schema)]
+pub struct NodeRadioStats {
+    /// Packets sent again because no ack came back
+    pub retransmits: u32,
+    /// Packets given up on after the last retransmit
+    pub max_attempts: u32,
+}
+
+/// Signal strength of packets from a node, in dBm
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Schema)]
+pub struct RssiStats {
+    pub last: i8,
+    /// A moving average over roughly the last eight packets
+    pub avg: i8,
+    pub worst: i8,
+}
+
+/// How the link to one node is doing, counted since it first joined
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Schema)]
+pub struct LinkStats {
+    pub serial: [u8; 8],
+    pub pipe: u8,
+    /// `None` until a packet arrives after the join
+    pub rssi: Option<RssiStats>,
+    /// Frames that opened and decoded
+    pub received: u32,
+    /// Frames that didn't open, or didn't decode once opened
+    pub bad_frames: u32,
+    /// Joins, including the first
+    pub joins: u32,
+    /// Fragments of messages from the node
+    pub frags: FragStats,
+    /// As reported in the node's latest keepalive
+    pub node: NodeRadioStats,
+}
+
+/// Identifies one fragment of a proxied message
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Schema)]
+pub struct FragHeader {
+    /// Chosen by the sender, different for each message in a row
+    pub msg_id: u16,
+    pub part: u8,
+    pub ttl_parts: u8,
+    /// [`PROXY_CRC`] of the whole reassembled message
+    pub crc: u32,
 }
 
 topic!(N2BTopic, Node2Bridge, "node/to/bridge");
 
 #[derive(Debug, Serialize, Deserialize, Schema)]
 pub enum Node2Bridge {
-    Initialize { serial: [u8; 8] },
-    Keepalive { serial: [u8; 8] },
-    Proxy { part: u8, ttl_parts: u8 },
+    Initialize { serial: [u8; 8], params: JoinParams },
+    /// `seq` is echoed in the reply, see [`time`]
+    Keepalive {
+        serial: [u8; 8],
+        radio: NodeRadioStats,
+        seq: u8,
+    },
+    Proxy { hdr: FragHeader },
+    /// Acknowledges fragments of a `Bridge2Node::Proxy` message
+    ProxyAck { ack: FragAck },
     Nop,
 }
 
@@ -84,124 +475,350 @@ topic!(B2NTopic, Bridge2Node, "bridge/to/node");
 
 #[derive(Debug, Serialize, Deserialize, Schema)]
 pub enum Bridge2Node {
-    InitializeAck { serial: [u8; 8], use_pipe: u8 },
-    Keepalive { serial: [u8; 8] },
-    Proxy { part: u8, ttl_parts: u8 },
+    /// `node_iv` echoes the join request this answers, see [`link`]
+    InitializeAck {
+        serial: [u8; 8],
+        use_pipe: u8,
+        params: LinkParams,
+        node_iv: [u8; 8],
+    },
+    InitializeReject {
+        serial: [u8; 8],
+        reason: JoinReject,
+        node_iv: [u8; 8],
+    },
+    /// `time_us` is the bridge's wall clock when keepalive `seq` arrived,
+    /// if the host has set it, see [`time`]
+    Keepalive {
+        serial: [u8; 8],
+        seq: u8,
+        time_us: Option<u64>,
+    },
+    Proxy { hdr: FragHeader },
+    /// Acknowledges fragments of a `Node2Bridge::Proxy` message
+    ProxyAck { ack: FragAck },
     Reset,
 }
 
-pub struct TopicExtract<'a, T> {
-    pub msg: T,
-    pub hdr: VarHeader,
-    pub remain: &'a [u8],
+/// Key size of frames on the radio link, where every byte counts
+pub const LINK_KEY: VarKeyKind = VarKeyKind::Key2;
+
+/// Sequence number of every join request and reply
+///
+/// Their nonces are fixed by the IV in the clear header, and a node repeats
+/// its request, and the bridge its reply, until the join goes through. Each
+/// repeat has to be the same bytes as the first, so nothing may count here
+pub const JOIN_SEQ: VarSeq = VarSeq::Seq2(0);
+
+/// Encode and seal a join request into `buf`, returning the frame's length
+pub fn seal_join_request<C: link::LinkCipher>(
+    cipher: &C,
+    key: &link::LinkKey,
+    hello: &link::Hello,
+    msg: &Node2Bridge,
+    buf: &mut [u8],
+) -> Option<usize> {
+    let body = buf.get_mut(link::HELLO_LEN..)?;
+    let used = codec::encode_topic::<N2BTopic>(LINK_KEY, JOIN_SEQ, msg, body).ok()?;
+    hello.seal(cipher, key, buf, used).ok()
+}
+
+/// Encode and seal a join reply into `buf`, returning the frame's length
+///
+/// The same `reply` with the same `welcome` always gives the same frame, so
+/// a repeated join can be answered again without reusing a nonce
+pub fn seal_join_reply<C: link::LinkCipher>(
+    cipher: &C,
+    key: &link::LinkKey,
+    welcome: &link::Welcome,
+    reply: &Bridge2Node,
+    buf: &mut [u8],
+) -> Option<usize> {
+    let body = buf.get_mut(link::WELCOME_LEN..)?;
+    let used = codec::encode_topic::<B2NTopic>(LINK_KEY, JOIN_SEQ, reply, body).ok()?;
+    welcome.seal(cipher, key, buf, used).ok()
 }
 
-pub fn extract_topic2<T>(data: &[u8]) -> Option<TopicExtract<'_, T::Message>>
-where
-    T: Topic,
-    T::Message: DeserializeOwned,
-{
-    let (hdr, remain) = VarHeader::take_from_slice(data)?;
-    if hdr.key != VarKey::Key2(T::TOPIC_KEY2) {
-        return None;
-    }
-    let (msg, remain) = postcard::take_from_bytes::<T::Message>(remain).ok()?;
-    Some(TopicExtract { msg, hdr, remain })
+/// Default capacity of a [`FragBuf`], the largest message that can be reassembled
+pub const FRAG_BUF_SIZE: usize = 1024;
+
+/// Most fragments a message can be split into, one per bit of [`FragAck::received`]
+pub const MAX_FRAGS: u8 = 64;
+
+/// How many times to resend missing fragments before giving up on a message
+pub const PROXY_RETRIES: u8 = 4;
+
+/// How long a sender waits without any new ack before resending
+pub const PROXY_ACK_TIMEOUT_MS: u64 = 500;
+
+/// CRC-32 used to check reassembled proxy messages
+pub const PROXY_CRC: Crc<u32> = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+
+/// Which fragments of a message have arrived
+///
+/// Sent back for every fragment received, so the sender can resend only the
+/// ones that went missing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Schema)]
+pub struct FragAck {
+    pub msg_id: u16,
+    pub ttl_parts: u8,
+    /// Bit `n` is set if part `n` has arrived
+    pub received: u64,
 }
 
-pub fn write_topic2<T>(msg: &T::Message, seq_no: VarSeq, buf: &mut [u8]) -> Option<usize>
-where
-    T: Topic,
-    T::Message: Serialize,
-{
-    let hdr = VarHeader {
-        key: VarKey::Key2(T::TOPIC_KEY2),
-        seq_no,
+impl FragAck {
+    pub const NONE: Self = Self {
+        msg_id: 0,
+        ttl_parts: 0,
+        received: 0,
     };
-    let (_hdr, remain) = hdr.write_to_slice(buf)?;
-    let used = postcard::to_slice(&msg, remain).ok()?;
-    Some(_hdr.len() + used.len())
+
+    pub fn is_complete(&self) -> bool {
+        self.ttl_parts != 0 && self.received == frag_mask(self.ttl_parts)
+    }
+
+    /// Parts of message `msg_id` that this ack doesn't cover
+    pub fn missing(&self, msg_id: u16, ttl_parts: u8) -> u64 {
+        let all = frag_mask(ttl_parts);
+        if self.msg_id != msg_id || self.ttl_parts != ttl_parts {
+            return all;
+        }
+        all & !self.received
+    }
+}
+
+/// A mask with one bit set for each of `ttl_parts` fragments
+pub const fn frag_mask(ttl_parts: u8) -> u64 {
+    if ttl_parts >= 64 {
+        u64::MAX
+    } else {
+        (1u64 << ttl_parts) - 1
+    }
 }
 
+/// Why a fragment was dropped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum FragError {
+    /// The part number, count or length is impossible
+    Malformed,
+    /// The part count or CRC differs from earlier parts with the same message ID
+    Mismatch,
+    /// The message doesn't fit in the buffer
+    Overflow,
+    /// Every part arrived, but the message doesn't match its CRC
+    Crc,
+}
+
+/// Counts of what a [`FragBuf`] has seen, for diagnostics
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Schema)]
+pub struct FragStats {
+    pub completed: u32,
+    /// Parts that had already arrived, or belonged to a finished message
+    pub duplicates: u32,
+    pub rejected: u32,
+}
 
 #[derive(Clone, Copy)]
 pub enum FragStatus {
     Idle,
     Active {
-        position: usize,
-        rx_frags: u8,
+        msg_id: u16,
         ttl_frags: u8,
-    }
+        crc: u32,
+        received: u64,
+        /// Total length, known once the last part has arrived
+        len: Option<usize>,
+    },
 }
 
-pub struct FragBuf {
-    data: [u8; 1024],
+/// Reassembles fragmented proxy messages
+///
+/// `FRAG` is the largest fragment payload accepted, and `CAP` the largest
+/// message. The fragment size actually used is negotiated when joining, and
+/// may be smaller than `FRAG`.
+///
+/// Parts may arrive in any order, since missing ones are resent after the
+/// rest. Every part but the last must be exactly `frag_size` long, so each
+/// part's position in the message is known as soon as it arrives.
+///
+/// Parts are matched up by message ID. Parts of the last completed message
+/// are acked again but otherwise ignored, so a resend after a lost ack
+/// doesn't deliver the message twice. That takes the same part count and CRC
+/// as well as the ID, since a sender that restarted counts IDs from zero
+/// again.
+pub struct FragBuf<const FRAG: usize = MAX_FRAG_SIZE, const CAP: usize = FRAG_BUF_SIZE> {
+    data: [u8; CAP],
     status: FragStatus,
+    last_ack: FragAck,
+    /// ID, part count and CRC of the last message delivered
+    done: Option<(u16, u8, u32)>,
+    stats: FragStats,
 }
 
-impl FragBuf {
+impl<const FRAG: usize, const CAP: usize> FragBuf<FRAG, CAP> {
+    /// `FRAG`, as offered in [`JoinParams::max_frag`]
+    pub const MAX_FRAG: u8 = FRAG as u8;
+    /// `CAP`, as offered in [`JoinParams::frag_capacity`]
+    pub const CAPACITY: u16 = CAP as u16;
+
     pub const fn new() -> Self {
+        const {
+            assert!(FRAG >= MIN_FRAG_SIZE as usize && FRAG <= MAX_FRAG_SIZE);
+            assert!(CAP >= FRAG && CAP <= u16::MAX as usize);
+        }
         Self {
-            data: [0u8; 1024],
+            data: [0u8; CAP],
             status: FragStatus::Idle,
+            last_ack: FragAck::NONE,
+            done: None,
+            stats: FragStats {
+                completed: 0,
+                duplicates: 0,
+                rejected: 0,
+            },
         }
     }
 
+    /// Forget any partial message, for when the sender has started over
     pub fn reset_frag(&mut self) {
         self.status = FragStatus::Idle;
+        self.last_ack = FragAck::NONE;
+        self.done = None;
     }
 
-    pub fn handle_frag<'a>(&'a mut self, part: u8, ttl_parts: u8, data: &[u8]) -> Option<&'a [u8]> {
-        if ttl_parts < 1 {
-            #[cfg(feature = "defmt")]
-            defmt::error!("Why is this calling handle frag");
-            self.status = FragStatus::Idle;
-            return None;
+    /// The ack to send for the most recently handled fragment
+    pub fn ack(&self) -> FragAck {
+        self.last_ack
+    }
+
+    pub fn stats(&self) -> FragStats {
+        self.stats
+    }
+
+    /// Start counting from zero, for when another sender takes over
+    pub fn clear_stats(&mut self) {
+        self.stats = FragStats::default();
+    }
+
+    /// Store one fragment, returning the message once every part has arrived
+    /// and its CRC matches
+    pub fn handle_frag<'a>(
+        &'a mut self,
+        hdr: FragHeader,
+        frag_size: usize,
+        data: &[u8],
+    ) -> Result<Option<&'a [u8]>, FragError> {
+        let res = self.handle_inner(hdr, frag_size, data);
+        if res.is_err() {
+            self.stats.rejected += 1;
         }
-        match self.status {
-            FragStatus::Idle if part == 0 => {
-                self.data[..data.len()].copy_from_slice(data);
-                self.status = FragStatus::Active { position: data.len(), rx_frags: 1, ttl_frags: ttl_parts };
-                None
-            },
-            FragStatus::Idle => {
-                #[cfg(feature = "defmt")]
-                defmt::warn!("Missed first frag!");
-                None
+        match res {
+            Ok(Some(len)) => Ok(Some(&self.data[..len])),
+            Ok(None) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn handle_inner(
+        &mut self,
+        hdr: FragHeader,
+        frag_size: usize,
+        data: &[u8],
+    ) -> Result<Option<usize>, FragError> {
+        let FragHeader {
+            msg_id,
+            part,
+            ttl_parts,
+            crc,
+        } = hdr;
+        let last = ttl_parts.wrapping_sub(1);
+        let valid = frag_size <= FRAG
+            && ttl_parts != 0
+            && ttl_parts <= MAX_FRAGS
+            && part < ttl_parts
+            && (part == last || data.len() == frag_size)
+            && data.len() <= frag_size;
+        if !valid {
+            return Err(FragError::Malformed);
+        }
+
+        if self.done == Some((msg_id, ttl_parts, crc)) {
+            self.stats.duplicates += 1;
+            self.last_ack = FragAck {
+                msg_id,
+                ttl_parts,
+                received: frag_mask(ttl_parts),
+            };
+            return Ok(None);
+        }
+
+        let (mut received, mut len) = match self.status {
+            FragStatus::Active {
+                msg_id: id,
+                ttl_frags,
+                crc: c,
+                received,
+                len,
+            } if id == msg_id => {
+                if ttl_frags != ttl_parts || c != crc {
+                    return Err(FragError::Mismatch);
+                }
+                (received, len)
+            }
+            // A new ID means the sender gave up on the last message
+            _ => (0, None),
+        };
+
+        let bit = 1u64 << part;
+        if received & bit != 0 {
+            self.stats.duplicates += 1;
+        } else {
+            let start = part as usize * frag_size;
+            let end = start + data.len();
+            let Some(range) = self.data.get_mut(start..end) else {
+                self.status = FragStatus::Idle;
+                return Err(FragError::Overflow);
+            };
+            range.copy_from_slice(data);
+            received |= bit;
+            if part == last {
+                len = Some(end);
             }
-            FragStatus::Active { position, rx_frags, ttl_frags } => {
-                if rx_frags != part || ttl_frags != ttl_parts {
-                    #[cfg(feature = "defmt")]
-                    defmt::warn!("Missed frag!");
-                    if part == 0 {
-                        self.data[..data.len()].copy_from_slice(data);
-                        self.status = FragStatus::Active { position: data.len(), rx_frags: 1, ttl_frags: ttl_parts };
-                    } else {
-                        self.status = FragStatus::Idle;
-                    }
-                    return None;
+        }
+
+        self.last_ack = FragAck {
+            msg_id,
+            ttl_parts,
+            received,
+        };
+        match len {
+            Some(len) if received == frag_mask(ttl_parts) => {
+                self.status = FragStatus::Idle;
+                if PROXY_CRC.checksum(&self.data[..len]) != crc {
+                    // Ack nothing, so the sender resends the whole message
+                    self.last_ack.received = 0;
+                    return Err(FragError::Crc);
                 }
-                let end = position + data.len();
-                let Some(range) = self.data.get_mut(position..end) else {
-                    #[cfg(feature = "defmt")]
-                    defmt::warn!("Frag overflow!");
-                    self.status = FragStatus::Idle;
-                    return None;
+                self.done = Some((msg_id, ttl_parts, crc));
+                self.stats.completed += 1;
+                Ok(Some(len))
+            }
+            _ => {
+                self.status = FragStatus::Active {
+                    msg_id,
+                    ttl_frags: ttl_parts,
+                    crc,
+                    received,
+                    len,
                 };
-                range.copy_from_slice(data);
-                if (part + 1) == ttl_parts {
-                    self.status = FragStatus::Idle;
-                    Some(&self.data[..end])
-                } else {
-                    self.status = FragStatus::Active { position: end, rx_frags: part + 1, ttl_frags: ttl_parts };
-                    None
-                }
-            },
+                Ok(None)
+            }
         }
     }
 }
 
-impl Default for FragBuf {
+impl<const FRAG: usize, const CAP: usize> Default for FragBuf<FRAG, CAP> {
     fn default() -> Self {
         Self::new()
     }
@@ -0,0 +1,590 @@
+//! Authenticated encryption of the node <-> bridge radio link
+//!
+//! Every ESB packet between a node and the bridge is sealed with AES-128-CCM,
+//! laid out the way the nRF52 CCM peripheral does it: a 13 byte nonce made of
+//! a 39 bit packet counter, a direction bit and an 8 byte IV, a 4 byte MIC,
+//! and one zero header byte as associated data. [`SoftCcm`] does this in
+//! software, and with the `nrf-ccm` feature [`NrfCcm`] uses the peripheral.
+//! Both produce the same bytes, so either end can use either.
+//!
+//! ## Keys
+//!
+//! Each node has its own [`LinkKey`], derived from the [`NetworkKey`] and the
+//! node's serial, so the bridge only needs the network key.
+//!
+//! ## Joining
+//!
+//! A node joins by sending a [`Hello`] on pipe 0: its serial and a random
+//! `node_iv` in the clear, then the `Initialize` message sealed with its link
+//! key. The bridge answers with a random `bridge_iv` in the clear, then its
+//! reply sealed with the link key. The reply echoes `node_iv`, so an old
+//! reply can't be replayed to a node that is joining again.
+//!
+//! Both sides then derive a [`Session`] key from the link key and both IVs.
+//! The bridge's random half means a recorded session can't be replayed
+//! after a node rejoins. Repeated `Initialize`s with the same `node_iv` get
+//! the same `bridge_iv`, since replies arrive one packet late.
+//!
+//! ## Session frames
+//!
+//! `[counter: u32 LE][ciphertext][MIC: 4 bytes]`
+//!
+//! Each side counts up from zero, and the receiver rejects counters it has
+//! already seen, within a window of [`REPLAY_WINDOW`] for packets that were
+//! delayed.
+//!
+//! Nothing here stops a radio from jamming, or from replaying a `Hello` to
+//! make the bridge start a new session. A node that stops hearing the bridge
+//! starts over.
+
+use aes::{
+    cipher::{generic_array::GenericArray, BlockEncrypt, KeyInit},
+    Aes128,
+};
+use ccm::{
+    aead::AeadInPlace,
+    consts::{U13, U4},
+    Ccm,
+};
+
+#[cfg(feature = "nrf-ccm")]
+mod nrf;
+#[cfg(feature = "nrf-ccm")]
+pub use nrf::NrfCcm;
+
+pub const KEY_LEN: usize = 16;
+pub const IV_LEN: usize = 8;
+pub const NONCE_LEN: usize = 13;
+pub const MIC_LEN: usize = 4;
+
+/// The counter in front of every session frame
+pub const CTR_LEN: usize = 4;
+
+/// Bytes a sealed session frame adds to its plaintext
+pub const SEAL_OVERHEAD: usize = CTR_LEN + MIC_LEN;
+
+/// The clear part of a join request: the node's serial, then its `node_iv`
+pub const HELLO_LEN: usize = 8 + IV_LEN;
+
+/// The clear part of a join reply: the bridge's `bridge_iv`
+pub const WELCOME_LEN: usize = IV_LEN;
+
+/// How far behind the newest counter a packet may arrive and still be accepted
+pub const REPLAY_WINDOW: u32 = 64;
+
+/// Nonce counter used for the sealed half of a join request or reply
+const JOIN_CTR: u32 = 0;
+
+/// Key shared by the bridge and every node of one network
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct NetworkKey(pub [u8; KEY_LEN]);
+
+/// Key of one node, see [`NetworkKey::node_key`]
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct LinkKey(pub [u8; KEY_LEN]);
+
+// Keys are never printed
+impl core::fmt::Debug for NetworkKey {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str("NetworkKey(..)")
+    }
+}
+
+impl core::fmt::Debug for LinkKey {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str("LinkKey(..)")
+    }
+}
+
+impl NetworkKey {
+    /// The key used when none was given at build time. Anyone can read it
+    /// here, so it only keeps out radios that aren't curacao devices
+    pub const DEVELOPMENT: Self = Self(*b"curacao-dev-key!");
+
+    /// Parse 32 hex digits
+    pub const fn from_hex(hex: &str) -> Option<Self> {
+        let hex = hex.as_bytes();
+        if hex.len() != KEY_LEN * 2 {
+            return None;
+        }
+        let mut out = [0u8; KEY_LEN];
+        let mut i = 0;
+        while i < KEY_LEN {
+            let (Some(hi), Some(lo)) = (nibble(hex[2 * i]), nibble(hex[2 * i + 1])) else {
+                return None;
+            };
+            out[i] = (hi << 4) | lo;
+            i += 1;
+        }
+        Some(Self(out))
+    }
+
+    /// The key given at build time, as in
+    /// `NetworkKey::from_build_env(option_env!("CURACAO_NETWORK_KEY"))`,
+    /// or [`Self::DEVELOPMENT`] if there wasn't one
+    pub const fn from_build_env(hex: Option<&str>) -> Self {
+        match hex {
+            None => Self::DEVELOPMENT,
+            Some(hex) => match Self::from_hex(hex) {
+                Some(key) => key,
+                None => panic!("CURACAO_NETWORK_KEY must be 32 hex digits"),
+            },
+        }
+    }
+
+    pub fn is_development(&self) -> bool {
+        *self == Self::DEVELOPMENT
+    }
+
+    /// The link key of the node with `serial`
+    pub fn node_key(&self, serial: &[u8; 8]) -> LinkKey {
+        let mut block = [0u8; 16];
+        block[..8].copy_from_slice(serial);
+        block[8..].copy_from_slice(b"nodekey\0");
+        LinkKey(aes_block(&self.0, block))
+    }
+}
+
+const fn nibble(c: u8) -> Option<u8> {
+    match c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'a'..=b'f' => Some(c - b'a' + 10),
+        b'A'..=b'F' => Some(c - b'A' + 10),
+        _ => None,
+    }
+}
+
+fn aes_block(key: &[u8; KEY_LEN], block: [u8; 16]) -> [u8; 16] {
+    let aes = Aes128::new(GenericArray::from_slice(key));
+    let mut block = GenericArray::from(block);
+    aes.encrypt_block(&mut block);
+    block.into()
+}
+
+/// Which way a frame is going, part of every nonce so a frame can't be
+/// reflected back to its sender
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    NodeToBridge,
+    BridgeToNode,
+}
+
+/// The nRF CCM nonce: the packet counter as 39 bits little endian with the
+/// direction in the top bit of byte 4, then the IV
+pub fn nonce(ctr: u32, dir: Direction, iv: &[u8; IV_LEN]) -> [u8; NONCE_LEN] {
+    let mut out = [0u8; NONCE_LEN];
+    out[..4].copy_from_slice(&ctr.to_le_bytes());
+    if dir == Direction::BridgeToNode {
+        out[4] = 0x80;
+    }
+    out[5..].copy_from_slice(iv);
+    out
+}
+
+/// Why a frame was refused
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SealError {
+    /// Too short to hold the framing, or too long for the buffer
+    Length,
+    /// The MIC doesn't match, so the frame was forged, corrupted, or sealed
+    /// with another key
+    Mic,
+    /// The counter was already seen, or is too far behind to tell
+    Replay,
+    /// The sender's counter ran out, the node has to join again
+    Exhausted,
+}
+
+/// AES-128-CCM in the layout described in the [module docs](self)
+pub trait LinkCipher {
+    /// Encrypt `buf` in place, returning the MIC
+    fn seal(&self, key: &LinkKey, nonce: &[u8; NONCE_LEN], buf: &mut [u8]) -> [u8; MIC_LEN];
+
+    /// Decrypt `buf` in place, if `mic` matches. On failure `buf` holds garbage
+    fn open(
+        &self,
+        key: &LinkKey,
+        nonce: &[u8; NONCE_LEN],
+        buf: &mut [u8],
+        mic: &[u8; MIC_LEN],
+    ) -> Result<(), SealError>;
+}
+
+/// The associated data: an S0 header byte, which we always leave zero
+const AAD: [u8; 1] = [0];
+
+type Aes128Ccm = Ccm<Aes128, U4, U13>;
+
+/// AES-CCM in software
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SoftCcm;
+
+impl LinkCipher for SoftCcm {
+    fn seal(&self, key: &LinkKey, nonce: &[u8; NONCE_LEN], buf: &mut [u8]) -> [u8; MIC_LEN] {
+        let ccm = Aes128Ccm::new(GenericArray::from_slice(&key.0));
+        // Only fails for messages longer than the 2 byte length field allows
+        let tag = ccm
+            .encrypt_in_place_detached(GenericArray::from_slice(nonce), &AAD, buf)
+            .unwrap();
+        tag.into()
+    }
+
+    fn open(
+        &self,
+        key: &LinkKey,
+        nonce: &[u8; NONCE_LEN],
+        buf: &mut [u8],
+        mic: &[u8; MIC_LEN],
+    ) -> Result<(), SealError> {
+        let ccm = Aes128Ccm::new(GenericArray::from_slice(&key.0));
+        ccm.decrypt_in_place_detached(
+            GenericArray::from_slice(nonce),
+            &AAD,
+            buf,
+            GenericArray::from_slice(mic),
+        )
+        .map_err(|_| SealError::Mic)
+    }
+}
+
+/// The backend picked by the `nrf-ccm` feature
+#[cfg(not(feature = "nrf-ccm"))]
+pub type Cipher = SoftCcm;
+#[cfg(not(feature = "nrf-ccm"))]
+pub const CIPHER: Cipher = SoftCcm;
+/// The backend picked by the `nrf-ccm` feature
+#[cfg(feature = "nrf-ccm")]
+pub type Cipher = NrfCcm;
+#[cfg(feature = "nrf-ccm")]
+pub const CIPHER: Cipher = NrfCcm;
+
+/// Counters of frames already received
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ReplayWindow {
+    /// The newest counter accepted, if any
+    top: Option<u32>,
+    /// Bit `n` is set if `top - n` was accepted
+    seen: u64,
+}
+
+impl ReplayWindow {
+    /// Would `ctr` be accepted?
+    pub fn check(&self, ctr: u32) -> bool {
+        let Some(top) = self.top else {
+            return true;
+        };
+        if ctr > top {
+            return true;
+        }
+        let behind = top - ctr;
+        behind < REPLAY_WINDOW && self.seen & (1 << behind) == 0
+    }
+
+    /// Record `ctr` as received, after it has passed [`Self::check`] and its MIC
+    pub fn accept(&mut self, ctr: u32) {
+        match self.top {
+            Some(top) if ctr <= top => self.seen |= 1 << (top - ctr),
+            Some(top) => {
+                let ahead = ctr - top;
+                self.seen = if ahead < REPLAY_WINDOW {
+                    (self.seen << ahead) | 1
+                } else {
+                    1
+                };
+                self.top = Some(ctr);
+            }
+            None => {
+                self.seen = 1;
+                self.top = Some(ctr);
+            }
+        }
+    }
+}
+
+/// Seals the frames we send in a session
+#[derive(Debug, Clone)]
+pub struct Sealer {
+    key: LinkKey,
+    iv: [u8; IV_LEN],
+    dir: Direction,
+    ctr: u32,
+}
+
+impl Sealer {
+    /// Seal the `len` byte plaintext at `buf[CTR_LEN..]`, returning the
+    /// length of the whole frame
+    pub fn seal<C: LinkCipher>(
+        &mut self,
+        cipher: &C,
+        buf: &mut [u8],
+        len: usize,
+    ) -> Result<usize, SealError> {
+        let total = len + SEAL_OVERHEAD;
+        if buf.len() < total {
+            return Err(SealError::Length);
+        }
+        let ctr = self.ctr;
+        self.ctr = ctr.checked_add(1).ok_or(SealError::Exhausted)?;
+
+        let (head, rest) = buf.split_at_mut(CTR_LEN);
+        head.copy_from_slice(&ctr.to_le_bytes());
+        let (body, tail) = rest.split_at_mut(len);
+        let mic = cipher.seal(&self.key, &nonce(ctr, self.dir, &self.iv), body);
+        tail[..MIC_LEN].copy_from_slice(&mic);
+        Ok(total)
+    }
+}
+
+/// Opens the frames we receive in a session
+#[derive(Debug, Clone)]
+pub struct Opener {
+    key: LinkKey,
+    iv: [u8; IV_LEN],
+    dir: Direction,
+    window: ReplayWindow,
+}
+
+impl Opener {
+    /// Check and decrypt `frame` into `buf`, returning the plaintext
+    pub fn open<'a, C: LinkCipher>(
+        &mut self,
+        cipher: &C,
+        frame: &[u8],
+        buf: &'a mut [u8],
+    ) -> Result<&'a mut [u8], SealError> {
+        let Some(len) = frame.len().checked_sub(SEAL_OVERHEAD) else {
+            return Err(SealError::Length);
+        };
+        let Some(out) = buf.get_mut(..len) else {
+            return Err(SealError::Length);
+        };
+        let (head, rest) = frame.split_at(CTR_LEN);
+        let (body, mic) = rest.split_at(len);
+        let ctr = u32::from_le_bytes(head.try_into().unwrap());
+        if !self.window.check(ctr) {
+            return Err(SealError::Replay);
+        }
+
+        out.copy_from_slice(body);
+        let nonce = nonce(ctr, self.dir, &self.iv);
+        cipher.open(&self.key, &nonce, out, mic.try_into().unwrap())?;
+        self.window.accept(ctr);
+        Ok(out)
+    }
+}
+
+/// Which end of the link we are
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Node,
+    Bridge,
+}
+
+/// Both halves of a session, see the [module docs](self)
+#[derive(Debug, Clone)]
+pub struct Session {
+    pub tx: Sealer,
+    pub rx: Opener,
+}
+
+impl Session {
+    pub fn new(
+        link: &LinkKey,
+        node_iv: &[u8; IV_LEN],
+        bridge_iv: &[u8; IV_LEN],
+        role: Role,
+    ) -> Self {
+        let mut block = [0u8; 16];
+        block[..IV_LEN].copy_from_slice(node_iv);
+        block[IV_LEN..].copy_from_slice(bridge_iv);
+        let key = LinkKey(aes_block(&link.0, block));
+
+        let mut iv = [0u8; IV_LEN];
+        iv[..4].copy_from_slice(&node_iv[..4]);
+        iv[4..].copy_from_slice(&bridge_iv[..4]);
+
+        let (tx, rx) = match role {
+            Role::Node => (Direction::NodeToBridge, Direction::BridgeToNode),
+            Role::Bridge => (Direction::BridgeToNode, Direction::NodeToBridge),
+        };
+        Self {
+            tx: Sealer {
+                key,
+                iv,
+                dir: tx,
+                ctr: 0,
+            },
+            rx: Opener {
+                key,
+                iv,
+                dir: rx,
+                window: ReplayWindow::default(),
+            },
+        }
+    }
+}
+
+/// The clear header of a join request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hello {
+    pub serial: [u8; 8],
+    pub node_iv: [u8; IV_LEN],
+}
+
+impl Hello {
+    /// Seal the `len` byte plaintext at `buf[HELLO_LEN..]` with the node's
+    /// link key, returning the length of the whole frame
+    ///
+    /// A `node_iv` must never be used for two different requests.
+    pub fn seal<C: LinkCipher>(
+        &self,
+        cipher: &C,
+        key: &LinkKey,
+        buf: &mut [u8],
+        len: usize,
+    ) -> Result<usize, SealError> {
+        seal_join(
+            cipher,
+            key,
+            Direction::NodeToBridge,
+            &self.node_iv,
+            buf,
+            HELLO_LEN,
+            len,
+            |head| {
+                head[..8].copy_from_slice(&self.serial);
+                head[8..].copy_from_slice(&self.node_iv);
+            },
+        )
+    }
+
+    /// Read the clear header of a join request
+    pub fn peek(frame: &[u8]) -> Option<Self> {
+        let head = frame.get(..HELLO_LEN)?;
+        Some(Self {
+            serial: head[..8].try_into().unwrap(),
+            node_iv: head[8..].try_into().unwrap(),
+        })
+    }
+
+    /// Check and decrypt a join request from the node with `key` into `buf`
+    pub fn open<'a, C: LinkCipher>(
+        &self,
+        cipher: &C,
+        key: &LinkKey,
+        frame: &[u8],
+        buf: &'a mut [u8],
+    ) -> Result<&'a mut [u8], SealError> {
+        open_join(
+            cipher,
+            key,
+            Direction::NodeToBridge,
+            &self.node_iv,
+            frame,
+            HELLO_LEN,
+            buf,
+        )
+    }
+}
+
+/// The clear header of a join reply
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Welcome {
+    pub bridge_iv: [u8; IV_LEN],
+}
+
+impl Welcome {
+    /// Seal the `len` byte plaintext at `buf[WELCOME_LEN..]` with the node's
+    /// link key, returning the length of the whole frame
+    ///
+    /// A `bridge_iv` must never be used for two different replies.
+    pub fn seal<C: LinkCipher>(
+        &self,
+        cipher: &C,
+        key: &LinkKey,
+        buf: &mut [u8],
+        len: usize,
+    ) -> Result<usize, SealError> {
+        seal_join(
+            cipher,
+            key,
+            Direction::BridgeToNode,
+            &self.bridge_iv,
+            buf,
+            WELCOME_LEN,
+            len,
+            |head| head.copy_from_slice(&self.bridge_iv),
+        )
+    }
+
+    pub fn peek(frame: &[u8]) -> Option<Self> {
+        let head = frame.get(..WELCOME_LEN)?;
+        Some(Self {
+            bridge_iv: head.try_into().unwrap(),
+        })
+    }
+
+    /// Check and decrypt a join reply sealed with our `key` into `buf`
+    pub fn open<'a, C: LinkCipher>(
+        &self,
+        cipher: &C,
+        key: &LinkKey,
+        frame: &[u8],
+        buf: &'a mut [u8],
+    ) -> Result<&'a mut [u8], SealError> {
+        open_join(
+            cipher,
+            key,
+            Direction::BridgeToNode,
+            &self.bridge_iv,
+            frame,
+            WELCOME_LEN,
+            buf,
+        )
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn seal_join<C: LinkCipher>(
+    cipher: &C,
+    key: &LinkKey,
+    dir: Direction,
+    iv: &[u8; IV_LEN],
+    buf: &mut [u8],
+    head_len: usize,
+    len: usize,
+    write_head: impl FnOnce(&mut [u8]),
+) -> Result<usize, SealError> {
+    let total = head_len + len + MIC_LEN;
+    if buf.len() < total {
+        return Err(SealError::Length);
+    }
+    let (head, rest) = buf.split_at_mut(head_len);
+    write_head(head);
+    let (body, tail) = rest.split_at_mut(len);
+    let mic = cipher.seal(key, &nonce(JOIN_CTR, dir, iv), body);
+    tail[..MIC_LEN].copy_from_slice(&mic);
+    Ok(total)
+}
+
+fn open_join<'a, C: LinkCipher>(
+    cipher: &C,
+    key: &LinkKey,
+    dir: Direction,
+    iv: &[u8; IV_LEN],
+    frame: &[u8],
+    head_len: usize,
+    buf: &'a mut [u8],
+) -> Result<&'a mut [u8], SealError> {
+    let Some(len) = frame.len().checked_sub(head_len + MIC_LEN) else {
+        return Err(SealError::Length);
+    };
+    let Some(out) = buf.get_mut(..len) else {
+        return Err(SealError::Length);
+    };
+    let (body, mic) = frame[head_len..].split_at(len);
+    out.copy_from_slice(body);
+    cipher.open(key, &nonce(JOIN_CTR, dir, iv), out, mic.try_into().unwrap())?;
+    Ok(out)
+}
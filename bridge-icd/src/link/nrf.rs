@@ -0,0 +1,134 @@
+//! AES-CCM on the nRF52 CCM peripheral
+//!
+//! The peripheral is driven directly through the PAC, one operation at a
+//! time with interrupts left off. Nothing else may use CCM or AAR.
+
+use core::sync::atomic::{compiler_fence, Ordering};
+
+use embassy_nrf::pac::{self, ccm::vals};
+
+use super::{LinkCipher, LinkKey, SealError, IV_LEN, KEY_LEN, MIC_LEN, NONCE_LEN};
+
+/// Largest payload the peripheral takes in extended length mode
+const MAX_PAYLOAD: usize = 251;
+
+/// S0, LENGTH and S1, the BLE header the peripheral expects in front
+const HEADER_LEN: usize = 3;
+
+/// The data structure CNFPTR points at
+#[repr(C)]
+struct CcmConfig {
+    key: [u8; KEY_LEN],
+    /// 39 bits are used, and the direction is kept separately
+    pktctr: [u8; 8],
+    direction: u8,
+    iv: [u8; IV_LEN],
+}
+
+impl CcmConfig {
+    fn new(key: &LinkKey, nonce: &[u8; NONCE_LEN]) -> Self {
+        let mut pktctr = [0u8; 8];
+        pktctr[..5].copy_from_slice(&nonce[..5]);
+        pktctr[4] &= 0x7F;
+        Self {
+            key: key.0,
+            pktctr,
+            direction: nonce[4] >> 7,
+            iv: nonce[5..].try_into().unwrap(),
+        }
+    }
+}
+
+/// AES-CCM using the CCM peripheral
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NrfCcm;
+
+impl NrfCcm {
+    /// Run one operation, `input` and `output` holding the BLE header
+    /// followed by the payload
+    fn run(
+        &self,
+        mode: vals::Mode,
+        config: &CcmConfig,
+        input: &[u8],
+        output: &mut [u8],
+    ) -> Result<(), SealError> {
+        let ccm = pac::CCM;
+        let mut scratch = [0u8; 16 + MAX_PAYLOAD + MIC_LEN];
+
+        ccm.enable().write(|w| w.set_enable(vals::Enable::ENABLED));
+        ccm.mode().write(|w| {
+            w.set_mode(mode);
+            w.set_datarate(vals::Datarate::_2MBIT);
+            w.set_length(vals::Length::EXTENDED);
+        });
+        ccm.maxpacketsize()
+            .write(|w| w.set_maxpacketsize(MAX_PAYLOAD as u8));
+        ccm.cnfptr().write_value(config as *const CcmConfig as u32);
+        ccm.inptr().write_value(input.as_ptr() as u32);
+        ccm.outptr().write_value(output.as_mut_ptr() as u32);
+        ccm.scratchptr().write_value(scratch.as_mut_ptr() as u32);
+        ccm.shorts().write(|w| w.set_endksg_crypt(true));
+        ccm.events_endcrypt().write_value(0);
+        ccm.events_error().write_value(0);
+
+        compiler_fence(Ordering::SeqCst);
+        ccm.tasks_ksgen().write_value(1);
+        while ccm.events_endcrypt().read() == 0 && ccm.events_error().read() == 0 {}
+        compiler_fence(Ordering::SeqCst);
+
+        let failed = ccm.events_error().read() != 0;
+        let mic_ok = ccm.micstatus().read().micstatus() == vals::Micstatus::CHECK_PASSED;
+        ccm.enable().write(|w| w.set_enable(vals::Enable::DISABLED));
+
+        if failed {
+            Err(SealError::Length)
+        } else if mode == vals::Mode::DECRYPTION && !mic_ok {
+            Err(SealError::Mic)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl LinkCipher for NrfCcm {
+    fn seal(&self, key: &LinkKey, nonce: &[u8; NONCE_LEN], buf: &mut [u8]) -> [u8; MIC_LEN] {
+        // Frames are at most an ESB payload, which always fits
+        assert!(buf.len() + MIC_LEN <= MAX_PAYLOAD);
+        let config = CcmConfig::new(key, nonce);
+        let mut input = [0u8; HEADER_LEN + MAX_PAYLOAD];
+        let mut output = [0u8; HEADER_LEN + MAX_PAYLOAD];
+        input[1] = buf.len() as u8;
+        input[HEADER_LEN..][..buf.len()].copy_from_slice(buf);
+
+        // Encryption can't fail for a frame of a valid length
+        let _ = self.run(vals::Mode::ENCRYPTION, &config, &input, &mut output);
+
+        let out = &output[HEADER_LEN..][..buf.len() + MIC_LEN];
+        let (body, mic) = out.split_at(buf.len());
+        buf.copy_from_slice(body);
+        mic.try_into().unwrap()
+    }
+
+    fn open(
+        &self,
+        key: &LinkKey,
+        nonce: &[u8; NONCE_LEN],
+        buf: &mut [u8],
+        mic: &[u8; MIC_LEN],
+    ) -> Result<(), SealError> {
+        if buf.len() + MIC_LEN > MAX_PAYLOAD {
+            return Err(SealError::Length);
+        }
+        let config = CcmConfig::new(key, nonce);
+        let mut input = [0u8; HEADER_LEN + MAX_PAYLOAD];
+        let mut output = [0u8; HEADER_LEN + MAX_PAYLOAD];
+        input[1] = (buf.len() + MIC_LEN) as u8;
+        input[HEADER_LEN..][..buf.len()].copy_from_slice(buf);
+        input[HEADER_LEN + buf.len()..][..MIC_LEN].copy_from_slice(mic);
+
+        self.run(vals::Mode::DECRYPTION, &config, &input, &mut output)?;
+        buf.copy_from_slice(&output[HEADER_LEN..][..buf.len()]);
+        Ok(())
+    }
+}
@@ -0,0 +1,115 @@
+//! Network time, from the host through the bridge to every node
+//!
+//! The host sets the bridge's wall clock with `SetTimeEndpoint`. Every
+//! keepalive reply then carries the bridge's time at the moment that
+//! keepalive arrived. The node remembers when it sent each keepalive, so
+//! each reply gives it one [`Sample`]: its own uptime and the wall clock at
+//! (nearly) the same instant. Replies themselves ride on the ack of a later
+//! packet, so when they arrive doesn't matter.
+//!
+//! [`ClockSync`] turns those samples into an estimate of the offset between
+//! the two clocks and of how fast the node's crystal drifts.
+
+/// Corrections larger than this are taken as the host's clock being set,
+/// and estimation starts over
+pub const STEP_US: u64 = 1_000_000;
+
+/// Drift is only estimated over at least this much local time
+pub const MIN_BASELINE_US: u64 = 30_000_000;
+
+/// Once the baseline is this long it starts over, so drift can follow
+/// changes in temperature
+pub const MAX_BASELINE_US: u64 = 15 * 60_000_000;
+
+/// Each sample moves the offset by this fraction of its error, to smooth
+/// out jitter in when keepalives are handled
+pub const SMOOTHING: i64 = 4;
+
+/// The local uptime and the wall clock at the same instant, both in
+/// microseconds
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sample {
+    pub local_us: u64,
+    pub remote_us: u64,
+}
+
+/// Offset and drift between a local uptime clock and the network's wall
+/// clock
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ClockSync {
+    /// Where the current drift baseline starts
+    base: Option<Sample>,
+    /// The latest estimate, extrapolated from by [`Self::now`]
+    anchor: Option<Sample>,
+    /// How much faster the wall clock runs than ours, in parts per billion
+    drift_ppb: i64,
+}
+
+impl ClockSync {
+    pub const fn new() -> Self {
+        Self {
+            base: None,
+            anchor: None,
+            drift_ppb: 0,
+        }
+    }
+
+    /// The wall clock at local uptime `local_us`, once synced
+    ///
+    /// This can step back by a little when a new sample arrives.
+    pub fn now(&self, local_us: u64) -> Option<u64> {
+        let anchor = self.anchor?;
+        Some(anchor.remote_us.wrapping_add_signed(self.elapsed(anchor, local_us)))
+    }
+
+    /// The drift currently being corrected for, in parts per billion
+    pub fn drift_ppb(&self) -> i64 {
+        self.drift_ppb
+    }
+
+    /// Fold in one sample
+    pub fn update(&mut self, sample: Sample) {
+        let (Some(base), Some(anchor)) = (self.base, self.anchor) else {
+            return self.restart(sample);
+        };
+        if sample.local_us < anchor.local_us {
+            // Older than what we have, from a reply that took the long way
+            return;
+        }
+
+        let predicted = anchor
+            .remote_us
+            .wrapping_add_signed(self.elapsed(anchor, sample.local_us));
+        let error = sample.remote_us.wrapping_sub(predicted) as i64;
+        if error.unsigned_abs() > STEP_US {
+            return self.restart(sample);
+        }
+        self.anchor = Some(Sample {
+            local_us: sample.local_us,
+            remote_us: predicted.wrapping_add_signed(error / SMOOTHING),
+        });
+
+        let span = sample.local_us - base.local_us;
+        if span >= MIN_BASELINE_US {
+            let remote_span = sample.remote_us.wrapping_sub(base.remote_us) as i64;
+            let gained = remote_span - span as i64;
+            self.drift_ppb = (gained as i128 * 1_000_000_000 / span as i128) as i64;
+        }
+        if span >= MAX_BASELINE_US {
+            self.base = Some(sample);
+        }
+    }
+
+    fn restart(&mut self, sample: Sample) {
+        self.base = Some(sample);
+        self.anchor = Some(sample);
+        self.drift_ppb = 0;
+    }
+
+    /// Wall clock time passed between `anchor` and `local_us`, signed as
+    /// `local_us` may be before the anchor
+    fn elapsed(&self, anchor: Sample, local_us: u64) -> i64 {
+        let local = local_us.wrapping_sub(anchor.local_us) as i64;
+        local + (local as i128 * self.drift_ppb as i128 / 1_000_000_000) as i64
+    }
+}
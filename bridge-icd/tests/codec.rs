@@ -0,0 +1,233 @@
+//! Round trips through the frame codec, at every key and sequence size
+//!
+//! Run on the host with `cargo test`.
+
+use bridge_icd::{
+    codec::{
+        decode, decode_request, decode_response, decode_topic, encode, encode_request,
+        encode_response, encode_topic, take_topic, DecodeError, EncodeError,
+    },
+    postcard_rpc::{
+        header::{VarKeyKind, VarSeq},
+        Endpoint, Topic,
+    },
+    B2NTopic, Bridge2Node, FragHeader, GroupError, GroupMember, JoinGroupEndpoint, N2BTopic,
+    Node2Bridge, NodeRadioStats, SetTimeEndpoint, WallTime, LINK_KEY,
+};
+use proptest::prelude::*;
+
+const KINDS: [VarKeyKind; 4] = [
+    VarKeyKind::Key1,
+    VarKeyKind::Key2,
+    VarKeyKind::Key4,
+    VarKeyKind::Key8,
+];
+
+fn key_len(kind: VarKeyKind) -> usize {
+    match kind {
+        VarKeyKind::Key1 => 1,
+        VarKeyKind::Key2 => 2,
+        VarKeyKind::Key4 => 4,
+        VarKeyKind::Key8 => 8,
+    }
+}
+
+fn seq_len(seq: VarSeq) -> usize {
+    match seq {
+        VarSeq::Seq1(_) => 1,
+        VarSeq::Seq2(_) => 2,
+        VarSeq::Seq4(_) => 4,
+    }
+}
+
+fn kind() -> impl Strategy<Value = VarKeyKind> {
+    prop::sample::select(KINDS.to_vec())
+}
+
+fn seq() -> impl Strategy<Value = VarSeq> {
+    prop_oneof![
+        any::<u8>().prop_map(VarSeq::Seq1),
+        any::<u16>().prop_map(VarSeq::Seq2),
+        any::<u32>().prop_map(VarSeq::Seq4),
+    ]
+}
+
+fn keepalive() -> impl Strategy<Value = Node2Bridge> {
+    (any::<[u8; 8]>(), any::<u32>(), any::<u32>(), any::<u8>()).prop_map(
+        |(serial, retransmits, max_attempts, seq)| Node2Bridge::Keepalive {
+            serial,
+            radio: NodeRadioStats {
+                retransmits,
+                max_attempts,
+            },
+            seq,
+        },
+    )
+}
+
+/// The frame is a header of the chosen sizes, then the message as postcard
+/// would write it on its own
+fn check_layout(frame: &[u8], kind: VarKeyKind, seq: VarSeq, body: &[u8]) {
+    assert_eq!(frame.len(), 1 + key_len(kind) + seq_len(seq) + body.len());
+    assert!(frame.ends_with(body));
+}
+
+proptest! {
+    #[test]
+    fn topics_round_trip(kind in kind(), seq in seq(), msg in keepalive()) {
+        let mut buf = [0u8; 64];
+        let used = encode_topic::<N2BTopic>(kind, seq, &msg, &mut buf).unwrap();
+        let mut body = [0u8; 64];
+        let body = postcard::to_slice(&msg, &mut body).unwrap();
+        check_layout(&buf[..used], kind, seq, body);
+
+        let (hdr, out) = decode_topic::<N2BTopic>(&buf[..used]).unwrap();
+        prop_assert_eq!(hdr.seq_no, seq);
+        prop_assert_eq!(format!("{out:?}"), format!("{msg:?}"));
+
+        // And back to the same bytes
+        let mut again = [0u8; 64];
+        let len = encode_topic::<N2BTopic>(kind, hdr.seq_no, &out, &mut again).unwrap();
+        prop_assert_eq!(&again[..len], &buf[..used]);
+    }
+
+    #[test]
+    fn requests_and_responses_round_trip(
+        kind in kind(),
+        seq in seq(),
+        unix_us in any::<u64>(),
+    ) {
+        let mut buf = [0u8; 32];
+        let req = WallTime { unix_us };
+        let used = encode_request::<SetTimeEndpoint>(kind, seq, &req, &mut buf).unwrap();
+        let (hdr, out) = decode_request::<SetTimeEndpoint>(&buf[..used]).unwrap();
+        prop_assert_eq!(hdr.seq_no, seq);
+        prop_assert_eq!(out, req);
+
+        let used = encode_response::<SetTimeEndpoint>(kind, seq, &(), &mut buf).unwrap();
+        check_layout(&buf[..used], kind, seq, &[]);
+        let (hdr, ()) = decode_response::<SetTimeEndpoint>(&buf[..used]).unwrap();
+        prop_assert_eq!(hdr.seq_no, seq);
+    }
+
+    #[test]
+    fn truncated_frames_are_rejected(
+        kind in kind(),
+        seq in seq(),
+        msg in keepalive(),
+        cut in any::<prop::sample::Index>(),
+    ) {
+        let mut buf = [0u8; 64];
+        let used = encode_topic::<N2BTopic>(kind, seq, &msg, &mut buf).unwrap();
+        let cut = cut.index(used);
+        let header = 1 + key_len(kind) + seq_len(seq);
+        let expected = if cut < header {
+            DecodeError::BadHeader
+        } else {
+            DecodeError::Deserialize
+        };
+        prop_assert_eq!(decode_topic::<N2BTopic>(&buf[..cut]).unwrap_err(), expected);
+    }
+}
+
+#[test]
+fn endpoint_vectors() {
+    let join = GroupMember {
+        group: "kitchen".try_into().unwrap(),
+        serial: *b"node0001",
+    };
+    let results = [
+        Ok(()),
+        Err(GroupError::GroupFull),
+        Err(GroupError::NotFound),
+    ];
+    for (i, kind) in KINDS.into_iter().enumerate() {
+        let seq = VarSeq::Seq2(i as u16);
+        let mut buf = [0u8; 64];
+        let used = encode_request::<JoinGroupEndpoint>(kind, seq, &join, &mut buf).unwrap();
+        let (_, req) = decode_request::<JoinGroupEndpoint>(&buf[..used]).unwrap();
+        assert_eq!(req, join);
+
+        for result in results {
+            let used = encode_response::<JoinGroupEndpoint>(kind, seq, &result, &mut buf).unwrap();
+            let (hdr, resp) = decode_response::<JoinGroupEndpoint>(&buf[..used]).unwrap();
+            assert_eq!((hdr.seq_no, resp), (seq, result));
+        }
+    }
+}
+
+#[test]
+fn keys_must_match() {
+    let mut buf = [0u8; 32];
+    let seq = VarSeq::Seq1(7);
+    let req = WallTime { unix_us: 1 };
+
+    // Two bytes or more tell these apart, a single byte may not
+    for kind in [VarKeyKind::Key2, VarKeyKind::Key4, VarKeyKind::Key8] {
+        let used = encode_request::<SetTimeEndpoint>(kind, seq, &req, &mut buf).unwrap();
+        let frame = &buf[..used];
+        assert_eq!(
+            decode_response::<SetTimeEndpoint>(frame).unwrap_err(),
+            DecodeError::KeyMismatch
+        );
+        assert_eq!(
+            decode::<WallTime>(N2BTopic::TOPIC_KEY, frame).unwrap_err(),
+            DecodeError::KeyMismatch
+        );
+        assert!(decode::<WallTime>(SetTimeEndpoint::REQ_KEY, frame).is_ok());
+    }
+}
+
+#[test]
+fn trailing_bytes() {
+    // A proxy fragment: the header, then the fragment's bytes
+    let msg = Bridge2Node::Proxy {
+        hdr: FragHeader {
+            msg_id: 3,
+            part: 1,
+            ttl_parts: 2,
+            crc: 0xDEAD_BEEF,
+        },
+    };
+    let mut buf = [0u8; 64];
+    let used = encode_topic::<B2NTopic>(LINK_KEY, VarSeq::Seq2(9), &msg, &mut buf).unwrap();
+    buf[used..][..5].copy_from_slice(b"frag!");
+    let frame = &buf[..used + 5];
+
+    let taken = take_topic::<B2NTopic>(frame).unwrap();
+    assert_eq!(taken.remain, b"frag!");
+    assert!(matches!(taken.msg, Bridge2Node::Proxy { hdr } if hdr.crc == 0xDEAD_BEEF));
+    assert_eq!(
+        decode_topic::<B2NTopic>(frame).unwrap_err(),
+        DecodeError::TrailingBytes(5)
+    );
+}
+
+#[test]
+fn encoding_needs_room() {
+    let req = WallTime { unix_us: u64::MAX };
+    let mut buf = [0u8; 32];
+    let used = encode(
+        SetTimeEndpoint::REQ_KEY,
+        VarKeyKind::Key8,
+        VarSeq::Seq4(0),
+        &req,
+        &mut buf,
+    )
+    .unwrap();
+    for len in 0..used {
+        assert_eq!(
+            encode_request::<SetTimeEndpoint>(
+                VarKeyKind::Key8,
+                VarSeq::Seq4(0),
+                &req,
+                &mut buf[..len]
+            ),
+            Err(EncodeError::BufferFull)
+        );
+    }
+    assert_eq!(
+        decode_topic::<N2BTopic>(&[]).unwrap_err(),
+        DecodeError::BadHeader
+    );
+}
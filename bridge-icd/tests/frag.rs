@@ -0,0 +1,305 @@
+//! Property tests for proxy fragment reassembly
+//!
+//! Run on the host with `cargo test`.
+
+use bridge_icd::{
+    frag_mask, Capabilities, FirmwareVersion, FragBuf, FragError, FragHeader, JoinParams, TooLarge,
+    FRAG_BUF_SIZE, MAX_FRAGS, MIN_FRAG_SIZE, PROTOCOL_VERSION, PROXY_CRC,
+};
+use proptest::prelude::*;
+
+/// Split `msg` into `(header, data)` parts the way the senders do
+fn fragment(msg: &[u8], msg_id: u16, frag_size: usize) -> Vec<(FragHeader, Vec<u8>)> {
+    let ttl_parts = msg.len().div_ceil(frag_size) as u8;
+    let crc = PROXY_CRC.checksum(msg);
+    msg.chunks(frag_size)
+        .enumerate()
+        .map(|(i, ch)| {
+            let hdr = FragHeader {
+                msg_id,
+                part: i as u8,
+                ttl_parts,
+                crc,
+            };
+            (hdr, ch.to_vec())
+        })
+        .collect()
+}
+
+/// A message, a fragment size that keeps it within 64 parts, and a message ID
+fn message() -> impl Strategy<Value = (Vec<u8>, usize, u16)> {
+    (MIN_FRAG_SIZE as usize..=128usize).prop_flat_map(|frag_size| {
+        let max = (frag_size * 64).min(FRAG_BUF_SIZE);
+        (
+            proptest::collection::vec(any::<u8>(), 1..=max),
+            Just(frag_size),
+            any::<u16>(),
+        )
+    })
+}
+
+/// Feed parts in, returning every message that was delivered
+fn feed(buf: &mut FragBuf, frag_size: usize, parts: &[(FragHeader, Vec<u8>)]) -> Vec<Vec<u8>> {
+    let mut out = vec![];
+    for (hdr, data) in parts {
+        if let Ok(Some(msg)) = buf.handle_frag(*hdr, frag_size, data) {
+            out.push(msg.to_vec());
+        }
+    }
+    out
+}
+
+proptest! {
+    #[test]
+    fn in_order_delivers_once((msg, frag_size, msg_id) in message()) {
+        let mut buf: FragBuf = FragBuf::new();
+        let parts = fragment(&msg, msg_id, frag_size);
+        let got = feed(&mut buf, frag_size, &parts);
+        prop_assert_eq!(got, vec![msg]);
+        prop_assert!(buf.ack().is_complete());
+        prop_assert_eq!(buf.stats().completed, 1);
+    }
+
+    #[test]
+    fn shuffled_and_duplicated_delivers_once(
+        (msg, frag_size, msg_id) in message(),
+        seed in any::<u64>(),
+        dups in proptest::collection::vec(any::<prop::sample::Index>(), 0..16),
+    ) {
+        let parts = fragment(&msg, msg_id, frag_size);
+        let mut sent = parts.clone();
+        for d in dups.iter() {
+            sent.push(d.get(&parts).clone());
+        }
+        shuffle(&mut sent, seed);
+
+        let mut buf: FragBuf = FragBuf::new();
+        let got = feed(&mut buf, frag_size, &sent);
+        prop_assert_eq!(got, vec![msg]);
+        prop_assert!(buf.ack().is_complete());
+    }
+
+    #[test]
+    fn lost_parts_are_resent_from_the_ack(
+        (msg, frag_size, msg_id) in message(),
+        lost in any::<u64>(),
+    ) {
+        let parts = fragment(&msg, msg_id, frag_size);
+        let ttl_parts = parts[0].0.ttl_parts;
+        let mut buf: FragBuf = FragBuf::new();
+
+        // First pass drops the parts selected by `lost`
+        let first: Vec<_> = parts
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| lost & (1 << i) == 0)
+            .map(|(_, p)| p.clone())
+            .collect();
+        let mut got = feed(&mut buf, frag_size, &first);
+
+        // Then resend what the ack says is missing, as the senders do
+        let missing = buf.ack().missing(msg_id, ttl_parts);
+        if first.is_empty() {
+            prop_assert_eq!(missing, frag_mask(ttl_parts));
+        }
+        let resend: Vec<_> = parts
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| missing & (1 << i) != 0)
+            .map(|(_, p)| p.clone())
+            .collect();
+        got.extend(feed(&mut buf, frag_size, &resend));
+
+        prop_assert_eq!(got, vec![msg]);
+        prop_assert_eq!(buf.ack().missing(msg_id, ttl_parts), 0);
+    }
+
+    #[test]
+    fn resent_completed_message_is_not_delivered_again((msg, frag_size, msg_id) in message()) {
+        let parts = fragment(&msg, msg_id, frag_size);
+        let mut buf: FragBuf = FragBuf::new();
+        prop_assert_eq!(feed(&mut buf, frag_size, &parts).len(), 1);
+
+        // The sender lost our ack and sends everything again
+        prop_assert!(feed(&mut buf, frag_size, &parts).is_empty());
+        prop_assert!(buf.ack().is_complete());
+        prop_assert_eq!(buf.stats().duplicates, parts.len() as u32);
+
+        // The next message is still delivered
+        let next = fragment(&msg, msg_id.wrapping_add(1), frag_size);
+        prop_assert_eq!(feed(&mut buf, frag_size, &next), vec![msg]);
+    }
+
+    #[test]
+    fn reused_id_with_new_payload_is_delivered(
+        (msg, frag_size, msg_id) in message(),
+        at in any::<prop::sample::Index>(),
+        flip in 1u8..,
+    ) {
+        let mut buf: FragBuf = FragBuf::new();
+        let parts = fragment(&msg, msg_id, frag_size);
+        prop_assert_eq!(feed(&mut buf, frag_size, &parts).len(), 1);
+
+        // The sender restarted and counts message IDs from the same place
+        let mut other = msg.clone();
+        other[at.index(msg.len())] ^= flip;
+        let parts = fragment(&other, msg_id, frag_size);
+        prop_assert_eq!(feed(&mut buf, frag_size, &parts), vec![other]);
+        prop_assert_eq!(buf.stats().duplicates, 0);
+    }
+
+    #[test]
+    fn corrupted_payload_is_rejected(
+        (msg, frag_size, msg_id) in message(),
+        at in any::<prop::sample::Index>(),
+        flip in 1u8..,
+    ) {
+        let mut parts = fragment(&msg, msg_id, frag_size);
+        let byte = at.index(msg.len());
+        parts[byte / frag_size].1[byte % frag_size] ^= flip;
+
+        let mut buf: FragBuf = FragBuf::new();
+        let (last, rest) = parts.split_last().unwrap();
+        prop_assert!(feed(&mut buf, frag_size, rest).is_empty());
+        let res = buf.handle_frag(last.0, frag_size, &last.1).map(|m| m.map(<[u8]>::to_vec));
+        prop_assert_eq!(res, Err(FragError::Crc));
+        // Nothing is acked, so the whole message gets resent
+        prop_assert_eq!(buf.ack().received, 0);
+
+        // And the clean resend goes through
+        let clean = fragment(&msg, msg_id, frag_size);
+        prop_assert_eq!(feed(&mut buf, frag_size, &clean), vec![msg]);
+    }
+
+    #[test]
+    fn header_mismatch_is_rejected(
+        (msg, frag_size, msg_id) in message(),
+        crc_flip in 1u32..,
+    ) {
+        let parts = fragment(&msg, msg_id, frag_size);
+        prop_assume!(parts.len() > 1);
+        let mut buf: FragBuf = FragBuf::new();
+        let (hdr, data) = &parts[0];
+        prop_assert_eq!(buf.handle_frag(*hdr, frag_size, data).map(|m| m.is_some()), Ok(false));
+
+        let (next, next_data) = &parts[1];
+        let bad_crc = FragHeader { crc: next.crc ^ crc_flip, ..*next };
+        prop_assert_eq!(
+            buf.handle_frag(bad_crc, frag_size, next_data).map(|m| m.is_some()),
+            Err(FragError::Mismatch)
+        );
+        let mut rejected = 1;
+
+        // Part 0 is a whole fragment, so it stays well formed with one more part
+        let bad_ttl = FragHeader { ttl_parts: hdr.ttl_parts + 1, ..*hdr };
+        if bad_ttl.ttl_parts <= MAX_FRAGS {
+            prop_assert_eq!(
+                buf.handle_frag(bad_ttl, frag_size, data).map(|m| m.is_some()),
+                Err(FragError::Mismatch)
+            );
+            rejected += 1;
+        }
+        prop_assert_eq!(buf.stats().rejected, rejected);
+
+        // The message still completes with the right headers
+        prop_assert_eq!(feed(&mut buf, frag_size, &parts[1..]), vec![msg]);
+    }
+}
+
+#[test]
+fn malformed_parts_are_rejected() {
+    let mut buf: FragBuf = FragBuf::new();
+    let hdr = FragHeader {
+        msg_id: 1,
+        part: 0,
+        ttl_parts: 2,
+        crc: 0,
+    };
+    // Not the last part, so it must be a whole fragment
+    assert_eq!(
+        buf.handle_frag(hdr, 32, &[0; 31]).map(|m| m.is_some()),
+        Err(FragError::Malformed)
+    );
+    // Part number past the end
+    let past = FragHeader { part: 2, ..hdr };
+    assert_eq!(
+        buf.handle_frag(past, 32, &[0; 32]).map(|m| m.is_some()),
+        Err(FragError::Malformed)
+    );
+    // No parts at all
+    let none = FragHeader {
+        ttl_parts: 0,
+        ..hdr
+    };
+    assert_eq!(
+        buf.handle_frag(none, 32, &[]).map(|m| m.is_some()),
+        Err(FragError::Malformed)
+    );
+    // Bigger fragments than this buffer was built for
+    let mut small: FragBuf<32, 256> = FragBuf::new();
+    assert_eq!(
+        small.handle_frag(hdr, 64, &[0; 64]).map(|m| m.is_some()),
+        Err(FragError::Malformed)
+    );
+    assert_eq!(buf.stats().rejected, 3);
+}
+
+#[test]
+fn small_buffer_overflows() {
+    let msg = [7u8; 300];
+    let parts = fragment(&msg, 9, 32);
+    let mut buf: FragBuf<32, 256> = FragBuf::new();
+    let (last, rest) = parts.split_last().unwrap();
+    for (hdr, data) in rest.iter().take(8) {
+        assert_eq!(
+            buf.handle_frag(*hdr, 32, data).map(|m| m.is_some()),
+            Ok(false)
+        );
+    }
+    assert_eq!(
+        buf.handle_frag(last.0, 32, &last.1).map(|m| m.is_some()),
+        Err(FragError::Overflow)
+    );
+}
+
+#[test]
+fn negotiated_params_bound_the_sender() {
+    let offer = |max_frag, frag_capacity| JoinParams {
+        protocol: PROTOCOL_VERSION,
+        firmware: FirmwareVersion {
+            major: 0,
+            minor: 1,
+            patch: 0,
+        },
+        max_frag,
+        frag_capacity,
+        caps: Capabilities::NONE,
+    };
+    let bridge = offer(<FragBuf>::MAX_FRAG, 4096);
+    let node = offer(MIN_FRAG_SIZE, 2048);
+    let params = bridge.negotiate(&node).unwrap();
+    assert_eq!(params.max_frag, MIN_FRAG_SIZE);
+    // Clamped so every message fits in MAX_FRAGS parts
+    assert_eq!(
+        params.frag_capacity,
+        MIN_FRAG_SIZE as u16 * MAX_FRAGS as u16
+    );
+
+    let max = params.frag_capacity as usize;
+    assert_eq!(params.frag_count(max), Ok(MAX_FRAGS));
+    assert_eq!(params.frag_count(1), Ok(1));
+    assert_eq!(
+        params.frag_count(max + 1),
+        Err(TooLarge { len: max + 1, max })
+    );
+}
+
+/// Fisher-Yates with a small xorshift, so failures shrink on the seed
+fn shuffle<T>(items: &mut [T], mut seed: u64) {
+    for i in (1..items.len()).rev() {
+        seed ^= seed << 13;
+        seed ^= seed >> 7;
+        seed ^= seed << 17;
+        items.swap(i, (seed % (i as u64 + 1)) as usize);
+    }
+}
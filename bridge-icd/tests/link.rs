@@ -0,0 +1,272 @@
+//! Tests for sealing the node <-> bridge radio link
+//!
+//! Run on the host with `cargo test`, using the software cipher.
+
+use bridge_icd::{
+    codec::take_topic,
+    link::{
+        nonce, Direction, Hello, LinkCipher, NetworkKey, Role, SealError, Session, SoftCcm,
+        Welcome, CTR_LEN, HELLO_LEN, MIC_LEN, REPLAY_WINDOW, SEAL_OVERHEAD, WELCOME_LEN,
+    },
+    seal_join_reply, seal_join_request, B2NTopic, Bridge2Node, Capabilities, FirmwareVersion,
+    JoinParams, LinkParams, N2BTopic, Node2Bridge, ESB_MAX_PAYLOAD, PROTOCOL_VERSION,
+};
+use proptest::prelude::*;
+
+const SERIAL: [u8; 8] = *b"node0001";
+const NODE_IV: [u8; 8] = [1, 2, 3, 4, 5, 6, 7, 8];
+const BRIDGE_IV: [u8; 8] = [9, 10, 11, 12, 13, 14, 15, 16];
+
+/// Both ends of one session
+fn sessions() -> (Session, Session) {
+    let link = NetworkKey::DEVELOPMENT.node_key(&SERIAL);
+    let node = Session::new(&link, &NODE_IV, &BRIDGE_IV, Role::Node);
+    let bridge = Session::new(&link, &NODE_IV, &BRIDGE_IV, Role::Bridge);
+    (node, bridge)
+}
+
+/// Seal `msg` into a new frame
+fn seal(session: &mut Session, msg: &[u8]) -> Vec<u8> {
+    let mut buf = vec![0u8; msg.len() + SEAL_OVERHEAD];
+    buf[CTR_LEN..][..msg.len()].copy_from_slice(msg);
+    let len = session.tx.seal(&SoftCcm, &mut buf, msg.len()).unwrap();
+    assert_eq!(len, buf.len());
+    buf
+}
+
+fn open(session: &mut Session, frame: &[u8]) -> Result<Vec<u8>, SealError> {
+    let mut buf = [0u8; 256];
+    session
+        .rx
+        .open(&SoftCcm, frame, &mut buf)
+        .map(|plain| plain.to_vec())
+}
+
+proptest! {
+    #[test]
+    fn sessions_interoperate(msgs in proptest::collection::vec(
+        proptest::collection::vec(any::<u8>(), 0..=200), 1..8,
+    )) {
+        let (mut node, mut bridge) = sessions();
+        for msg in msgs.iter() {
+            let frame = seal(&mut node, msg);
+            prop_assert_eq!(open(&mut bridge, &frame).unwrap(), msg.clone());
+
+            let frame = seal(&mut bridge, msg);
+            prop_assert_eq!(open(&mut node, &frame).unwrap(), msg.clone());
+        }
+    }
+
+    #[test]
+    fn tampering_is_detected(
+        msg in proptest::collection::vec(any::<u8>(), 1..=200),
+        at in any::<prop::sample::Index>(),
+        flip in 1u8..,
+    ) {
+        let (mut node, mut bridge) = sessions();
+        let mut frame = seal(&mut node, &msg);
+        // Anything after the counter, which would just change the nonce
+        let byte = CTR_LEN + at.index(frame.len() - CTR_LEN);
+        frame[byte] ^= flip;
+        prop_assert_eq!(open(&mut bridge, &frame), Err(SealError::Mic));
+    }
+
+    #[test]
+    fn software_ccm_round_trips(
+        key in any::<[u8; 16]>(),
+        iv in any::<[u8; 8]>(),
+        ctr in any::<u32>(),
+        msg in proptest::collection::vec(any::<u8>(), 0..=244),
+    ) {
+        let key = bridge_icd::link::LinkKey(key);
+        let nonce = nonce(ctr, Direction::NodeToBridge, &iv);
+        let mut buf = msg.clone();
+        let mic = SoftCcm.seal(&key, &nonce, &mut buf);
+        prop_assert_eq!(SoftCcm.open(&key, &nonce, &mut buf, &mic), Ok(()));
+        prop_assert_eq!(buf, msg);
+    }
+}
+
+#[test]
+fn replays_are_rejected() {
+    let (mut node, mut bridge) = sessions();
+    let frame = seal(&mut node, b"hello");
+    assert_eq!(open(&mut bridge, &frame).unwrap(), b"hello");
+    assert_eq!(open(&mut bridge, &frame), Err(SealError::Replay));
+}
+
+#[test]
+fn late_frames_within_the_window_are_accepted() {
+    let (mut node, mut bridge) = sessions();
+    let frames: Vec<_> = (0..=REPLAY_WINDOW + 1)
+        .map(|i| seal(&mut node, &[i as u8]))
+        .collect();
+
+    // The newest first, then the ones that were held up
+    let newest = REPLAY_WINDOW as usize;
+    assert!(open(&mut bridge, &frames[newest]).is_ok());
+    assert!(open(&mut bridge, &frames[1]).is_ok());
+    assert!(open(&mut bridge, &frames[newest - 1]).is_ok());
+    assert_eq!(open(&mut bridge, &frames[1]), Err(SealError::Replay));
+    // Too far behind to tell whether it was seen
+    assert_eq!(open(&mut bridge, &frames[0]), Err(SealError::Replay));
+    assert!(open(&mut bridge, &frames[newest + 1]).is_ok());
+}
+
+#[test]
+fn frames_only_open_in_their_session_and_direction() {
+    let (mut node, mut bridge) = sessions();
+
+    // Reflected back to the sender
+    let frame = seal(&mut node, b"ping");
+    assert_eq!(open(&mut node, &frame), Err(SealError::Mic));
+
+    // Another node's session with the same IVs
+    let other = NetworkKey::DEVELOPMENT.node_key(b"node0002");
+    let mut other = Session::new(&other, &NODE_IV, &BRIDGE_IV, Role::Bridge);
+    assert_eq!(open(&mut other, &frame), Err(SealError::Mic));
+
+    // A later session of the same node
+    let link = NetworkKey::DEVELOPMENT.node_key(&SERIAL);
+    let mut later = Session::new(&link, &NODE_IV, &[0; 8], Role::Bridge);
+    assert_eq!(open(&mut later, &frame), Err(SealError::Mic));
+
+    assert!(open(&mut bridge, &frame).is_ok());
+    assert_eq!(
+        open(&mut bridge, &[0; SEAL_OVERHEAD - 1]),
+        Err(SealError::Length)
+    );
+}
+
+#[test]
+fn join_handshake() {
+    let link = NetworkKey::DEVELOPMENT.node_key(&SERIAL);
+    let mut buf = [0u8; 64];
+
+    let hello = Hello {
+        serial: SERIAL,
+        node_iv: NODE_IV,
+    };
+    buf[HELLO_LEN..][..4].copy_from_slice(b"join");
+    let len = hello.seal(&SoftCcm, &link, &mut buf, 4).unwrap();
+    assert_eq!(len, HELLO_LEN + 4 + MIC_LEN);
+    let frame = &buf[..len];
+
+    // The bridge derives the key from the clear serial
+    let peeked = Hello::peek(frame).unwrap();
+    assert_eq!(peeked, hello);
+    let key = NetworkKey::DEVELOPMENT.node_key(&peeked.serial);
+    let mut out = [0u8; 64];
+    assert_eq!(
+        peeked.open(&SoftCcm, &key, frame, &mut out).unwrap(),
+        b"join"
+    );
+
+    // With another network's key it doesn't open
+    let wrong = NetworkKey::from_hex("000102030405060708090a0b0c0d0e0f").unwrap();
+    let wrong = wrong.node_key(&SERIAL);
+    assert_eq!(
+        peeked.open(&SoftCcm, &wrong, frame, &mut out),
+        Err(SealError::Mic)
+    );
+
+    let welcome = Welcome {
+        bridge_iv: BRIDGE_IV,
+    };
+    let mut buf = [0u8; 64];
+    buf[WELCOME_LEN..][..2].copy_from_slice(b"ok");
+    let len = welcome.seal(&SoftCcm, &link, &mut buf, 2).unwrap();
+    let frame = &buf[..len];
+    let peeked = Welcome::peek(frame).unwrap();
+    assert_eq!(peeked, welcome);
+    assert_eq!(
+        peeked.open(&SoftCcm, &link, frame, &mut out).unwrap(),
+        b"ok"
+    );
+}
+
+#[test]
+fn network_keys_parse_from_hex() {
+    let key = NetworkKey::from_hex("000102030405060708090A0B0C0D0E0F").unwrap();
+    assert_eq!(key.0, core::array::from_fn(|i| i as u8));
+    assert!(!key.is_development());
+    assert!(NetworkKey::from_hex("0001").is_none());
+    assert!(NetworkKey::from_hex("zz0102030405060708090a0b0c0d0e0f").is_none());
+    assert!(NetworkKey::from_build_env(None).is_development());
+}
+
+/// A node retries its join request, and the bridge its reply, under the same
+/// IV until the join goes through. With one nonce per IV, every repeat must
+/// be the same frame or CCM's keystream leaks
+#[test]
+fn repeated_joins_seal_the_same_frames() {
+    let link = NetworkKey::DEVELOPMENT.node_key(&SERIAL);
+    let hello = Hello {
+        serial: SERIAL,
+        node_iv: NODE_IV,
+    };
+    let request = Node2Bridge::Initialize {
+        serial: SERIAL,
+        params: JoinParams {
+            protocol: PROTOCOL_VERSION,
+            firmware: FirmwareVersion {
+                major: 1,
+                minor: 2,
+                patch: 3,
+            },
+            max_frag: 32,
+            frag_capacity: 1024,
+            caps: Capabilities::NONE,
+        },
+    };
+    let reply = Bridge2Node::InitializeAck {
+        serial: SERIAL,
+        use_pipe: 3,
+        params: LinkParams {
+            max_frag: 32,
+            frag_capacity: 1024,
+            caps: Capabilities::NONE,
+        },
+        node_iv: NODE_IV,
+    };
+    let welcome = Welcome {
+        bridge_iv: BRIDGE_IV,
+    };
+
+    let request_frame = || {
+        let mut buf = [0u8; ESB_MAX_PAYLOAD as usize];
+        let len = seal_join_request(&SoftCcm, &link, &hello, &request, &mut buf).unwrap();
+        buf[..len].to_vec()
+    };
+    let reply_frame = || {
+        let mut buf = [0u8; ESB_MAX_PAYLOAD as usize];
+        let len = seal_join_reply(&SoftCcm, &link, &welcome, &reply, &mut buf).unwrap();
+        buf[..len].to_vec()
+    };
+
+    let first = request_frame();
+    for _ in 0..3 {
+        assert_eq!(request_frame(), first);
+    }
+    let mut out = [0u8; 64];
+    let plain = Hello::peek(&first)
+        .unwrap()
+        .open(&SoftCcm, &link, &first, &mut out)
+        .unwrap();
+    let opened = take_topic::<N2BTopic>(plain).unwrap();
+    assert!(matches!(opened.msg, Node2Bridge::Initialize { serial, .. } if serial == SERIAL));
+
+    let first = reply_frame();
+    for _ in 0..3 {
+        assert_eq!(reply_frame(), first);
+    }
+    let plain = Welcome::peek(&first)
+        .unwrap()
+        .open(&SoftCcm, &link, &first, &mut out)
+        .unwrap();
+    let opened = take_topic::<B2NTopic>(plain).unwrap();
+    assert!(matches!(
+        opened.msg,
+        Bridge2Node::InitializeAck { use_pipe: 3, .. }
+    ));
+}
@@ -0,0 +1,136 @@
+//! Tests for estimating network time from keepalive replies
+
+use bridge_icd::time::{ClockSync, Sample, MAX_BASELINE_US, MIN_BASELINE_US, STEP_US};
+use proptest::prelude::*;
+
+/// A keepalive every three seconds
+const PERIOD_US: u64 = 3_000_000;
+/// Somewhere in 2024
+const EPOCH_US: u64 = 1_700_000_000_000_000;
+/// How late or early the bridge may handle a keepalive
+const JITTER_US: i64 = 200;
+
+/// The wall clock at local time `local_us`, for a node whose crystal is
+/// `ppm` slow and which booted at `EPOCH_US`
+fn wall(local_us: u64, ppm: i64) -> u64 {
+    (EPOCH_US + local_us).wrapping_add_signed((local_us as i128 * ppm as i128 / 1_000_000) as i64)
+}
+
+fn sample(local_us: u64, ppm: i64) -> Sample {
+    Sample {
+        local_us,
+        remote_us: wall(local_us, ppm),
+    }
+}
+
+#[test]
+fn unsynced_until_the_first_sample() {
+    let mut clock = ClockSync::new();
+    assert_eq!(clock.now(0), None);
+
+    clock.update(sample(1_000, 0));
+    assert_eq!(clock.now(1_000), Some(wall(1_000, 0)));
+    assert_eq!(clock.now(11_000), Some(wall(11_000, 0)));
+    assert_eq!(clock.drift_ppb(), 0);
+}
+
+#[test]
+fn step_starts_over() {
+    let mut clock = ClockSync::new();
+    for i in 1..=20 {
+        clock.update(sample(i * PERIOD_US, 20));
+    }
+    assert_ne!(clock.drift_ppb(), 0);
+
+    // The host's clock was set an hour back
+    let local_us = 21 * PERIOD_US;
+    let remote_us = wall(local_us, 20) - 3_600_000_000;
+    clock.update(Sample {
+        local_us,
+        remote_us,
+    });
+    assert_eq!(clock.now(local_us), Some(remote_us));
+    assert_eq!(clock.drift_ppb(), 0);
+}
+
+#[test]
+fn stale_samples_are_ignored() {
+    let mut clock = ClockSync::new();
+    clock.update(sample(2 * PERIOD_US, 0));
+    let before = clock;
+    clock.update(sample(PERIOD_US, 0));
+    assert_eq!(clock, before);
+}
+
+#[test]
+fn jitter_is_smoothed() {
+    let mut clock = ClockSync::new();
+    clock.update(sample(PERIOD_US, 0));
+
+    // One reply handled late is only followed by a quarter
+    let late = Sample {
+        local_us: 2 * PERIOD_US,
+        remote_us: wall(2 * PERIOD_US, 0) + 4_000,
+    };
+    clock.update(late);
+    assert_eq!(
+        clock.now(late.local_us),
+        Some(wall(late.local_us, 0) + 1_000)
+    );
+
+    // And is forgotten again as good ones come in
+    let mut err = 1_000;
+    for i in 3..10 {
+        clock.update(sample(i * PERIOD_US, 0));
+        let now = clock.now(i * PERIOD_US).unwrap() as i64 - wall(i * PERIOD_US, 0) as i64;
+        assert!(now < err, "still off by {now}us");
+        err = now;
+    }
+    assert!(err < 150);
+}
+
+proptest! {
+    #[test]
+    fn drift_is_tracked(
+        ppm in -50i64..=50,
+        jitter in proptest::collection::vec(-JITTER_US..=JITTER_US, 400),
+    ) {
+        // Both ends of the first baseline may be off the most
+        let bound = 2 * JITTER_US * 1_000_000_000 / MIN_BASELINE_US as i64 + 1;
+        let mut clock = ClockSync::new();
+        for (i, j) in jitter.iter().enumerate() {
+            let local_us = (i as u64 + 1) * PERIOD_US;
+            clock.update(Sample {
+                local_us,
+                remote_us: wall(local_us, ppm).wrapping_add_signed(*j),
+            });
+            if local_us - PERIOD_US >= MIN_BASELINE_US {
+                let err = clock.drift_ppb() - ppm * 1_000;
+                prop_assert!(err.abs() <= bound, "drift off by {}ppb", err);
+            }
+        }
+
+        // Past a baseline's worth of samples, a minute without any keeps
+        // within a millisecond
+        let last = jitter.len() as u64 * PERIOD_US;
+        prop_assert!(last > MAX_BASELINE_US);
+        let later = last + 60_000_000;
+        let err = clock.now(later).unwrap() as i64 - wall(later, ppm) as i64;
+        prop_assert!(err.abs() < 1_000, "off by {}us", err);
+    }
+
+    #[test]
+    fn small_corrections_are_smoothed(err in -(STEP_US as i64)..=STEP_US as i64) {
+        let mut clock = ClockSync::new();
+        for i in 1..=20 {
+            clock.update(sample(i * PERIOD_US, 10));
+        }
+        let local_us = 21 * PERIOD_US;
+        let predicted = clock.now(local_us).unwrap();
+        clock.update(Sample {
+            local_us,
+            remote_us: predicted.wrapping_add_signed(err),
+        });
+        prop_assert_eq!(clock.now(local_us), Some(predicted.wrapping_add_signed(err / 4)));
+    }
+}
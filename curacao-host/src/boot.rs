@@ -0,0 +1,54 @@
+use bootloader_icd::scratch::{BootMessage, FaultRegs};
+use serde::Serialize;
+
+/// A [`BootMessage`] with the panic reason decoded as text
+#[derive(Debug, Serialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum BootState {
+    None,
+    StayInBootloader,
+    JustBoot,
+    BootAttempted,
+    AppPanicked { uptime: u64, reason: String },
+    BootPanicked { uptime: u64, reason: String },
+    AppFaulted { uptime: u64, regs: FaultRegs },
+}
+
+impl From<Option<BootMessage>> for BootState {
+    fn from(value: Option<BootMessage>) -> Self {
+        match value {
+            None => BootState::None,
+            Some(BootMessage::StayInBootloader) => BootState::StayInBootloader,
+            Some(BootMessage::JustBoot) => BootState::JustBoot,
+            Some(BootMessage::BootAttempted) => BootState::BootAttempted,
+            Some(BootMessage::AppPanicked { uptime, reason }) => BootState::AppPanicked {
+                uptime,
+                reason: String::from_utf8_lossy(&reason).into_owned(),
+            },
+            Some(BootMessage::BootPanicked { uptime, reason }) => BootState::BootPanicked {
+                uptime,
+                reason: String::from_utf8_lossy(&reason).into_owned(),
+            },
+            Some(BootMessage::AppFaulted { uptime, regs }) => BootState::AppFaulted { uptime, regs },
+        }
+    }
+}
+
+/// Names of the bits set in the nRF52840 RESETREAS register
+pub fn reset_causes(raw: u32) -> Vec<&'static str> {
+    const BITS: &[(u32, &str)] = &[
+        (0, "pin"),
+        (1, "watchdog"),
+        (2, "soft_reset"),
+        (3, "lockup"),
+        (16, "system_off"),
+        (17, "lpcomp"),
+        (18, "debug_interface"),
+        (19, "nfc"),
+        (20, "vbus"),
+    ];
+    BITS.iter()
+        .filter(|(bit, _)| raw & (1 << bit) != 0)
+        .map(|(_, name)| *name)
+        .collect()
+}
@@ -0,0 +1,375 @@
+//! Firmware bundles (`.cura` files)
+//!
+//! A bundle is a tar archive holding:
+//!
+//! * `manifest.toml`: what the image is for, see [`Manifest`]
+//! * `image.bin`: the flat image, starting at `manifest.base`
+//! * `image.sha256`: hex SHA-256 of `image.bin`
+//! * `signature.ed25519`: optional hex ed25519 signature over the bytes of
+//!   `manifest.toml` followed by the raw SHA-256 of the image
+//!
+//! Before writing, the manifest is checked against what the bootloader
+//! reports about itself, so an image can't land on the wrong board or on a
+//! bootloader that speaks a different protocol.
+
+use std::{
+    fmt,
+    io::{self, Read, Write},
+};
+
+use bootloader_icd::{
+    BootloadEndpoint, BootloaderInfo, EraseFlashEndpoint, GetAppFlashInfoEndpoint,
+    GetBootloaderInfoEndpoint, Version, WriteFlashEndpoint,
+};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use postcard_rpc::{Endpoint, Key};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    codes,
+    image::{hex, unhex},
+    FirmwareImage,
+};
+
+/// Bumped when the layout of a bundle changes incompatibly
+pub const FORMAT: u32 = 1;
+
+const MANIFEST: &str = "manifest.toml";
+const IMAGE: &str = "image.bin";
+const DIGEST: &str = "image.sha256";
+const SIGNATURE: &str = "signature.ed25519";
+
+#[derive(Debug)]
+pub enum BundleError {
+    Io(io::Error),
+    /// The archive or manifest is malformed
+    Format(String),
+    /// The image doesn't match its digest
+    Digest,
+    /// The signature is missing or doesn't verify
+    Signature(String),
+    /// The bundle isn't meant for this device
+    Incompatible(String),
+}
+
+impl fmt::Display for BundleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BundleError::Io(e) => write!(f, "I/O error: {e}"),
+            BundleError::Format(s) => write!(f, "malformed bundle: {s}"),
+            BundleError::Digest => write!(f, "image does not match its digest"),
+            BundleError::Signature(s) => write!(f, "bad signature: {s}"),
+            BundleError::Incompatible(s) => write!(f, "bundle does not fit this device: {s}"),
+        }
+    }
+}
+
+impl std::error::Error for BundleError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            BundleError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl BundleError {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            BundleError::Io(_) => "io",
+            _ => "bundle",
+        }
+    }
+
+    pub fn code(&self) -> u16 {
+        match self {
+            BundleError::Io(_) => codes::IO,
+            _ => codes::BUNDLE,
+        }
+    }
+}
+
+impl From<io::Error> for BundleError {
+    fn from(e: io::Error) -> Self {
+        BundleError::Io(e)
+    }
+}
+
+/// A bootloader endpoint, and the keys its request and response hash to
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct IcdKey {
+    pub path: String,
+    /// Hex of the 8-byte request key
+    pub request: String,
+    /// Hex of the 8-byte response key
+    pub response: String,
+}
+
+impl IcdKey {
+    pub fn new(path: &str, request: Key, response: Key) -> Self {
+        Self {
+            path: path.to_string(),
+            request: hex(&request.to_bytes()),
+            response: hex(&response.to_bytes()),
+        }
+    }
+
+    pub fn of<E: Endpoint>() -> Self {
+        Self::new(E::PATH, E::REQ_KEY, E::RESP_KEY)
+    }
+
+    /// Keys of the bootloader endpoints every flash uses, as this tool was built with
+    ///
+    /// Reading back and hashing flash are left out: delta and resumed
+    /// flashes check for those endpoints themselves.
+    pub fn flashing() -> Vec<Self> {
+        vec![
+            Self::of::<GetAppFlashInfoEndpoint>(),
+            Self::of::<GetBootloaderInfoEndpoint>(),
+            Self::of::<EraseFlashEndpoint>(),
+            Self::of::<WriteFlashEndpoint>(),
+            Self::of::<BootloadEndpoint>(),
+        ]
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Manifest {
+    pub format: u32,
+    /// Must match the board reported by the bootloader
+    pub board: String,
+    #[serde(serialize_with = "ser_version", deserialize_with = "de_version")]
+    pub min_bootloader: Version,
+    /// Free-form version of the app, for humans and reports
+    pub image_version: String,
+    /// Flash address of the first byte of the image
+    pub base: u32,
+    pub len: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub build_id: Option<String>,
+    /// Bootloader endpoints used to flash the image, which the device must serve unchanged
+    pub icd_keys: Vec<IcdKey>,
+}
+
+impl Manifest {
+    /// Check the manifest against what the bootloader reports about itself
+    pub fn check(&self, info: Option<&BootloaderInfo>, served: &[IcdKey]) -> Result<(), BundleError> {
+        let Some(info) = info else {
+            return Err(BundleError::Incompatible(
+                "bootloader is too old to report its board and version".into(),
+            ));
+        };
+        if info.board != self.board {
+            return Err(BundleError::Incompatible(format!(
+                "bundle is for board '{}', device is '{}'",
+                self.board, info.board
+            )));
+        }
+        if info.version < self.min_bootloader {
+            return Err(BundleError::Incompatible(format!(
+                "bundle needs bootloader {} or newer, device has {}",
+                self.min_bootloader, info.version
+            )));
+        }
+        for key in self.icd_keys.iter() {
+            match served.iter().find(|k| k.path == key.path) {
+                Some(k) if k == key => {}
+                Some(_) => {
+                    return Err(BundleError::Incompatible(format!(
+                        "bootloader endpoint '{}' has a different schema",
+                        key.path
+                    )))
+                }
+                None => {
+                    return Err(BundleError::Incompatible(format!(
+                        "bootloader does not serve '{}'",
+                        key.path
+                    )))
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Bundle {
+    pub manifest: Manifest,
+    /// The manifest exactly as stored, which is what the signature covers
+    manifest_bytes: Vec<u8>,
+    pub image: Vec<u8>,
+    pub digest: [u8; 32],
+    pub signature: Option<[u8; 64]>,
+}
+
+impl Bundle {
+    /// Bundle up `image`. The manifest's `base`, `len` and `build_id` are
+    /// taken from the image
+    pub fn new(mut manifest: Manifest, image: &FirmwareImage) -> Result<Self, BundleError> {
+        manifest.format = FORMAT;
+        manifest.base = image.base;
+        manifest.len = image.data.len() as u32;
+        manifest.build_id = image.build_id_hex();
+        let manifest_bytes = toml::to_string(&manifest)
+            .map_err(|e| BundleError::Format(e.to_string()))?
+            .into_bytes();
+        Ok(Self {
+            manifest,
+            manifest_bytes,
+            image: image.data.clone(),
+            digest: Sha256::digest(&image.data).into(),
+            signature: None,
+        })
+    }
+
+    fn signed_message(&self) -> Vec<u8> {
+        let mut msg = self.manifest_bytes.clone();
+        msg.extend_from_slice(&self.digest);
+        msg
+    }
+
+    pub fn sign(&mut self, key: &SigningKey) {
+        self.signature = Some(key.sign(&self.signed_message()).to_bytes());
+    }
+
+    pub fn verify(&self, key: &VerifyingKey) -> Result<(), BundleError> {
+        let sig = self
+            .signature
+            .ok_or_else(|| BundleError::Signature("bundle is not signed".into()))?;
+        key.verify(&self.signed_message(), &Signature::from_bytes(&sig))
+            .map_err(|e| BundleError::Signature(e.to_string()))
+    }
+
+    /// The image to flash, as if it had been loaded from the original ELF
+    pub fn firmware_image(&self) -> FirmwareImage {
+        FirmwareImage {
+            base: self.manifest.base,
+            data: self.image.clone(),
+            build_id: self.manifest.build_id.as_deref().and_then(unhex),
+            vector_table: None,
+        }
+    }
+
+    pub fn write(&self, w: impl Write) -> Result<(), BundleError> {
+        let mut tar = tar::Builder::new(w);
+        let mut add = |name: &str, data: &[u8]| {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            tar.append_data(&mut header, name, data)
+        };
+        add(MANIFEST, &self.manifest_bytes)?;
+        add(IMAGE, &self.image)?;
+        add(DIGEST, hex(&self.digest).as_bytes())?;
+        if let Some(sig) = self.signature.as_ref() {
+            add(SIGNATURE, hex(sig).as_bytes())?;
+        }
+        tar.into_inner()?.flush()?;
+        Ok(())
+    }
+
+    /// Read a bundle, checking its format and digest, but not its signature
+    pub fn read(r: impl Read) -> Result<Self, BundleError> {
+        let mut manifest_bytes = None;
+        let mut image = None;
+        let mut digest = None;
+        let mut signature = None;
+
+        let mut tar = tar::Archive::new(r);
+        for entry in tar.entries()? {
+            let mut entry = entry?;
+            let name = entry.path()?.to_string_lossy().into_owned();
+            let mut data = vec![];
+            entry.read_to_end(&mut data)?;
+            match name.as_str() {
+                MANIFEST => manifest_bytes = Some(data),
+                IMAGE => image = Some(data),
+                DIGEST => digest = Some(data),
+                SIGNATURE => signature = Some(data),
+                // Allow later versions to add files
+                _ => {}
+            }
+        }
+
+        let missing = |name: &str| BundleError::Format(format!("missing {name}"));
+        let manifest_bytes = manifest_bytes.ok_or_else(|| missing(MANIFEST))?;
+        let image = image.ok_or_else(|| missing(IMAGE))?;
+        let digest = digest.ok_or_else(|| missing(DIGEST))?;
+
+        let manifest_str = std::str::from_utf8(&manifest_bytes)
+            .map_err(|e| BundleError::Format(format!("{MANIFEST}: {e}")))?;
+        let manifest: Manifest =
+            toml::from_str(manifest_str).map_err(|e| BundleError::Format(format!("{MANIFEST}: {e}")))?;
+        if manifest.format != FORMAT {
+            return Err(BundleError::Format(format!(
+                "format {} is not supported, expected {FORMAT}",
+                manifest.format
+            )));
+        }
+        if manifest.len as usize != image.len() {
+            return Err(BundleError::Format(format!(
+                "{IMAGE} is {} bytes, manifest says {}",
+                image.len(),
+                manifest.len
+            )));
+        }
+
+        let digest: [u8; 32] = parse_hex_file(&digest, DIGEST)?;
+        if Sha256::digest(&image)[..] != digest[..] {
+            return Err(BundleError::Digest);
+        }
+        let signature = match signature {
+            Some(s) => Some(parse_hex_file::<64>(&s, SIGNATURE)?),
+            None => None,
+        };
+
+        Ok(Self {
+            manifest,
+            manifest_bytes,
+            image,
+            digest,
+            signature,
+        })
+    }
+}
+
+/// Read an ed25519 signing key, stored as 32 bytes of hex
+pub fn signing_key(text: &str) -> Result<SigningKey, BundleError> {
+    parse_hex_file::<32>(text.as_bytes(), "signing key").map(|k| SigningKey::from_bytes(&k))
+}
+
+/// Read an ed25519 public key, stored as 32 bytes of hex
+pub fn verifying_key(text: &str) -> Result<VerifyingKey, BundleError> {
+    let bytes = parse_hex_file::<32>(text.as_bytes(), "verifying key")?;
+    VerifyingKey::from_bytes(&bytes).map_err(|e| BundleError::Signature(e.to_string()))
+}
+
+fn parse_hex_file<const N: usize>(data: &[u8], what: &str) -> Result<[u8; N], BundleError> {
+    std::str::from_utf8(data)
+        .ok()
+        .and_then(|s| unhex(s.trim()))
+        .and_then(|v| v.try_into().ok())
+        .ok_or_else(|| BundleError::Format(format!("{what} is not {N} bytes of hex")))
+}
+
+/// Parse a version written as `major.minor.patch`
+pub fn parse_version(s: &str) -> Option<Version> {
+    let mut parts = s.trim().splitn(3, '.').map(|p| p.parse::<u16>().ok());
+    Some(Version {
+        major: parts.next()??,
+        minor: parts.next().unwrap_or(Some(0))?,
+        patch: parts.next().unwrap_or(Some(0))?,
+    })
+}
+
+fn ser_version<S: Serializer>(v: &Version, ser: S) -> Result<S::Ok, S::Error> {
+    ser.collect_str(v)
+}
+
+fn de_version<'de, D: Deserializer<'de>>(de: D) -> Result<Version, D::Error> {
+    let s = String::deserialize(de)?;
+    parse_version(&s).ok_or_else(|| serde::de::Error::custom(format!("invalid version '{s}'")))
+}
@@ -0,0 +1,371 @@
+use std::{
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use bootloader_icd::{
+    scratch::BootMessage, AppPartitionInfo, BootloadEndpoint, BootloaderInfo, DataChunk,
+    EraseFlashEndpoint, FlashEraseCommand, FlashReadCommand, FlashWriteCommand,
+    GetAppFlashInfoEndpoint, GetBootMessageEndpoint, GetBootloaderInfoEndpoint,
+    HashFlashEndpoint, ReadFlashEndpoint, RebootReasonEndpoint, WriteError, WriteFlashEndpoint,
+};
+use postcard_rpc::Endpoint;
+use poststation_sdk::SquadClient;
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::time::{sleep, timeout};
+
+use crate::{bundle::IcdKey, Error};
+
+/// How long to wait for each kind of request before giving up
+#[derive(Debug, Clone, Copy)]
+pub struct Timeouts {
+    pub info: Duration,
+    pub read: Duration,
+    /// Erasing walks every sector in the range, so this should allow for
+    /// erasing the whole partition
+    pub erase: Duration,
+    pub write: Duration,
+    pub boot: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            info: Duration::from_secs(1),
+            read: Duration::from_secs(2),
+            erase: Duration::from_secs(30),
+            write: Duration::from_secs(2),
+            boot: Duration::from_secs(2),
+        }
+    }
+}
+
+/// How to retry requests that failed with a transient error
+///
+/// See [`Error::is_transient`]. Errors reported by the device are never
+/// retried, and neither is [`BootloaderClient::boot`].
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    /// Total number of attempts, including the first one
+    pub attempts: u32,
+    /// Delay before the first retry, multiplied by the attempt number for
+    /// later retries
+    pub backoff: Duration,
+}
+
+impl RetryPolicy {
+    pub const NONE: Self = Self {
+        attempts: 1,
+        backoff: Duration::ZERO,
+    };
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            attempts: 3,
+            backoff: Duration::from_millis(50),
+        }
+    }
+}
+
+/// A typed client for the curacao bootloader, proxied through poststation
+pub struct BootloaderClient {
+    serial: u64,
+    client: SquadClient,
+    ctr: AtomicU32,
+    timeouts: Timeouts,
+    retry: RetryPolicy,
+    info: Mutex<Option<AppPartitionInfo>>,
+}
+
+impl BootloaderClient {
+    pub fn new(client: SquadClient, serial: u64) -> Self {
+        Self {
+            serial,
+            client,
+            ctr: AtomicU32::new(0),
+            timeouts: Timeouts::default(),
+            retry: RetryPolicy::default(),
+            info: Mutex::new(None),
+        }
+    }
+
+    pub fn with_timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    pub fn with_retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    pub fn serial(&self) -> u64 {
+        self.serial
+    }
+
+    pub fn client(&self) -> &SquadClient {
+        &self.client
+    }
+
+    pub fn into_client(self) -> SquadClient {
+        self.client
+    }
+
+    #[inline(always)]
+    fn ctr(&self) -> u32 {
+        self.ctr.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Make a request to any endpoint of the bootloader, applying the timeout
+    /// and retry policy
+    pub async fn call<E>(&self, req: &E::Request, tout: Duration) -> Result<E::Response, Error>
+    where
+        E: Endpoint,
+        E::Request: Serialize,
+        E::Response: DeserializeOwned,
+    {
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            let err = match self.call_once::<E>(req, tout).await {
+                Ok(resp) => return Ok(resp),
+                Err(e) => e,
+            };
+            if attempt >= self.retry.attempts || !err.is_transient() {
+                return Err(err);
+            }
+            sleep(self.retry.backoff * attempt).await;
+        }
+    }
+
+    /// Make a single request, for requests that must not be repeated
+    pub async fn call_once<E>(&self, req: &E::Request, tout: Duration) -> Result<E::Response, Error>
+    where
+        E: Endpoint,
+        E::Request: Serialize,
+        E::Response: DeserializeOwned,
+    {
+        let fut = self.client.proxy_endpoint::<E>(self.serial, self.ctr(), req);
+        match timeout(tout, fut).await {
+            Ok(Ok(resp)) => Ok(resp),
+            Ok(Err(e)) => Err(Error::Transport(e)),
+            Err(_) => Err(Error::Timeout {
+                path: E::PATH,
+                after: tout,
+            }),
+        }
+    }
+
+    /// Get the app partition info. This is cached after the first request
+    pub async fn partinfo(&self) -> Result<AppPartitionInfo, Error> {
+        if let Some(info) = *self.info.lock().unwrap() {
+            return Ok(info);
+        }
+        let info = self
+            .call::<GetAppFlashInfoEndpoint>(&(), self.timeouts.info)
+            .await?;
+        *self.info.lock().unwrap() = Some(info);
+        Ok(info)
+    }
+
+    /// Read a single chunk, no larger than the partition's transfer chunk
+    pub async fn read_chunk(&self, start: u32, len: u32) -> Result<DataChunk, Error> {
+        self.call::<ReadFlashEndpoint>(&FlashReadCommand { start, len }, self.timeouts.read)
+            .await?
+            .map_err(Error::Read)
+    }
+
+    /// Read any range of the partition
+    pub async fn read(&self, start: u32, len: u32) -> Result<Vec<u8>, Error> {
+        let info = self.partinfo().await?;
+        check_range(&info, start, len)?;
+        let mut out = Vec::with_capacity(len as usize);
+        let mut addr = start;
+        let end = start + len;
+        while addr < end {
+            let take = (end - addr).min(info.transfer_chunk);
+            let data = self.read_chunk(addr, take).await?;
+            out.extend_from_slice(&data.data);
+            addr += take;
+        }
+        Ok(out)
+    }
+
+    /// CRC-32 of a range, computed on the device. See [`Self::has_endpoint`]
+    /// for checking whether the bootloader supports this.
+    pub async fn crc32(&self, start: u32, len: u32) -> Result<u32, Error> {
+        let info = self.partinfo().await?;
+        check_range(&info, start, len)?;
+        self.call::<HashFlashEndpoint>(&FlashReadCommand { start, len }, self.timeouts.read)
+            .await?
+            .map_err(Error::Read)
+    }
+
+    /// The bootloader's version and board, or `None` if it is too old to say
+    pub async fn bootloader_info(&self) -> Result<Option<BootloaderInfo>, Error> {
+        if !self.has_endpoint(GetBootloaderInfoEndpoint::PATH).await? {
+            return Ok(None);
+        }
+        self.call::<GetBootloaderInfoEndpoint>(&(), self.timeouts.info)
+            .await
+            .map(Some)
+    }
+
+    /// Keys of every endpoint the bootloader serves, to check bundles against
+    pub async fn icd_keys(&self) -> Result<Vec<IcdKey>, Error> {
+        let schema = self
+            .client
+            .get_device_schemas(self.serial)
+            .await
+            .map_err(Error::Transport)?;
+        Ok(schema
+            .map(|s| {
+                s.endpoints
+                    .iter()
+                    .map(|e| IcdKey::new(&e.path, e.req_key, e.resp_key))
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    /// Does the bootloader serve the endpoint at `path`? Older bootloaders
+    /// may lack some endpoints
+    pub async fn has_endpoint(&self, path: &str) -> Result<bool, Error> {
+        let schema = self
+            .client
+            .get_device_schemas(self.serial)
+            .await
+            .map_err(Error::Transport)?;
+        Ok(schema.is_some_and(|s| s.endpoints.iter().any(|e| e.path == path)))
+    }
+
+    /// Erase a range, which must be aligned to the erase size
+    pub async fn erase(&self, start: u32, len: u32) -> Result<(), Error> {
+        let info = self.partinfo().await?;
+        check_range(&info, start, len)?;
+        if start % info.erase_sz != 0 || len % info.erase_sz != 0 {
+            return Err(Error::Layout(format!(
+                "erase {start:08X}+{len} is not aligned to {}B sectors",
+                info.erase_sz
+            )));
+        }
+        self.call::<EraseFlashEndpoint>(
+            &FlashEraseCommand {
+                start,
+                len,
+                force: false,
+            },
+            self.timeouts.erase,
+        )
+        .await?
+        .map_err(Error::Erase)
+    }
+
+    /// Write data to an erased range, in transfer chunk sized pieces
+    pub async fn write(&self, start: u32, data: &[u8]) -> Result<(), Error> {
+        self.write_with_progress(start, data, |_, _| {}).await
+    }
+
+    /// Write data to an erased range, calling `progress` with the number of
+    /// bytes written and total bytes after each chunk
+    pub async fn write_with_progress(
+        &self,
+        start: u32,
+        data: &[u8],
+        mut progress: impl FnMut(usize, usize),
+    ) -> Result<(), Error> {
+        let info = self.partinfo().await?;
+        check_range(&info, start, data.len() as u32)?;
+        // Both are powers of two, so the larger is a multiple of the smaller
+        let unit = info.write_sz.max(info.align).max(1);
+        if start % unit != 0 || data.len() as u32 % info.write_sz.max(1) != 0 {
+            return Err(Error::Layout(format!(
+                "write {start:08X}+{} is not aligned to {}B words",
+                data.len(),
+                unit
+            )));
+        }
+
+        // Keep chunks a multiple of the write size and buffer alignment
+        let chunk = (info.transfer_chunk - (info.transfer_chunk % unit)) as usize;
+        if chunk == 0 {
+            return Err(Error::Layout(format!(
+                "transfer chunk {}B is smaller than a {unit}B write",
+                info.transfer_chunk
+            )));
+        }
+        let mut done = 0;
+        for (i, ch) in data.chunks(chunk).enumerate() {
+            let addr = start + (i * chunk) as u32;
+            self.write_chunk(addr, ch).await?;
+            done += ch.len();
+            progress(done, data.len());
+        }
+        Ok(())
+    }
+
+    async fn write_chunk(&self, start: u32, data: &[u8]) -> Result<(), Error> {
+        let res = self
+            .call::<WriteFlashEndpoint>(
+                &FlashWriteCommand {
+                    start,
+                    data: data.to_vec(),
+                    force: false,
+                },
+                self.timeouts.write,
+            )
+            .await?;
+        match res {
+            Ok(()) => Ok(()),
+            // If a retried write already landed before its response was lost,
+            // the flash will already contain what we wanted
+            Err(WriteError::NeedsErase) => {
+                let current = self.read_chunk(start, data.len() as u32).await?;
+                if current.data == data {
+                    Ok(())
+                } else {
+                    Err(Error::Write(WriteError::NeedsErase))
+                }
+            }
+            Err(e) => Err(Error::Write(e)),
+        }
+    }
+
+    pub async fn boot_msg(&self) -> Result<Option<BootMessage>, Error> {
+        self.call::<GetBootMessageEndpoint>(&(), self.timeouts.info)
+            .await
+    }
+
+    /// Command the bootloader to boot the app
+    ///
+    /// This is never retried: once the bootloader has acted on it, it is gone,
+    /// and asking again would fail or boot the app twice.
+    pub async fn boot(&self) -> Result<(), Error> {
+        self.call_once::<BootloadEndpoint>(&(), self.timeouts.boot)
+            .await?
+            .map_err(Error::Boot)
+    }
+
+    /// The raw RESETREAS register value, see [`reset_causes`](crate::reset_causes)
+    pub async fn reboot_reason(&self) -> Result<u32, Error> {
+        self.call::<RebootReasonEndpoint>(&(), self.timeouts.info)
+            .await
+    }
+}
+
+fn check_range(info: &AppPartitionInfo, start: u32, len: u32) -> Result<(), Error> {
+    let end = start.checked_add(len);
+    let pend = info.start + info.len;
+    if start < info.start || end.map_or(true, |e| e > pend) {
+        return Err(Error::Layout(format!(
+            "{start:08X}+{len} is outside of the app partition {:08X}..{pend:08X}",
+            info.start
+        )));
+    }
+    Ok(())
+}
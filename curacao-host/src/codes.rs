@@ -0,0 +1,43 @@
+//! Stable numeric codes for errors in machine-readable output
+//!
+//! Every tool built on this crate reports its errors with these codes, so a
+//! code means the same thing whichever tool printed it. Codes from 0x0100
+//! up are reported by the bootloader itself, see `bootloader_icd`.
+
+/// The poststation server or proxy request failed
+pub const TRANSPORT: u16 = 0x0001;
+/// Local file I/O failed
+pub const IO: u16 = 0x0002;
+/// The command or its arguments were invalid
+pub const USAGE: u16 = 0x0003;
+/// The expected device did not show up
+pub const DEVICE_NOT_FOUND: u16 = 0x0004;
+/// The firmware image could not be built
+pub const IMAGE: u16 = 0x0005;
+/// The device did not answer within the timeout
+pub const TIMEOUT: u16 = 0x0006;
+/// The request doesn't fit the device's app partition
+pub const LAYOUT: u16 = 0x0007;
+/// The command was interrupted with Ctrl-C
+pub const CANCELLED: u16 = 0x0008;
+/// The app didn't come up after flashing
+pub const HEALTH_CHECK: u16 = 0x0009;
+/// Some devices in a fleet failed to flash
+pub const FLEET: u16 = 0x000A;
+/// The firmware bundle is invalid or doesn't fit the device
+pub const BUNDLE: u16 = 0x000B;
+/// The connection settings or config file are invalid
+pub const CONFIG: u16 = 0x000C;
+
+/// The kind of the `io::Error` that `e` comes down to, if any, for reporting
+/// next to the code
+pub fn io_kind(e: &(dyn std::error::Error + 'static)) -> Option<std::io::ErrorKind> {
+    let mut next = Some(e);
+    while let Some(e) = next {
+        if let Some(io) = e.downcast_ref::<std::io::Error>() {
+            return Some(io.kind());
+        }
+        next = e.source();
+    }
+    None
+}
@@ -0,0 +1,140 @@
+//! Where to find the poststation server
+//!
+//! The server address is taken from the first of these that is set:
+//!
+//! 1. `--server` on the command line
+//! 2. `--profile` on the command line, naming a profile from the config file
+//! 3. The `CURACAO_SERVER` environment variable
+//! 4. The `CURACAO_PROFILE` environment variable
+//! 5. The `default` profile named in the config file
+//! 6. `localhost:51837`
+//!
+//! The config file is `$CURACAO_CONFIG` if set, or
+//! `~/.config/curacao/config.toml`, and may be missing:
+//!
+//! ```toml
+//! default = "lab"
+//!
+//! [profile.lab]
+//! server = "lab-pi.local:51837"
+//!
+//! [profile.local]
+//! server = "localhost:51837"
+//! ```
+
+use std::{collections::BTreeMap, env, fs, io, path::PathBuf, time::Duration};
+
+use poststation_sdk::SquadClient;
+use serde::Deserialize;
+use tokio::{net::TcpStream, time::timeout};
+
+use crate::Error;
+
+pub const DEFAULT_SERVER: &str = "localhost:51837";
+
+/// How long to wait for the server to accept a connection
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Profile to use when none is given
+    pub default: Option<String>,
+    #[serde(default)]
+    pub profile: BTreeMap<String, Profile>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    /// `host:port` of the poststation server
+    pub server: String,
+}
+
+impl Config {
+    /// `$CURACAO_CONFIG`, or `~/.config/curacao/config.toml`
+    pub fn path() -> Option<PathBuf> {
+        match env::var_os("CURACAO_CONFIG") {
+            Some(p) => Some(PathBuf::from(p)),
+            None => env::var_os("HOME")
+                .map(|h| PathBuf::from(h).join(".config/curacao/config.toml")),
+        }
+    }
+
+    /// Load the config file, or an empty config if there isn't one
+    pub fn load() -> Result<Self, Error> {
+        let Some(path) = Self::path() else {
+            return Ok(Self::default());
+        };
+        match fs::read_to_string(&path) {
+            Ok(s) => toml::from_str(&s)
+                .map_err(|e| Error::Config(format!("'{}': {e}", path.display()))),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(Error::Config(format!("'{}': {e}", path.display()))),
+        }
+    }
+
+    fn server_for(&self, profile: &str) -> Result<String, Error> {
+        self.profile
+            .get(profile)
+            .map(|p| p.server.clone())
+            .ok_or_else(|| {
+                let known = self.profile.keys().cloned().collect::<Vec<_>>();
+                Error::Config(format!(
+                    "no profile '{profile}' in the config file, known profiles: [{}]",
+                    known.join(", ")
+                ))
+            })
+    }
+}
+
+/// Connection settings given on the command line
+#[derive(Debug, Clone, Default)]
+pub struct ConnectOpts {
+    pub server: Option<String>,
+    pub profile: Option<String>,
+}
+
+impl ConnectOpts {
+    /// Pick the server address, see the [module docs](self) for the order
+    pub fn server(&self) -> Result<String, Error> {
+        if let Some(server) = self.server.as_ref() {
+            return Ok(server.clone());
+        }
+        if let Some(profile) = self.profile.as_deref() {
+            return Config::load()?.server_for(profile);
+        }
+        if let Ok(server) = env::var("CURACAO_SERVER") {
+            return Ok(server);
+        }
+        let config = Config::load()?;
+        if let Ok(profile) = env::var("CURACAO_PROFILE") {
+            return config.server_for(&profile);
+        }
+        match config.default.as_deref() {
+            Some(profile) => config.server_for(profile),
+            None => Ok(DEFAULT_SERVER.to_string()),
+        }
+    }
+
+    /// Connect to the poststation server
+    pub async fn connect(&self) -> Result<SquadClient, Error> {
+        connect(&self.server()?).await
+    }
+}
+
+/// Connect to the poststation server at `addr`
+///
+/// Checks that the server is reachable first, so a wrong address or a
+/// server that isn't running is reported as an error.
+pub async fn connect(addr: &str) -> Result<SquadClient, Error> {
+    let unreachable = |why: String| {
+        Error::Transport(format!("can't reach poststation server at '{addr}': {why}"))
+    };
+    match timeout(CONNECT_TIMEOUT, TcpStream::connect(addr)).await {
+        Ok(Ok(_probe)) => {}
+        Ok(Err(e)) => return Err(unreachable(e.to_string())),
+        Err(_) => return Err(unreachable(format!("no answer within {CONNECT_TIMEOUT:?}"))),
+    }
+    Ok(poststation_sdk::connect(addr).await)
+}
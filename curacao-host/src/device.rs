@@ -0,0 +1,34 @@
+use std::time::{Duration, Instant};
+
+use poststation_sdk::SquadClient;
+use tokio::time::sleep;
+
+use crate::Error;
+
+/// Is the device with this serial currently connected to poststation?
+pub async fn device_connected(client: &SquadClient, serial: u64) -> Result<bool, Error> {
+    Ok(client
+        .get_devices()
+        .await
+        .map_err(Error::Transport)?
+        .iter()
+        .any(|d| d.is_connected && d.serial == serial))
+}
+
+/// Wait up to `tout` for the device with this serial to connect
+///
+/// Returns `false` if it didn't show up in time.
+pub async fn wait_for_device(
+    client: &SquadClient,
+    serial: u64,
+    tout: Duration,
+) -> Result<bool, Error> {
+    let start = Instant::now();
+    while start.elapsed() < tout {
+        if device_connected(client, serial).await? {
+            return Ok(true);
+        }
+        sleep(Duration::from_millis(10)).await;
+    }
+    Ok(false)
+}
@@ -0,0 +1,78 @@
+use std::{fmt, time::Duration};
+
+use bootloader_icd::{EraseError, FailedSanityCheck, ReadError, WriteError};
+
+use crate::codes;
+
+/// Errors returned by the [`BootloaderClient`](crate::BootloaderClient)
+///
+/// Device errors keep the typed error returned by the bootloader. Each
+/// variant has a stable `kind` and `code` for machine-readable output.
+#[derive(Debug)]
+pub enum Error {
+    /// The poststation server or proxy request failed
+    Transport(String),
+    /// The device did not answer within the timeout
+    Timeout { path: &'static str, after: Duration },
+    Read(ReadError),
+    Erase(EraseError),
+    Write(WriteError),
+    Boot(FailedSanityCheck),
+    /// The request doesn't fit the device's app partition
+    Layout(String),
+    /// The connection settings or config file are invalid
+    Config(String),
+}
+
+impl Error {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Error::Transport(_) => "transport",
+            Error::Timeout { .. } => "timeout",
+            Error::Read(_) => "read",
+            Error::Erase(_) => "erase",
+            Error::Write(_) => "write",
+            Error::Boot(_) => "boot",
+            Error::Layout(_) => "layout",
+            Error::Config(_) => "config",
+        }
+    }
+
+    pub fn code(&self) -> u16 {
+        match self {
+            Error::Transport(_) => codes::TRANSPORT,
+            Error::Timeout { .. } => codes::TIMEOUT,
+            Error::Layout(_) => codes::LAYOUT,
+            Error::Config(_) => codes::CONFIG,
+            Error::Read(e) => e.code(),
+            Error::Erase(e) => e.code(),
+            Error::Write(e) => e.code(),
+            Error::Boot(e) => e.code(),
+        }
+    }
+
+    /// Is this error worth retrying?
+    ///
+    /// Only failures of the proxy itself are transient, errors reported by
+    /// the device will come back the same way if we ask again.
+    pub fn is_transient(&self) -> bool {
+        matches!(self, Error::Transport(_) | Error::Timeout { .. })
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Transport(s) => write!(f, "transport error: {s}"),
+            Error::Timeout { path, after } => write!(f, "'{path}' timed out after {after:?}"),
+            Error::Read(e) => write!(f, "read error: {e}"),
+            Error::Erase(e) => write!(f, "erase error: {e}"),
+            Error::Write(e) => write!(f, "write error: {e}"),
+            Error::Boot(e) => write!(f, "boot error: {e}"),
+            Error::Layout(s) => write!(f, "layout error: {s}"),
+            Error::Config(s) => write!(f, "config error: {s}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
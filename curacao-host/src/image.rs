@@ -0,0 +1,267 @@
+use std::{
+    fmt::{self, Write},
+    ops::Range,
+};
+
+use bootloader_icd::AppPartitionInfo;
+use crc::{Crc, CRC_32_ISO_HDLC};
+use object::{
+    elf::PT_LOAD,
+    read::elf::{ElfFile32, ProgramHeader},
+    Endianness, Object, ObjectSection,
+};
+use sha2::{Digest, Sha256};
+
+use crate::codes;
+
+/// Errors building a flat image from an ELF file
+#[derive(Debug)]
+pub enum ImageError {
+    /// The file isn't a 32-bit ELF we can read
+    Parse(String),
+    /// The ELF has no loadable data
+    Empty,
+    /// The vector table isn't linked at the start of the app partition
+    NotAtAppStart { vector_table: u32, app_start: u32 },
+    /// The vector table doesn't look like one
+    BadVectorTable(&'static str),
+    /// The image extends past the end of the app partition
+    TooLarge { len: u32, max: u32 },
+    /// The device reported a partition layout we can't flash
+    Partition(String),
+    /// A segment is loaded somewhere other than flash
+    OutsideFlash { addr: u32, len: u32 },
+}
+
+impl fmt::Display for ImageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImageError::Parse(s) => write!(f, "failed to parse ELF: {s}"),
+            ImageError::Empty => write!(f, "ELF has no loadable segments"),
+            ImageError::NotAtAppStart {
+                vector_table,
+                app_start,
+            } => write!(
+                f,
+                "vector table linked at {vector_table:08X}, but the app starts at {app_start:08X}"
+            ),
+            ImageError::BadVectorTable(s) => write!(f, "bad vector table: {s}"),
+            ImageError::TooLarge { len, max } => {
+                write!(f, "image is {len} bytes, but the app partition is {max} bytes")
+            }
+            ImageError::Partition(s) => write!(f, "unsupported partition layout: {s}"),
+            ImageError::OutsideFlash { addr, len } => {
+                write!(f, "segment of {len} bytes at {addr:08X} is not in flash")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ImageError {}
+
+impl ImageError {
+    pub fn kind(&self) -> &'static str {
+        "image"
+    }
+
+    pub fn code(&self) -> u16 {
+        codes::IMAGE
+    }
+}
+
+/// Where the nRF52840's flash is mapped
+pub const FLASH: Range<u32> = 0x0000_0000..0x0010_0000;
+
+/// The contents of flash described by an ELF file
+#[derive(Debug, Clone)]
+pub struct FirmwareImage {
+    /// Flash address of the first byte of `data`
+    pub base: u32,
+    /// Flat image, with any gaps between segments filled with 0xFF
+    pub data: Vec<u8>,
+    /// Contents of the `.note.gnu.build-id` section, if the image was linked with one
+    pub build_id: Option<Vec<u8>>,
+    /// Address of the `.vector_table` section, if there is one
+    pub vector_table: Option<u32>,
+}
+
+impl FirmwareImage {
+    /// Build the flat image from the PT_LOAD segments of an ELF file
+    ///
+    /// Segments are placed at their physical (load) address, which for
+    /// initialized data is the copy in flash rather than its RAM address.
+    /// Every segment must lie within [`FLASH`].
+    pub fn from_elf(elf: &[u8]) -> Result<Self, ImageError> {
+        let file = ElfFile32::<Endianness>::parse(elf).map_err(|e| ImageError::Parse(e.to_string()))?;
+        let endian = file.endian();
+
+        let mut segments = vec![];
+        for ph in file.raw_segments() {
+            if ph.p_type(endian) != PT_LOAD || ph.p_filesz(endian) == 0 {
+                continue;
+            }
+            let data = ph
+                .data(endian, elf)
+                .map_err(|_| ImageError::Parse("segment data out of bounds".into()))?;
+            let addr = ph.p_paddr(endian);
+            let len = data.len() as u32;
+            match addr.checked_add(len) {
+                Some(end) if FLASH.contains(&addr) && end <= FLASH.end => {}
+                _ => return Err(ImageError::OutsideFlash { addr, len }),
+            }
+            segments.push((addr, data));
+        }
+        segments.sort_by_key(|(addr, _)| *addr);
+
+        let Some(base) = segments.first().map(|(addr, _)| *addr) else {
+            return Err(ImageError::Empty);
+        };
+        let end = segments
+            .iter()
+            .map(|(addr, data)| *addr + data.len() as u32)
+            .max()
+            .unwrap_or(base);
+
+        let mut out = vec![0xFFu8; (end - base) as usize];
+        for (addr, data) in segments {
+            let offset = (addr - base) as usize;
+            out[offset..][..data.len()].copy_from_slice(data);
+        }
+
+        Ok(Self {
+            base,
+            data: out,
+            build_id: gnu_build_id(&file),
+            vector_table: file
+                .section_by_name(".vector_table")
+                .map(|s| s.address() as u32),
+        })
+    }
+
+    /// Check that the image can be booted from the app partition described by `info`
+    ///
+    /// The vector table must sit at the very start of the partition, since
+    /// that is where the bootloader jumps to, and the image must fit in it.
+    pub fn check_layout(&self, info: &AppPartitionInfo) -> Result<(), ImageError> {
+        let vector_table = self.vector_table.unwrap_or(self.base);
+        if vector_table != info.start || self.base != info.start {
+            return Err(ImageError::NotAtAppStart {
+                vector_table,
+                app_start: info.start,
+            });
+        }
+
+        let word = |i: usize| {
+            self.data
+                .get(i * 4..(i + 1) * 4)
+                .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+        };
+        let (Some(sp), Some(reset)) = (word(0), word(1)) else {
+            return Err(ImageError::BadVectorTable("too short"));
+        };
+        // The Cortex-M SRAM region, the end of RAM itself is chip specific
+        if sp & 0b11 != 0 || !(0x2000_0000..=0x4000_0000).contains(&sp) {
+            return Err(ImageError::BadVectorTable("initial stack pointer not in RAM"));
+        }
+        let end = self.base + self.data.len() as u32;
+        if reset & 1 == 0 || !(self.base..end).contains(&(reset & !1)) {
+            return Err(ImageError::BadVectorTable("reset vector not in image"));
+        }
+
+        if self.data.len() as u64 > info.len as u64 {
+            return Err(ImageError::TooLarge {
+                len: self.data.len() as u32,
+                max: info.len,
+            });
+        }
+        Ok(())
+    }
+
+    /// The image padded with 0xFF to whole erase sectors, to be written at `info.start`
+    ///
+    /// Fails if the image doesn't boot from, or fit in, the partition.
+    pub fn flash_data(&self, info: &AppPartitionInfo) -> Result<Vec<u8>, ImageError> {
+        if info.erase_sz == 0 || info.write_sz == 0 || info.erase_sz % info.write_sz != 0 {
+            return Err(ImageError::Partition(format!(
+                "erase size {}B is not a multiple of write size {}B",
+                info.erase_sz, info.write_sz
+            )));
+        }
+        if info.start % info.erase_sz != 0 {
+            return Err(ImageError::Partition(format!(
+                "start {:08X} is not aligned to {}B sectors",
+                info.start, info.erase_sz
+            )));
+        }
+        self.check_layout(info)?;
+
+        let len = (self.data.len() as u64).next_multiple_of(info.erase_sz as u64);
+        if len > info.len as u64 {
+            return Err(ImageError::TooLarge {
+                len: len as u32,
+                max: info.len,
+            });
+        }
+        let mut out = self.data.clone();
+        out.resize(len as usize, 0xFF);
+        Ok(out)
+    }
+
+    /// Short identifier of the flat image contents, see [`image_id`]
+    pub fn image_id(&self) -> String {
+        image_id(&self.data)
+    }
+
+    pub fn build_id_hex(&self) -> Option<String> {
+        self.build_id.as_deref().map(hex)
+    }
+}
+
+/// Short identifier of flat image contents: the first 8 bytes of its SHA-256
+///
+/// Hashing a readback of the same range on the device gives the same ID if
+/// the image matches what is flashed.
+pub fn image_id(data: &[u8]) -> String {
+    hex(&Sha256::digest(data)[..8])
+}
+
+/// CRC-32 of flash contents, matching [`BootloaderClient::crc32`](crate::BootloaderClient::crc32)
+pub fn crc32(data: &[u8]) -> u32 {
+    const CRC32: Crc<u32> = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+    CRC32.checksum(data)
+}
+
+fn gnu_build_id(file: &ElfFile32<'_, Endianness>) -> Option<Vec<u8>> {
+    let data = file.section_by_name(".note.gnu.build-id")?.data().ok()?;
+    // namesz, descsz, type, then the 4-byte aligned name and the descriptor
+    let word = |i: usize| -> Option<usize> {
+        let b = data.get(i * 4..(i + 1) * 4)?;
+        let b = b.try_into().ok()?;
+        Some(match file.endian() {
+            Endianness::Little => u32::from_le_bytes(b),
+            Endianness::Big => u32::from_be_bytes(b),
+        } as usize)
+    };
+    let namesz = word(0)?;
+    let descsz = word(1)?;
+    let desc_start = 12 + namesz.next_multiple_of(4);
+    data.get(desc_start..desc_start + descsz).map(<[u8]>::to_vec)
+}
+
+pub(crate) fn hex(data: &[u8]) -> String {
+    let mut out = String::with_capacity(data.len() * 2);
+    for b in data {
+        write!(&mut out, "{b:02x}").ok();
+    }
+    out
+}
+
+pub(crate) fn unhex(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 2 != 0 {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
@@ -0,0 +1,17 @@
+//! Host-side helpers for talking to the curacao bootloader through poststation
+
+mod boot;
+pub mod bundle;
+mod client;
+pub mod codes;
+pub mod config;
+mod device;
+mod error;
+mod image;
+
+pub use boot::{reset_causes, BootState};
+pub use client::{BootloaderClient, RetryPolicy, Timeouts};
+pub use config::ConnectOpts;
+pub use device::{device_connected, wait_for_device};
+pub use error::Error;
+pub use image::{crc32, image_id, FirmwareImage, ImageError, FLASH};
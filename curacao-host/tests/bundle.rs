@@ -0,0 +1,205 @@
+//! Writing, reading, signing and checking firmware bundles
+//!
+//! Run on the host with `cargo test`.
+
+use bootloader_icd::{BootloaderInfo, Version};
+use std::io;
+
+use curacao_host::{
+    bundle::{self, Bundle, BundleError, IcdKey, Manifest, FORMAT},
+    codes, FirmwareImage,
+};
+
+fn image() -> FirmwareImage {
+    FirmwareImage {
+        base: 0x0001_0000,
+        data: (0..=255u8).cycle().take(3000).collect(),
+        build_id: Some(vec![0xAB, 0xCD]),
+        vector_table: None,
+    }
+}
+
+fn manifest() -> Manifest {
+    Manifest {
+        format: 0,
+        board: "nrf52840-dk".into(),
+        min_bootloader: Version {
+            major: 1,
+            minor: 2,
+            patch: 0,
+        },
+        image_version: "1.2.3".into(),
+        base: 0,
+        len: 0,
+        build_id: None,
+        icd_keys: vec![IcdKey {
+            path: "bootloader/boot".into(),
+            request: "0011223344556677".into(),
+            response: "8899aabbccddeeff".into(),
+        }],
+    }
+}
+
+fn info(board: &str, minor: u16) -> BootloaderInfo {
+    BootloaderInfo {
+        version: Version {
+            major: 1,
+            minor,
+            patch: 0,
+        },
+        board: board.into(),
+    }
+}
+
+fn write(bundle: &Bundle) -> Vec<u8> {
+    let mut out = vec![];
+    bundle.write(&mut out).unwrap();
+    out
+}
+
+/// Replace the only copy of `from` in `data` with `to`, of the same length
+fn patch(data: &mut [u8], from: &[u8], to: &[u8]) {
+    let at = data
+        .windows(from.len())
+        .position(|w| w == from)
+        .expect("pattern not found");
+    data[at..][..to.len()].copy_from_slice(to);
+}
+
+#[test]
+fn bundles_round_trip() {
+    let bundle = Bundle::new(manifest(), &image()).unwrap();
+    assert_eq!(bundle.manifest.format, FORMAT);
+    assert_eq!(bundle.manifest.base, 0x0001_0000);
+    assert_eq!(bundle.manifest.len, 3000);
+    assert_eq!(bundle.manifest.build_id.as_deref(), Some("abcd"));
+
+    let read = Bundle::read(&write(&bundle)[..]).unwrap();
+    assert_eq!(read.manifest.board, "nrf52840-dk");
+    assert_eq!(read.manifest.min_bootloader, manifest().min_bootloader);
+    assert_eq!(read.manifest.icd_keys, manifest().icd_keys);
+    assert_eq!(read.signature, None);
+
+    let flashed = read.firmware_image();
+    assert_eq!(flashed.base, image().base);
+    assert_eq!(flashed.data, image().data);
+    assert_eq!(flashed.build_id, image().build_id);
+}
+
+#[test]
+fn signatures() {
+    let key = bundle::signing_key(&"07".repeat(32)).unwrap();
+    let other = bundle::signing_key(&"08".repeat(32)).unwrap();
+
+    let mut bundle = Bundle::new(manifest(), &image()).unwrap();
+    assert!(matches!(
+        bundle.verify(&key.verifying_key()),
+        Err(BundleError::Signature(_))
+    ));
+    bundle.sign(&key);
+
+    let mut data = write(&bundle);
+    let read = Bundle::read(&data[..]).unwrap();
+    assert!(read.verify(&key.verifying_key()).is_ok());
+    assert!(matches!(
+        read.verify(&other.verifying_key()),
+        Err(BundleError::Signature(_))
+    ));
+
+    // The signature covers the manifest as stored
+    patch(&mut data, b"\"1.2.3\"", b"\"1.2.4\"");
+    let read = Bundle::read(&data[..]).unwrap();
+    assert_eq!(read.manifest.image_version, "1.2.4");
+    assert!(matches!(
+        read.verify(&key.verifying_key()),
+        Err(BundleError::Signature(_))
+    ));
+}
+
+#[test]
+fn damaged_bundles() {
+    let bundle = Bundle::new(manifest(), &image()).unwrap();
+    let mut data = write(&bundle);
+    patch(&mut data, &image().data[..300], &[0u8; 300]);
+    assert!(matches!(Bundle::read(&data[..]), Err(BundleError::Digest)));
+
+    assert!(matches!(
+        Bundle::read(&b"not a tar file"[..]),
+        Err(BundleError::Io(_) | BundleError::Format(_))
+    ));
+}
+
+#[test]
+fn manifests_are_checked_against_the_device() {
+    let manifest = manifest();
+    let served = manifest.icd_keys.clone();
+    assert!(manifest
+        .check(Some(&info("nrf52840-dk", 2)), &served)
+        .is_ok());
+    assert!(manifest
+        .check(Some(&info("nrf52840-dk", 9)), &served)
+        .is_ok());
+
+    let mut changed = served.clone();
+    changed[0].response = "0000000000000000".into();
+    let refused = [
+        (None, &served[..]),
+        (Some(info("other-board", 2)), &served[..]),
+        (Some(info("nrf52840-dk", 1)), &served[..]),
+        (Some(info("nrf52840-dk", 2)), &changed[..]),
+        (Some(info("nrf52840-dk", 2)), &[][..]),
+    ];
+    for (info, served) in refused {
+        assert!(matches!(
+            manifest.check(info.as_ref(), served),
+            Err(BundleError::Incompatible(_))
+        ));
+    }
+}
+
+#[test]
+fn only_flashing_endpoints_are_pinned() {
+    let mut paths: Vec<_> = IcdKey::flashing().into_iter().map(|k| k.path).collect();
+    paths.sort();
+    assert_eq!(
+        paths,
+        [
+            "bootloader/boot",
+            "bootloader/flash/erase",
+            "bootloader/flash/info",
+            "bootloader/flash/write",
+            "bootloader/info",
+        ]
+    );
+}
+
+#[test]
+fn versions_and_keys_parse() {
+    let v = |major, minor, patch| Version {
+        major,
+        minor,
+        patch,
+    };
+    assert_eq!(bundle::parse_version("1.2.3"), Some(v(1, 2, 3)));
+    assert_eq!(bundle::parse_version(" 4 "), Some(v(4, 0, 0)));
+    assert_eq!(bundle::parse_version("1.x"), None);
+    assert_eq!(bundle::parse_version(""), None);
+
+    let key = bundle::signing_key(&"07".repeat(32))
+        .unwrap()
+        .verifying_key();
+    let hex: String = key.to_bytes().iter().map(|b| format!("{b:02x}")).collect();
+    assert_eq!(bundle::verifying_key(&format!("{hex}\n")).unwrap(), key);
+    assert!(matches!(
+        bundle::signing_key("abcd"),
+        Err(BundleError::Format(_))
+    ));
+}
+
+#[test]
+fn io_errors_keep_their_kind() {
+    let e = BundleError::from(io::Error::from(io::ErrorKind::NotFound));
+    assert_eq!(e.code(), codes::IO);
+    assert_eq!(codes::io_kind(&e), Some(io::ErrorKind::NotFound));
+    assert_eq!(codes::io_kind(&BundleError::Digest), None);
+}
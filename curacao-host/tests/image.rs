@@ -0,0 +1,168 @@
+//! Building flat images from ELF files, and checking them against a partition
+//!
+//! Run on the host with `cargo test`.
+
+use bootloader_icd::AppPartitionInfo;
+use curacao_host::{crc32, FirmwareImage, ImageError};
+
+const PT_LOAD: u32 = 1;
+const APP_START: u32 = 0x0001_0000;
+
+/// A little-endian ARM ELF holding only the given `(paddr, data)` segments
+fn elf(segments: &[(u32, &[u8])]) -> Vec<u8> {
+    let mut out = vec![];
+    out.extend_from_slice(b"\x7fELF\x01\x01\x01\0\0\0\0\0\0\0\0\0");
+    let phnum = segments.len() as u16;
+    let data_start = 52 + 32 * segments.len() as u32;
+    // type, machine, version, entry, phoff, shoff, flags
+    for h in [2u16, 40] {
+        out.extend_from_slice(&h.to_le_bytes());
+    }
+    for w in [1u32, APP_START, 52, 0, 0] {
+        out.extend_from_slice(&w.to_le_bytes());
+    }
+    // ehsize, phentsize, phnum, shentsize, shnum, shstrndx
+    for h in [52u16, 32, phnum, 40, 0, 0] {
+        out.extend_from_slice(&h.to_le_bytes());
+    }
+
+    let mut offset = data_start;
+    for (paddr, data) in segments {
+        let len = data.len() as u32;
+        for w in [PT_LOAD, offset, *paddr, *paddr, len, len, 5, 4] {
+            out.extend_from_slice(&w.to_le_bytes());
+        }
+        offset += len;
+    }
+    for (_, data) in segments {
+        out.extend_from_slice(data);
+    }
+    out
+}
+
+/// A vector table with a stack in RAM and a reset vector just after it
+fn vectors() -> Vec<u8> {
+    let mut out = vec![];
+    out.extend_from_slice(&0x2004_0000u32.to_le_bytes());
+    out.extend_from_slice(&((APP_START + 8) | 1).to_le_bytes());
+    // b .
+    out.extend_from_slice(&[0xFE, 0xE7, 0x00, 0xBF]);
+    out
+}
+
+fn partition(len: u32) -> AppPartitionInfo {
+    AppPartitionInfo {
+        start: APP_START,
+        len,
+        transfer_chunk: 1024,
+        write_sz: 4,
+        erase_sz: 4096,
+        align: 4,
+    }
+}
+
+#[test]
+fn segments_are_placed_at_their_load_address() {
+    let code = vectors();
+    let image = FirmwareImage::from_elf(&elf(&[
+        // Out of order, with a gap
+        (APP_START + 16, b"data"),
+        (APP_START, &code),
+    ]))
+    .unwrap();
+    assert_eq!(image.base, APP_START);
+    assert_eq!(&image.data[..12], &code[..]);
+    assert_eq!(&image.data[12..16], &[0xFF; 4]);
+    assert_eq!(&image.data[16..], b"data");
+    assert_eq!(image.build_id, None);
+    assert_eq!(image.vector_table, None);
+}
+
+#[test]
+fn segments_must_be_in_flash() {
+    let cases = [
+        // Data linked to RAM with no copy in flash
+        (0x2000_0000, 16),
+        // Running past the end of flash
+        (0x000F_FFF0, 32),
+        // Wrapping around the address space
+        (0xFFFF_FFF0, 32),
+    ];
+    for (addr, len) in cases {
+        let data = vec![0u8; len as usize];
+        let err =
+            FirmwareImage::from_elf(&elf(&[(APP_START, &vectors()), (addr, &data)])).unwrap_err();
+        assert!(
+            matches!(err, ImageError::OutsideFlash { addr: a, len: l } if (a, l) == (addr, len)),
+            "{err}"
+        );
+    }
+}
+
+#[test]
+fn bad_elves() {
+    assert!(matches!(
+        FirmwareImage::from_elf(&elf(&[])),
+        Err(ImageError::Empty)
+    ));
+    assert!(matches!(
+        FirmwareImage::from_elf(b"not an ELF"),
+        Err(ImageError::Parse(_))
+    ));
+}
+
+#[test]
+fn flash_data_is_padded_to_whole_sectors() {
+    let image = FirmwareImage::from_elf(&elf(&[(APP_START, &vectors())])).unwrap();
+    let data = image.flash_data(&partition(8192)).unwrap();
+    assert_eq!(data.len(), 4096);
+    assert_eq!(&data[..12], &image.data[..]);
+    assert!(data[12..].iter().all(|b| *b == 0xFF));
+
+    let mut info = partition(8192);
+    info.erase_sz = 6;
+    assert!(matches!(
+        image.flash_data(&info),
+        Err(ImageError::Partition(_))
+    ));
+}
+
+#[test]
+fn layout_is_checked() {
+    let image = FirmwareImage::from_elf(&elf(&[(APP_START, &vectors())])).unwrap();
+    assert!(image.check_layout(&partition(4096)).is_ok());
+    assert!(matches!(
+        image.flash_data(&partition(2048)),
+        Err(ImageError::TooLarge {
+            len: 4096,
+            max: 2048
+        })
+    ));
+
+    let elsewhere = FirmwareImage::from_elf(&elf(&[(APP_START + 4096, &vectors())])).unwrap();
+    assert!(matches!(
+        elsewhere.check_layout(&partition(8192)),
+        Err(ImageError::NotAtAppStart { .. })
+    ));
+
+    let mut bad_sp = vectors();
+    bad_sp[..4].copy_from_slice(&0x0000_1000u32.to_le_bytes());
+    let mut bad_reset = vectors();
+    bad_reset[4..8].copy_from_slice(&((APP_START + 0x1000) | 1).to_le_bytes());
+    for table in [&bad_sp[..], &bad_reset[..], &vectors()[..4]] {
+        let image = FirmwareImage::from_elf(&elf(&[(APP_START, table)])).unwrap();
+        assert!(matches!(
+            image.check_layout(&partition(4096)),
+            Err(ImageError::BadVectorTable(_))
+        ));
+    }
+}
+
+#[test]
+fn checksums() {
+    // The CRC-32 check value
+    assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    let image = FirmwareImage::from_elf(&elf(&[(APP_START, &vectors())])).unwrap();
+    assert_eq!(image.image_id().len(), 16);
+    assert_eq!(image.image_id(), curacao_host::image_id(&image.data));
+}
@@ -0,0 +1,94 @@
+//! Only rewrite the sectors that changed
+//!
+//! Each erase sector of the new image is compared against the device, using
+//! the bootloader's CRC endpoint if it has one, or a readback if not.
+
+use std::time::Instant;
+
+use bootloader_icd::{AppPartitionInfo, HashFlashEndpoint};
+use curacao_host::{crc32, BootloaderClient};
+use postcard_rpc::Endpoint;
+use serde::Serialize;
+
+use crate::{error::Error, output::Output};
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Compare {
+    Crc32,
+    Readback,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DeltaReport {
+    pub compare: Compare,
+    pub sectors: u32,
+    pub changed: u32,
+    /// Bytes of the image that didn't need to be erased and written
+    pub bytes_saved: u32,
+    pub millis: u64,
+}
+
+/// Write `data` at `info.start`, skipping sectors that already match
+///
+/// `data` must already be padded to whole erase sectors.
+pub async fn flash(
+    bl: &BootloaderClient,
+    out: Output,
+    info: &AppPartitionInfo,
+    data: &[u8],
+) -> Result<DeltaReport, Error> {
+    let started = Instant::now();
+    let compare = if bl.has_endpoint(HashFlashEndpoint::PATH).await? {
+        Compare::Crc32
+    } else {
+        Compare::Readback
+    };
+
+    let sector = info.erase_sz as usize;
+    let sectors = (data.len() / sector) as u32;
+    let mut changed = vec![];
+    let mut progress = out.progress("compare", data.len() as u32);
+    for (i, ch) in data.chunks(sector).enumerate() {
+        let addr = info.start + (i * sector) as u32;
+        let same = match compare {
+            Compare::Crc32 => bl.crc32(addr, ch.len() as u32).await? == crc32(ch),
+            Compare::Readback => bl.read(addr, ch.len() as u32).await? == ch,
+        };
+        if !same {
+            changed.push((addr, ch));
+        }
+        progress.update(((i + 1) * sector) as u32);
+    }
+
+    let total = (changed.len() * sector) as u32;
+    let mut done = 0;
+    let mut progress = out.progress("write", total);
+    for (addr, ch) in changed.iter() {
+        bl.erase(*addr, ch.len() as u32).await?;
+        bl.write(*addr, ch).await?;
+        done += ch.len() as u32;
+        progress.update(done);
+    }
+
+    Ok(DeltaReport {
+        compare,
+        sectors,
+        changed: changed.len() as u32,
+        bytes_saved: data.len() as u32 - total,
+        millis: started.elapsed().as_millis() as u64,
+    })
+}
+
+impl DeltaReport {
+    pub fn print_summary(&self) {
+        println!(
+            "Delta ({:?}): rewrote {}/{} sectors, saved {:0.02}KiB in {:0.02}s",
+            self.compare,
+            self.changed,
+            self.sectors,
+            self.bytes_saved as f32 / 1024.0,
+            self.millis as f32 / 1000.0,
+        );
+    }
+}
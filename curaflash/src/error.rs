@@ -0,0 +1,137 @@
+use std::fmt;
+
+use curacao_host::{bundle::BundleError, codes, ImageError};
+
+/// Errors reported by curaflash
+///
+/// Each variant has a stable `kind` and `code` for machine-readable output,
+/// see [`curacao_host::codes`]. Wrapped errors keep their own.
+#[derive(Debug)]
+pub enum Error {
+    /// The bootloader request failed
+    Host(curacao_host::Error),
+    /// The expected device did not show up
+    DeviceNotFound(String),
+    /// The firmware image could not be built
+    Image(ImageError),
+    /// Local file I/O failed
+    Io(std::io::Error),
+    /// The command or its arguments were invalid
+    Usage(String),
+    /// An argument or input file could not be parsed
+    Parse {
+        what: String,
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+    /// The app didn't come up after flashing
+    HealthCheck(String),
+    /// Some devices in a fleet failed to flash
+    Fleet(String),
+    /// The firmware bundle is invalid or doesn't fit the device
+    Bundle(BundleError),
+    /// Loading the image or bundle at `path` failed
+    File { path: String, source: Box<Error> },
+}
+
+impl Error {
+    pub fn parse(
+        what: impl Into<String>,
+        source: impl std::error::Error + Send + Sync + 'static,
+    ) -> Self {
+        Error::Parse {
+            what: what.into(),
+            source: Box::new(source),
+        }
+    }
+
+    /// Name the file that `self` came from
+    pub fn in_file(self, path: &str) -> Self {
+        Error::File {
+            path: path.to_string(),
+            source: Box::new(self),
+        }
+    }
+
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Error::Host(e) => e.kind(),
+            Error::DeviceNotFound(_) => "device_not_found",
+            Error::Image(e) => e.kind(),
+            Error::Io(_) => "io",
+            Error::Usage(_) | Error::Parse { .. } => "usage",
+            Error::HealthCheck(_) => "health_check",
+            Error::Fleet(_) => "fleet",
+            Error::Bundle(e) => e.kind(),
+            Error::File { source, .. } => source.kind(),
+        }
+    }
+
+    pub fn code(&self) -> u16 {
+        match self {
+            Error::Host(e) => e.code(),
+            Error::Image(e) => e.code(),
+            Error::Bundle(e) => e.code(),
+            Error::File { source, .. } => source.code(),
+            Error::Io(_) => codes::IO,
+            Error::Usage(_) | Error::Parse { .. } => codes::USAGE,
+            Error::DeviceNotFound(_) => codes::DEVICE_NOT_FOUND,
+            Error::HealthCheck(_) => codes::HEALTH_CHECK,
+            Error::Fleet(_) => codes::FLEET,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Host(e) => write!(f, "{e}"),
+            Error::DeviceNotFound(s) => write!(f, "device not found: {s}"),
+            Error::Image(e) => write!(f, "image error: {e}"),
+            Error::Io(e) => write!(f, "I/O error: {e}"),
+            Error::Usage(s) => write!(f, "{s}"),
+            Error::Parse { what, source } => write!(f, "invalid {what}: {source}"),
+            Error::HealthCheck(s) => write!(f, "health check failed: {s}"),
+            Error::Fleet(s) => write!(f, "fleet flash failed: {s}"),
+            Error::Bundle(e) => write!(f, "bundle error: {e}"),
+            Error::File { path, source } => write!(f, "'{path}': {source}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Host(e) => Some(e),
+            Error::Image(e) => Some(e),
+            Error::Io(e) => Some(e),
+            Error::Parse { source, .. } => Some(source.as_ref()),
+            Error::Bundle(e) => Some(e),
+            Error::File { source, .. } => Some(source.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<curacao_host::Error> for Error {
+    fn from(e: curacao_host::Error) -> Self {
+        Error::Host(e)
+    }
+}
+
+impl From<ImageError> for Error {
+    fn from(e: ImageError) -> Self {
+        Error::Image(e)
+    }
+}
+
+impl From<BundleError> for Error {
+    fn from(e: BundleError) -> Self {
+        Error::Bundle(e)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
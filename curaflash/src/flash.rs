@@ -0,0 +1,158 @@
+//! Flash one device: reset it into the bootloader, write the image, boot it
+
+use bootloader_icd::GetAppFlashInfoEndpoint;
+use curacao_host::{bundle::Manifest, BootloaderClient, FirmwareImage};
+use postcard_rpc::Endpoint;
+use poststation_sdk::SquadClient;
+
+use crate::{
+    delta,
+    error::Error,
+    health::{self, HealthCheck},
+    output::{Output, Record},
+    pack,
+    reset::{self, ResetPlan},
+    resume,
+};
+
+pub const DEFAULT_RESET_PATH: &str = "curacao/postboot/reset";
+
+/// The serials a device has when running the app and the bootloader
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Target {
+    pub app_serial: u64,
+    pub boot_serial: u64,
+}
+
+impl Target {
+    /// The bootloader reports the inverse of the app's serial
+    pub fn from_app(app_serial: u64) -> Self {
+        Self {
+            app_serial,
+            boot_serial: !app_serial,
+        }
+    }
+}
+
+/// How to reset the app, write the image, and check the result
+#[derive(Debug, Clone)]
+pub struct FlashOpts {
+    pub reset: ResetPlan,
+    pub delta: bool,
+    /// Continue an interrupted transfer instead of starting over
+    pub resume: bool,
+    /// Manifest of the bundle the image came from, checked before writing
+    pub manifest: Option<Manifest>,
+    /// `None` to skip the check after booting
+    pub health: Option<HealthCheck>,
+}
+
+/// A connected device, and what it is currently running
+#[derive(Debug, Clone, Copy)]
+pub struct Found {
+    pub target: Target,
+    pub in_bootloader: bool,
+    /// The app serves the reset endpoint, so it can be flashed without a button press
+    pub resettable: bool,
+}
+
+/// Every connected device, using its schema to tell whether it is currently
+/// running the bootloader
+pub async fn find_devices(client: &SquadClient, reset_path: &str) -> Result<Vec<Found>, Error> {
+    let devices = client
+        .get_devices()
+        .await
+        .map_err(curacao_host::Error::Transport)?;
+    let mut out = vec![];
+    for dev in devices.iter().filter(|d| d.is_connected) {
+        let schema = client
+            .get_device_schemas(dev.serial)
+            .await
+            .map_err(curacao_host::Error::Transport)?;
+        let has = |path: &str| {
+            schema
+                .as_ref()
+                .is_some_and(|s| s.endpoints.iter().any(|e| e.path == path))
+        };
+        let in_bootloader = has(GetAppFlashInfoEndpoint::PATH);
+        out.push(Found {
+            target: if in_bootloader {
+                Target::from_app(!dev.serial)
+            } else {
+                Target::from_app(dev.serial)
+            },
+            in_bootloader,
+            resettable: has(reset_path),
+        });
+    }
+    Ok(out)
+}
+
+/// Flash a device, returning the client for anything that follows
+pub async fn flash(
+    client: SquadClient,
+    out: Output,
+    target: Target,
+    image: &FirmwareImage,
+    opts: &FlashOpts,
+) -> Result<SquadClient, Error> {
+    let Target {
+        app_serial,
+        boot_serial,
+    } = target;
+
+    out.note("Looking for bootloader device...");
+    let how = reset::reset(&client, out, target, &opts.reset).await?;
+    out.emit(Record::Device {
+        role: "bootloader",
+        serial: boot_serial,
+        action: match how {
+            None => "Found",
+            Some(_) => "Reset into",
+        },
+    });
+    let bl = BootloaderClient::new(client, boot_serial);
+
+    let info = bl.partinfo().await?;
+    out.emit(Record::PartitionInfo(&info));
+    if let Some(manifest) = opts.manifest.as_ref() {
+        pack::check_device(&bl, manifest).await?;
+    }
+    let bin_image = image.flash_data(&info)?;
+
+    if opts.delta {
+        let report = delta::flash(&bl, out, &info, &bin_image).await?;
+        out.emit(Record::Delta(&report));
+    } else if opts.resume {
+        let report = resume::flash(&bl, out, &info, &bin_image).await?;
+        out.emit(Record::Resume(&report));
+    } else {
+        erase_and_write(&bl, out, info.start, &bin_image).await?;
+    }
+    bl.boot().await?;
+    out.ok("boot", "Boot command sent".to_string());
+
+    if let Some(health) = opts.health.as_ref() {
+        health::check(&bl, out, app_serial, health).await?;
+    }
+
+    Ok(bl.into_client())
+}
+
+/// Erase a sector aligned range and write `data` to it
+pub async fn erase_and_write(
+    bl: &BootloaderClient,
+    out: Output,
+    start: u32,
+    data: &[u8],
+) -> Result<(), Error> {
+    let total = data.len() as u32;
+    let mut progress = out.progress("erase", total);
+    bl.erase(start, total).await?;
+    progress.update(total);
+
+    let mut progress = out.progress("write", total);
+    bl.write_with_progress(start, data, |done, _| progress.update(done as u32))
+        .await?;
+    Ok(())
+}
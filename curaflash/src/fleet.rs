@@ -0,0 +1,288 @@
+//! Flash many devices at once, as described by a manifest
+//!
+//! ```toml
+//! parallel = 4
+//! # Bundles must be signed with this key, unless --trust-key overrides it.
+//! # Entries with a plain ELF aren't checked
+//! trust_key = "keys/release.pub"
+//!
+//! [[device]]
+//! serial = "E58A068274AB2C5F"
+//! elf = "release/scd41-node.cura"
+//!
+//! [[device]]
+//! # Every other node, whether running the app or sitting in the bootloader
+//! serial = "*"
+//! elf = "target/thumbv7em-none-eabihf/release/poststation-node"
+//! reset = ["endpoint", "button"]
+//! reset_path = "app/reset"
+//! reset_msg = { delay_ms = 10 }
+//! ```
+//!
+//! Serials are the app serials, patterns may use `*` and `?`. Each connected
+//! device is flashed by the first entry that matches it.
+
+use std::{
+    collections::HashMap,
+    fs,
+    sync::Arc,
+    time::Instant,
+};
+
+use curacao_host::{bundle::Manifest as BundleManifest, config::connect, FirmwareImage};
+use serde::{Deserialize, Serialize};
+use tokio::{sync::Semaphore, task::JoinSet};
+
+use crate::{
+    error::Error,
+    flash::{self, FlashOpts, Target},
+    health::HealthCheck,
+    output::{ErrorRecord, Output, Record},
+    pack,
+    parse_serial,
+    reset::{ResetPlan, Strategy},
+};
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Manifest {
+    /// How many devices to flash at once
+    pub parallel: Option<usize>,
+    /// File holding the hex ed25519 public key bundles must be signed with,
+    /// used for the `.cura` entries only
+    pub trust_key: Option<String>,
+    #[serde(rename = "device")]
+    pub devices: Vec<Entry>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Entry {
+    /// App serial in hex, or a pattern using `*` and `?`
+    pub serial: String,
+    /// ELF or `.cura` bundle to flash
+    pub elf: String,
+    /// Reset strategies, overriding the command line
+    pub reset: Option<Vec<Strategy>>,
+    pub reset_path: Option<String>,
+    pub reset_msg: Option<toml::Value>,
+    #[serde(default)]
+    pub delta: bool,
+    #[serde(default)]
+    pub resume: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DeviceResult {
+    pub serial: String,
+    pub elf: String,
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<ErrorRecord>,
+    pub millis: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FleetReport {
+    pub passed: usize,
+    pub failed: usize,
+    pub devices: Vec<DeviceResult>,
+}
+
+impl FleetReport {
+    pub fn print_summary(&self) {
+        println!("{:<16}  {:<4}  {:>7}  ELF / error", "SERIAL", "OK", "TIME");
+        for d in self.devices.iter() {
+            println!(
+                "{:<16}  {:<4}  {:>6.1}s  {}",
+                d.serial,
+                if d.ok { "pass" } else { "FAIL" },
+                d.millis as f32 / 1000.0,
+                d.elf
+            );
+            if let Some(e) = d.error.as_ref() {
+                println!("{:<16}  {:<4}  {:>7}  {}", "", "", "", e.message);
+            }
+        }
+        println!("{} passed, {} failed", self.passed, self.failed);
+    }
+}
+
+/// Does a hex serial match a pattern with `*` and `?` wildcards?
+fn matches(pattern: &[u8], serial: &[u8]) -> bool {
+    match (pattern.split_first(), serial.split_first()) {
+        (None, None) => true,
+        (Some((&b'*', rest)), _) => {
+            matches(rest, serial) || (!serial.is_empty() && matches(pattern, &serial[1..]))
+        }
+        (Some((p, prest)), Some((s, srest))) => {
+            (*p == b'?' || p.eq_ignore_ascii_case(s)) && matches(prest, srest)
+        }
+        _ => false,
+    }
+}
+
+/// Pick the entry and target for each device
+///
+/// Exact serials are flashed whether or not they are connected, so a missing
+/// device shows up as a failure. Patterns match connected devices by their
+/// app serial, whether they are running the app or sitting in the bootloader.
+fn plan(manifest: &Manifest, connected: &[Target]) -> Result<Vec<(Target, usize)>, Error> {
+    let mut out: Vec<(Target, usize)> = vec![];
+    let mut push = |target: Target, idx: usize| {
+        if !out.iter().any(|(t, _)| *t == target) {
+            out.push((target, idx));
+        }
+    };
+    for (idx, entry) in manifest.devices.iter().enumerate() {
+        if entry.serial.contains(['*', '?']) {
+            let pat = entry.serial.as_bytes();
+            for target in connected {
+                if matches(pat, format!("{:016X}", target.app_serial).as_bytes()) {
+                    push(*target, idx);
+                }
+            }
+        } else {
+            push(Target::from_app(parse_serial(&entry.serial)?), idx);
+        }
+    }
+    Ok(out)
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn run(
+    manifest_path: &str,
+    parallel: Option<usize>,
+    trust_key: Option<String>,
+    insecure: bool,
+    report_path: &str,
+    health: Option<HealthCheck>,
+    reset: ResetPlan,
+    server: &str,
+    out: Output,
+) -> Result<(), Error> {
+    let manifest = fs::read_to_string(manifest_path)?;
+    let manifest: Manifest = toml::from_str(&manifest)
+        .map_err(|e| Error::parse(format!("manifest '{manifest_path}'"), e))?;
+    let parallel = parallel.or(manifest.parallel).unwrap_or(4).max(1);
+    let trust_key = trust_key.or(manifest.trust_key.clone());
+
+    // Load each image once, up front, so a bad ELF or bundle fails before flashing anything
+    let mut images: HashMap<&str, Arc<(FirmwareImage, Option<BundleManifest>)>> = HashMap::new();
+    for entry in manifest.devices.iter() {
+        if !images.contains_key(entry.elf.as_str()) {
+            let trust_key = trust_key.as_deref().filter(|_| pack::is_bundle(&entry.elf));
+            let loaded = pack::load(&entry.elf, trust_key, insecure, out)?;
+            images.insert(&entry.elf, Arc::new(loaded));
+        }
+    }
+
+    let client = connect(server).await?;
+    let connected = flash::find_devices(&client, &reset.path)
+        .await?
+        .iter()
+        .map(|f| f.target)
+        .collect::<Vec<_>>();
+    let targets = plan(&manifest, &connected)?;
+    out.note(&format!(
+        "Flashing {} devices, {parallel} at a time...",
+        targets.len()
+    ));
+
+    let limit = Arc::new(Semaphore::new(parallel));
+    let mut tasks = JoinSet::new();
+    // Which device each task flashes, to report a task that panicked
+    let mut names = HashMap::new();
+    for (target, idx) in targets {
+        let entry = &manifest.devices[idx];
+        let image = images[entry.elf.as_str()].clone();
+        let mut reset = reset.clone();
+        if let Some(msg) = entry.reset_msg.clone() {
+            reset.msg = serde_json::to_value(msg)
+                .map_err(|e| Error::parse("reset_msg", e))?;
+        }
+        if let Some(path) = entry.reset_path.clone() {
+            reset.path = path;
+            reset.strategies = ResetPlan::default_strategies(true);
+        }
+        if let Some(strategies) = entry.reset.clone() {
+            reset.strategies = strategies;
+        }
+        let opts = FlashOpts {
+            reset,
+            delta: entry.delta,
+            resume: entry.resume,
+            manifest: image.1.clone(),
+            health: health.clone(),
+        };
+        let serial = format!("{:016X}", target.app_serial);
+        let elf = entry.elf.clone();
+        let server = server.to_string();
+        let limit = limit.clone();
+        let name = (serial.clone(), elf.clone());
+        let task = tasks.spawn(async move {
+            let _permit = limit.acquire_owned().await;
+            let started = Instant::now();
+            let quiet = Output {
+                json: out.json,
+                quiet: true,
+            };
+            let res = match connect(&server).await {
+                Ok(client) => flash::flash(client, quiet, target, &image.0, &opts)
+                    .await
+                    .map(drop),
+                Err(e) => Err(e.into()),
+            };
+            DeviceResult {
+                serial,
+                elf,
+                ok: res.is_ok(),
+                error: res.as_ref().err().map(ErrorRecord::from),
+                millis: started.elapsed().as_millis() as u64,
+            }
+        });
+        names.insert(task.id(), name);
+    }
+
+    // Every device gets a result, so the report is always written
+    let mut devices = vec![];
+    while let Some(res) = tasks.join_next_with_id().await {
+        let res = match res {
+            Ok((_, res)) => res,
+            Err(e) => {
+                let (serial, elf) = names.remove(&e.id()).unwrap_or_default();
+                let err = Error::Fleet(format!("flash task failed: {e}"));
+                DeviceResult {
+                    serial,
+                    elf,
+                    ok: false,
+                    error: Some(ErrorRecord::from(&err)),
+                    millis: 0,
+                }
+            }
+        };
+        out.emit(Record::FleetDevice(&res));
+        devices.push(res);
+    }
+    devices.sort_by(|a, b| a.serial.cmp(&b.serial));
+
+    let failed = devices.iter().filter(|d| !d.ok).count();
+    let report = FleetReport {
+        passed: devices.len() - failed,
+        failed,
+        devices,
+    };
+    out.emit(Record::FleetReport(&report));
+    let json = serde_json::to_string_pretty(&report).map_err(std::io::Error::from)?;
+    fs::write(report_path, json)?;
+    out.note(&format!("Wrote report to '{report_path}'"));
+
+    if report.failed != 0 {
+        return Err(Error::Fleet(format!(
+            "{} of {} devices failed",
+            report.failed,
+            report.devices.len()
+        )));
+    }
+    Ok(())
+}
@@ -0,0 +1,91 @@
+//! Check that the app actually came up after booting it
+//!
+//! The bootloader accepts a boot as soon as the image passes its sanity
+//! check, so an image that panics at startup would otherwise look like a
+//! successful flash. Apps that panic are reset back into the bootloader,
+//! which keeps the panic message for us to report.
+
+use std::time::Duration;
+
+use bootloader_icd::GetUniqueIdEndpoint;
+use curacao_host::{wait_for_device, BootState, BootloaderClient};
+use serde_json::Value;
+use tokio::time::timeout;
+
+use crate::{
+    error::Error,
+    output::{Output, Record},
+};
+
+/// How to decide that the app is healthy
+#[derive(Debug, Clone)]
+pub struct HealthCheck {
+    /// How long the app has to connect and answer
+    pub timeout: Duration,
+    /// Endpoint to call instead of the unique ID endpoint, with its request
+    pub endpoint: Option<(String, Value)>,
+}
+
+/// Wait for the app to connect and answer, after `bl` has been told to boot
+///
+/// If it doesn't, the bootloader's boot message is reported and an error is
+/// returned.
+pub async fn check(
+    bl: &BootloaderClient,
+    out: Output,
+    app_serial: u64,
+    cfg: &HealthCheck,
+) -> Result<(), Error> {
+    out.note("Waiting for app to start...");
+    let res = match timeout(cfg.timeout, call_app(bl, app_serial, cfg)).await {
+        Ok(res) => res,
+        Err(_) => Err(format!("app did not answer within {:?}", cfg.timeout)),
+    };
+    let failure = match res {
+        Ok(detail) => {
+            out.emit(Record::Device {
+                role: "app",
+                serial: app_serial,
+                action: "Healthy",
+            });
+            out.ok("health", detail);
+            return Ok(());
+        }
+        Err(e) => e,
+    };
+
+    // The bootloader comes back if the app panicked or faulted
+    let boot_serial = bl.serial();
+    if wait_for_device(bl.client(), boot_serial, Duration::from_secs(3)).await? {
+        out.emit(Record::Device {
+            role: "bootloader",
+            serial: boot_serial,
+            action: "App failed, found",
+        });
+        let state: BootState = bl.boot_msg().await?.into();
+        out.emit(Record::BootMessage(state));
+    }
+    Err(Error::HealthCheck(failure))
+}
+
+async fn call_app(bl: &BootloaderClient, app_serial: u64, cfg: &HealthCheck) -> Result<String, String> {
+    let client = bl.client();
+    // The deadline is applied by our caller
+    if !wait_for_device(client, app_serial, Duration::MAX).await.map_err(|e| e.to_string())? {
+        return Err("app did not connect".into());
+    }
+    match &cfg.endpoint {
+        None => {
+            let id = client
+                .proxy_endpoint::<GetUniqueIdEndpoint>(app_serial, 0, &())
+                .await?;
+            Ok(format!("App answered with unique ID {id:016X}"))
+        }
+        Some((path, msg)) => {
+            let resp = client
+                .proxy_endpoint_json(app_serial, path, 0, msg.clone())
+                .await?;
+            Ok(format!("App answered '{path}' with {resp}"))
+        }
+    }
+}
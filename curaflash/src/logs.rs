@@ -0,0 +1,48 @@
+//! Stream a device's log messages after flashing, like `probe-rs run` does
+
+use std::time::Duration;
+
+use poststation_sdk::SquadClient;
+use tokio::time::interval;
+
+use crate::{
+    error::Error,
+    output::{Output, Record},
+};
+
+/// How many of the most recent logs to fetch on each poll
+const POLL_COUNT: u32 = 64;
+
+/// Print log messages from `serial` as they arrive, forever
+///
+/// Logs already stored when this starts are skipped, so start it before
+/// flashing to catch everything the new image logs at startup.
+/// Nodes behind a bridge send their logs through it, so this works for
+/// them as well as for devices on USB.
+pub async fn follow(client: SquadClient, out: Output, serial: u64) -> Result<(), Error> {
+    let mut ticker = interval(Duration::from_millis(100));
+    let mut last = None;
+    let mut first = true;
+    loop {
+        ticker.tick().await;
+        let mut logs = client
+            .get_device_logs(serial, POLL_COUNT)
+            .await
+            .map_err(curacao_host::Error::Transport)?
+            .unwrap_or_default();
+        // uuidv7s sort by time
+        logs.sort_by_key(|l| l.uuidv7);
+        if !first {
+            for log in logs.iter().filter(|l| last.map_or(true, |last| l.uuidv7 > last)) {
+                out.emit(Record::Log {
+                    serial,
+                    msg: &log.msg,
+                });
+            }
+        }
+        if let Some(newest) = logs.last() {
+            last = Some(newest.uuidv7);
+        }
+        first = false;
+    }
+}
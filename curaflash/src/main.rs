@@ -1,29 +1,137 @@
-use std::{
-    env::temp_dir,
-    fmt::Write,
-    fs::{self, File},
-    io::{Read, Write as _},
-    num::ParseIntError,
-    process::Command,
-    str::from_utf8,
-    sync::atomic::{AtomicU32, Ordering},
-    time::{Duration, Instant},
-};
-
-use bootloader_icd::{
-    scratch::BootMessage, AppPartitionInfo, BootloadEndpoint, DataChunk, EraseFlashEndpoint,
-    FlashEraseCommand, FlashReadCommand, FlashWriteCommand, GetAppFlashInfoEndpoint,
-    GetBootMessageEndpoint, ReadFlashEndpoint, WriteFlashEndpoint,
-};
-use clap::Parser;
-use postcard_rpc::Endpoint;
-use poststation_sdk::{connect, SquadClient};
-use rand::{thread_rng, Rng, RngCore};
-use serde::{de::DeserializeOwned, Serialize};
+use std::{num::ParseIntError, time::Duration};
+
+use clap::{Parser, Subcommand};
+use curacao_host::{config::connect, ConnectOpts};
+use error::Error;
+use flash::{FlashOpts, Target, DEFAULT_RESET_PATH};
+use health::HealthCheck;
+use output::{Output, Record};
+use pack::PackOpts;
+use reset::{ResetPlan, Strategy};
+use poststation_sdk::SquadClient;
 use serde_json::Value;
-use tokio::time::sleep;
+
+mod delta;
+mod error;
+mod flash;
+mod fleet;
+mod health;
+mod logs;
+mod output;
+mod pack;
+mod reset;
+mod resume;
 
 #[derive(Parser, Debug)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+struct Cli {
+    #[command(subcommand)]
+    cmd: Option<Cmd>,
+
+    #[command(flatten)]
+    args: Args,
+
+    #[command(flatten)]
+    health: HealthArgs,
+
+    #[command(flatten)]
+    reset: ResetArgs,
+
+    #[command(flatten)]
+    conn: ConnArgs,
+
+    /// Emit one JSON record per line instead of human-readable text
+    #[arg(long, global = true)]
+    json: bool,
+}
+
+#[derive(clap::Args, Debug)]
+struct ConnArgs {
+    /// Poststation server as `host:port`, overriding any profile
+    #[arg(long, global = true)]
+    server: Option<String>,
+
+    /// Profile from the config file naming the poststation server
+    #[arg(long, global = true)]
+    profile: Option<String>,
+}
+
+impl From<ConnArgs> for ConnectOpts {
+    fn from(args: ConnArgs) -> Self {
+        ConnectOpts {
+            server: args.server,
+            profile: args.profile,
+        }
+    }
+}
+
+#[derive(Subcommand, Debug)]
+enum Cmd {
+    /// Flash one device, the same as giving no subcommand
+    Flash {
+        #[command(flatten)]
+        args: Args,
+
+        #[command(flatten)]
+        health: HealthArgs,
+
+        #[command(flatten)]
+        reset: ResetArgs,
+    },
+    /// Package an ELF into a `.cura` bundle
+    Pack {
+        elf: String,
+
+        /// Where to write the bundle
+        #[arg(short, long)]
+        output: String,
+
+        /// Board the image is built for, as reported by the bootloader
+        #[arg(long, default_value = "nrf52840")]
+        board: String,
+
+        /// Oldest bootloader version the image can be flashed with
+        #[arg(long, default_value = "0.1.0")]
+        min_bootloader: String,
+
+        /// Version of the app, recorded in the manifest
+        #[arg(long)]
+        image_version: String,
+
+        /// File holding a hex ed25519 key to sign the bundle with
+        #[arg(long)]
+        sign_key: Option<String>,
+    },
+    /// Flash every device listed in a manifest
+    Fleet {
+        manifest: String,
+
+        /// How many devices to flash at once, overriding the manifest
+        #[arg(short = 'j', long)]
+        parallel: Option<usize>,
+
+        /// Where to write the JSON report
+        #[arg(long, default_value = "fleet-report.json")]
+        report: String,
+
+        /// File holding a hex ed25519 public key that bundles must be signed
+        /// with, overriding the manifest
+        #[arg(long)]
+        trust_key: Option<String>,
+
+        /// Flash signed bundles without a --trust-key to check them against
+        #[arg(long, conflicts_with = "trust_key")]
+        insecure: bool,
+
+        #[command(flatten)]
+        health: HealthArgs,
+
+        #[command(flatten)]
+        reset: ResetArgs,
+    },
+}
+
+#[derive(clap::Args, Debug)]
 struct Args {
     /// Bootloader serial
     #[arg(short, long)]
@@ -33,222 +141,293 @@ struct Args {
     #[arg(short, long)]
     app_serial: Option<String>,
 
+    /// Endpoint for the `endpoint` reset strategy
     #[arg(short, long)]
     reset_path: Option<String>,
 
-    #[arg(short, long)]
+    /// JSON request for --reset-path
+    #[arg(short = 'm', long, requires = "reset_path")]
     reset_msg_json: Option<String>,
 
-    elf_path: String,
-}
+    /// Only erase and write the sectors that differ from what is on the device
+    #[arg(long)]
+    delta: bool,
 
-struct Bootloader {
-    serial: u64,
-    client: SquadClient,
-    ctr: AtomicU32,
+    /// Continue an interrupted transfer of the same image, instead of erasing
+    /// and writing everything again
+    #[arg(long, conflicts_with = "delta")]
+    resume: bool,
+
+    /// Stay attached after booting and print the device's logs until Ctrl-C
+    #[arg(long)]
+    attach: bool,
+
+    /// File holding a hex ed25519 public key that bundles must be signed with
+    #[arg(long)]
+    trust_key: Option<String>,
+
+    /// Flash a signed bundle without a --trust-key to check it against
+    #[arg(long, conflicts_with = "trust_key")]
+    insecure: bool,
+
+    /// ELF or `.cura` bundle to flash. When used as a cargo runner, cargo passes this for us
+    #[arg(required = true)]
+    elf_path: Option<String>,
+
+    /// Arguments from `cargo run -- ...`, which firmware can't take
+    #[arg(hide = true, trailing_var_arg = true, allow_hyphen_values = true)]
+    program_args: Vec<String>,
 }
 
-impl Bootloader {
-    pub fn new(client: SquadClient, serial: u64) -> Self {
-        Self {
-            serial,
-            client,
-            ctr: AtomicU32::new(0),
-        }
-    }
+#[derive(clap::Args, Debug)]
+struct HealthArgs {
+    /// Seconds to wait for the app to connect and answer after booting
+    #[arg(long, default_value_t = 10)]
+    health_timeout: u64,
 
-    #[inline(always)]
-    fn ctr(&self) -> u32 {
-        self.ctr.fetch_add(1, Ordering::Relaxed)
-    }
+    /// Endpoint the app must answer after booting, instead of the unique ID endpoint
+    #[arg(long)]
+    health_path: Option<String>,
 
-    async fn proxy_ep<E>(&self, req: &E::Request) -> Result<E::Response, String>
-    where
-        E: Endpoint,
-        E::Request: Serialize,
-        E::Response: DeserializeOwned,
-    {
-        self.client
-            .proxy_endpoint::<E>(self.serial, self.ctr(), req)
-            .await
-    }
+    /// JSON request for --health-path
+    #[arg(long, requires = "health_path")]
+    health_msg_json: Option<String>,
 
-    async fn partinfo(&self) -> Result<AppPartitionInfo, String> {
-        self.proxy_ep::<GetAppFlashInfoEndpoint>(&()).await
-    }
+    /// Don't wait for the app to come up after booting
+    #[arg(long)]
+    no_health_check: bool,
+}
 
-    async fn read_chunk(&self, start: u32, len: u32) -> Result<DataChunk, String> {
-        self.proxy_ep::<ReadFlashEndpoint>(&FlashReadCommand { start, len })
-            .await?
-            .map_err(|e| format!("Error: '{e:?}'"))
-    }
+#[derive(clap::Args, Debug)]
+struct ResetArgs {
+    /// Ways to get into the bootloader, tried in order: typed, endpoint, button.
+    /// Defaults to typed, then endpoint if --reset-path is given
+    #[arg(long = "reset", value_delimiter = ',')]
+    strategies: Vec<Strategy>,
 
-    async fn erase(&self, start: u32, len: u32) -> Result<(), String> {
-        self.proxy_ep::<EraseFlashEndpoint>(&FlashEraseCommand {
-            start,
-            len,
-            force: false,
-        })
-        .await?
-        .map_err(|e| format!("Error: '{e:?}'"))
-    }
+    /// Seconds to wait for the bootloader after each automatic reset
+    #[arg(long, default_value_t = 3)]
+    boot_timeout: u64,
 
-    async fn write(&self, start: u32, data: &[u8]) -> Result<(), String> {
-        for (i, ch) in data.chunks(512).enumerate() {
-            let addr = start + (i as u32 * 512);
-            let res = self
-                .proxy_ep::<WriteFlashEndpoint>(&FlashWriteCommand {
-                    start: addr,
-                    data: ch.to_vec(),
-                    force: false,
-                })
-                .await?;
-            if let Err(e) = res {
-                return Err(format!("Error: '{e:?}'"));
-            }
+    /// Seconds to wait for the button to be pressed
+    #[arg(long, default_value_t = 30)]
+    button_timeout: u64,
+}
+
+impl ResetArgs {
+    fn plan(self, path: Option<String>, msg: Value) -> ResetPlan {
+        let strategies = match self.strategies.is_empty() {
+            true => ResetPlan::default_strategies(path.is_some()),
+            false => self.strategies,
+        };
+        ResetPlan {
+            strategies,
+            path: path.unwrap_or_else(|| DEFAULT_RESET_PATH.to_string()),
+            msg,
+            timeout: Duration::from_secs(self.boot_timeout),
+            button_timeout: Duration::from_secs(self.button_timeout),
         }
-        Ok(())
     }
+}
 
-    async fn boot_msg(&self) -> Result<Option<BootMessage>, String> {
-        self.proxy_ep::<GetBootMessageEndpoint>(&()).await
+impl HealthArgs {
+    fn check(self) -> Result<Option<HealthCheck>, Error> {
+        if self.no_health_check {
+            return Ok(None);
+        }
+        let endpoint = match self.health_path {
+            Some(path) => {
+                let msg = match self.health_msg_json.as_deref() {
+                    Some(s) => serde_json::from_str(s)
+                        .map_err(|e| Error::parse("--health-msg-json", e))?,
+                    None => Value::Null,
+                };
+                Some((path, msg))
+            }
+            None => None,
+        };
+        Ok(Some(HealthCheck {
+            timeout: Duration::from_secs(self.health_timeout),
+            endpoint,
+        }))
     }
+}
 
-    async fn boot(&self) -> Result<(), String> {
-        self.proxy_ep::<BootloadEndpoint>(&())
-            .await?
-            .map_err(|_| "Err: Failed Sanity Check".into())
-    }
+#[tokio::main]
+async fn main() {
+    let cli = Cli::parse();
+    let out = Output {
+        json: cli.json,
+        quiet: false,
+    };
 
-    async fn dumpfmt(&self, start: u32, len: u32, chunk: u32) -> Result<String, String> {
-        let mut out = String::new();
-        let mut addr = start;
-        let end = start + len;
-        while addr < end {
-            let take = (end - addr).min(chunk);
-            let Ok(data) = self.read_chunk(addr, take).await else {
-                return Err("Error getting data".into());
-            };
-            for (i, ch) in data.data.chunks(16).enumerate() {
-                let base = addr + (i as u32 * 16);
-                write!(&mut out, "0x{:08X} |", base).ok();
-                for b in ch {
-                    write!(&mut out, " {b:02X}").ok();
-                }
-                for _ in 0..(16 - ch.len()) {
-                    write!(&mut out, "   ").ok();
-                }
-                write!(&mut out, " | ").ok();
-                for b in ch {
-                    if b.is_ascii() && !b.is_ascii_control() {
-                        write!(&mut out, "{}", *b as char).ok();
-                    } else {
-                        write!(&mut out, "·").ok();
-                    }
-                }
-                out += "\n";
+    let conn = ConnectOpts::from(cli.conn);
+    let res = match cli.cmd {
+        Some(Cmd::Fleet {
+            manifest,
+            parallel,
+            report,
+            trust_key,
+            insecure,
+            health,
+            reset,
+        }) => match (health.check(), conn.server()) {
+            (Ok(health), Ok(server)) => {
+                let reset = reset.plan(None, Value::Null);
+                fleet::run(
+                    &manifest, parallel, trust_key, insecure, &report, health, reset, &server,
+                    out,
+                )
+                .await
             }
-
-            addr += take;
+            (Err(e), _) => Err(e),
+            (_, Err(e)) => Err(e.into()),
+        },
+        Some(Cmd::Flash {
+            args,
+            health,
+            reset,
+        }) => run(args, health, reset, &conn, out).await,
+        Some(Cmd::Pack {
+            elf,
+            output,
+            board,
+            min_bootloader,
+            image_version,
+            sign_key,
+        }) => {
+            let opts = PackOpts {
+                board,
+                min_bootloader,
+                image_version,
+                sign_key,
+            };
+            pack::pack(&elf, &output, opts, out)
         }
-        Ok(out)
+        None => run(cli.args, cli.health, cli.reset, &conn, out).await,
+    };
+    if let Err(e) = res {
+        out.error(&e);
+        std::process::exit(1);
     }
 }
 
-async fn find_dev(client: &SquadClient, serial: u64) -> bool {
-    client
-        .get_devices()
-        .await
-        .unwrap()
-        .iter()
-        .any(|d| d.is_connected && d.serial == serial)
+fn parse_serial(s: &str) -> Result<u64, Error> {
+    u64::from_str_radix(s, 16).map_err(|e| Error::parse(format!("serial '{s}'"), e))
 }
 
-#[tokio::main]
-async fn main() -> Result<(), String> {
-    let args = Args::parse();
-
-    let app_serial: u64;
-    let boot_serial: u64;
-    let reset_path = args
-        .reset_path
-        .as_deref()
-        .unwrap_or("curacao/postboot/reset");
-    let reset_msg = args.reset_msg_json.as_deref().unwrap_or("{}");
-
-    match (args.app_serial, args.boot_serial) {
-        (None, None) => {
-            return Err("Must provide one of --boot-serial or --app-serial".into());
-        }
-        (Some(a), Some(b)) => {
-            app_serial = u64::from_str_radix(&a, 16).map_err(|e| format!("Error: {e:?}"))?;
-            boot_serial = u64::from_str_radix(&b, 16).map_err(|e| format!("Error: {e:?}"))?;
-        }
-        (Some(a), None) => {
-            app_serial = u64::from_str_radix(&a, 16).map_err(|e| format!("Error: {e:?}"))?;
-            boot_serial = !app_serial;
-        }
-        (None, Some(b)) => {
-            boot_serial = u64::from_str_radix(&b, 16).map_err(|e| format!("Error: {e:?}"))?;
-            app_serial = !boot_serial;
-        }
+async fn run(
+    args: Args,
+    health: HealthArgs,
+    reset: ResetArgs,
+    conn: &ConnectOpts,
+    out: Output,
+) -> Result<(), Error> {
+    let elf_path = args
+        .elf_path
+        .ok_or_else(|| Error::Usage("Must provide an ELF file or bundle".into()))?;
+    if !args.program_args.is_empty() {
+        return Err(Error::Usage(format!(
+            "firmware doesn't take arguments, got {:?}",
+            args.program_args
+        )));
     }
+    let reset_msg = match args.reset_msg_json.as_deref() {
+        Some(s) => serde_json::from_str(s)
+            .map_err(|e| Error::parse("--reset-msg-json", e))?,
+        None => Value::Null,
+    };
+    let reset = reset.plan(args.reset_path, reset_msg);
 
-    // create the binfile
-    let mut bin_path = temp_dir();
-    let rndm: u64 = thread_rng().gen();
-    bin_path.push(format!("{rndm:016X}.bin"));
-    Command::new("rust-objcopy")
-        .args(["-O", "binary", &args.elf_path, bin_path.to_str().unwrap()])
-        .output()
-        .map_err(|e| format!("{e:?}"))?;
-
-    // read it back
-    let mut file = File::open(bin_path).map_err(|e| format!("{e:?}"))?;
-    let mut bin_image = vec![];
-    file.read_to_end(&mut bin_image).map_err(|e| format!("{e:?}"))?;
-    // this is lazy
-    while bin_image.len() % 4096 != 0 {
-        bin_image.push(0xFF);
-    }
+    let (image, manifest) = pack::load(&elf_path, args.trust_key.as_deref(), args.insecure, out)?;
 
     // Connect to device
-    let client = connect("localhost:51837").await;
-
-    // Is the app serial there?
-    if find_dev(&client, app_serial).await {
-        println!("Found app, sending app reset message");
-        client
-            .proxy_endpoint_json(app_serial, reset_path, 0, Value::from(reset_msg))
-            .await?;
-    }
+    let server = conn.server()?;
+    let client = connect(&server).await?;
+
+    let target = match (args.app_serial, args.boot_serial) {
+        (None, None) => select_device(&client, out, &reset.path).await?,
+        (Some(a), Some(b)) => Target {
+            app_serial: parse_serial(&a)?,
+            boot_serial: parse_serial(&b)?,
+        },
+        (Some(a), None) => Target::from_app(parse_serial(&a)?),
+        (None, Some(b)) => Target::from_app(!parse_serial(&b)?),
+    };
 
-    let start = Instant::now();
-    let mut found = false;
-    println!("Looking for bootloader device...");
-    while start.elapsed() < Duration::from_secs(3) {
-        if find_dev(&client, boot_serial).await {
-            found = true;
-            break;
-        }
-        sleep(Duration::from_millis(10)).await;
-    }
-    if !found {
-        return Err("Failed to find bootloader device!".into());
-    }
-    let bl = Bootloader::new(client, boot_serial);
+    let opts = FlashOpts {
+        reset,
+        delta: args.delta,
+        resume: args.resume,
+        manifest,
+        health: health.check()?,
+    };
 
-    println!("Found device, writing {:0.02}KiB...", bin_image.len() as f32 / 1024.0);
-    bl.erase(64 * 1024, bin_image.len() as u32).await.unwrap();
-    bl.write(64 * 1024, &bin_image).await.unwrap();
-    println!("Written. Commanding boot...");
-    bl.boot().await?;
-    println!("Boot command sent");
+    // Start following before flashing, so logs from startup aren't missed
+    let follower = args.attach.then(|| {
+        let server = server.clone();
+        tokio::spawn(async move {
+            let client = connect(&server).await?;
+            logs::follow(client, out, target.app_serial).await
+        })
+    });
 
-    return Ok(());
+    let res = flash::flash(client, out, target, &image, &opts).await;
+    let Some(follower) = follower else {
+        return res.map(drop);
+    };
+    if let Err(e) = res {
+        follower.abort();
+        return Err(e);
+    }
 
+    out.note("Attached, press Ctrl-C to exit");
+    tokio::select! {
+        res = follower => res.map_err(std::io::Error::from)?,
+        _ = tokio::signal::ctrl_c() => Ok(()),
+    }
 }
 
+/// Pick the only connected device that can be flashed, when no serial was given
+async fn select_device(
+    client: &SquadClient,
+    out: Output,
+    reset_path: &str,
+) -> Result<Target, Error> {
+    let found = flash::find_devices(client, reset_path)
+        .await?
+        .into_iter()
+        .filter(|f| f.in_bootloader || f.resettable)
+        .collect::<Vec<_>>();
+    match found.as_slice() {
+        [one] => {
+            out.emit(Record::Device {
+                role: if one.in_bootloader { "bootloader" } else { "app" },
+                serial: if one.in_bootloader {
+                    one.target.boot_serial
+                } else {
+                    one.target.app_serial
+                },
+                action: "Auto-selected",
+            });
+            Ok(one.target)
+        }
+        [] => Err(Error::DeviceNotFound(
+            "no connected device is in the bootloader or serves the reset endpoint".into(),
+        )),
+        many => {
+            let serials = many
+                .iter()
+                .map(|f| format!("{:016X}", f.target.app_serial))
+                .collect::<Vec<_>>();
+            Err(Error::Usage(format!(
+                "{} devices could be flashed, pick one with --app-serial: {}",
+                many.len(),
+                serials.join(", ")
+            )))
+        }
+    }
+}
 
 pub trait FromStrRadix: Sized {
     fn from_str_radix_gen(src: &str, radix: u32) -> Result<Self, ParseIntError>;
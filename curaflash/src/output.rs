@@ -0,0 +1,288 @@
+//! Human-readable or JSON output
+//!
+//! Every step of a flash reports through [`Output`]. In JSON mode each
+//! [`Record`] is printed as a single line of JSON, so CI can consume the
+//! output without scraping text.
+
+use std::{
+    io::Write,
+    time::{Duration, Instant},
+};
+
+use bootloader_icd::AppPartitionInfo;
+use curacao_host::{bundle::Manifest, codes, BootState};
+use serde::Serialize;
+
+use crate::{
+    delta::DeltaReport,
+    error::Error,
+    fleet::{DeviceResult, FleetReport},
+    resume::ResumeReport,
+};
+
+/// How often to report progress while a step is running
+const PROGRESS_EVERY: Duration = Duration::from_millis(250);
+
+#[derive(Debug, Serialize)]
+pub struct ErrorRecord {
+    pub kind: &'static str,
+    pub code: u16,
+    pub message: String,
+    /// `io::ErrorKind` of the underlying I/O error, if there is one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub io_kind: Option<String>,
+}
+
+impl From<&Error> for ErrorRecord {
+    fn from(e: &Error) -> Self {
+        ErrorRecord {
+            kind: e.kind(),
+            code: e.code(),
+            message: e.to_string(),
+            io_kind: codes::io_kind(e).map(|k| format!("{k:?}")),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "record", rename_all = "snake_case")]
+pub enum Record<'a> {
+    Image {
+        path: &'a str,
+        len: usize,
+    },
+    Bundle {
+        path: &'a str,
+        manifest: &'a Manifest,
+        signed: bool,
+    },
+    Device {
+        role: &'static str,
+        #[serde(serialize_with = "ser_serial")]
+        serial: u64,
+        action: &'static str,
+    },
+    Reset {
+        strategy: &'static str,
+        ok: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        detail: Option<String>,
+    },
+    PartitionInfo(&'a AppPartitionInfo),
+    BootMessage(BootState),
+    Progress {
+        op: &'static str,
+        /// Bytes done so far
+        done: u32,
+        total: u32,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        bytes_per_sec: Option<u32>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        eta_secs: Option<u32>,
+    },
+    Delta(&'a DeltaReport),
+    Resume(&'a ResumeReport),
+    Log {
+        #[serde(serialize_with = "ser_serial")]
+        serial: u64,
+        msg: &'a str,
+    },
+    FleetDevice(&'a DeviceResult),
+    FleetReport(&'a FleetReport),
+    Ok {
+        op: &'static str,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        detail: Option<String>,
+    },
+    Error(ErrorRecord),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Output {
+    pub json: bool,
+    /// Drop all records, for devices flashed as part of a fleet
+    pub quiet: bool,
+}
+
+impl Output {
+    pub fn emit(&self, rec: Record<'_>) {
+        if self.quiet {
+            return;
+        }
+        if self.json {
+            match serde_json::to_string(&rec) {
+                Ok(s) => println!("{s}"),
+                Err(e) => eprintln!("Error serializing record: {e}"),
+            }
+        } else {
+            print_human(rec);
+        }
+    }
+
+    pub fn error(&self, e: &Error) {
+        self.emit(Record::Error(e.into()));
+    }
+
+    pub fn ok(&self, op: &'static str, detail: impl Into<Option<String>>) {
+        self.emit(Record::Ok {
+            op,
+            detail: detail.into(),
+        });
+    }
+
+    /// Start reporting progress of a step that handles `total` bytes
+    pub fn progress(&self, op: &'static str, total: u32) -> Progress {
+        Progress {
+            out: *self,
+            op,
+            total,
+            started: Instant::now(),
+            last: None,
+        }
+    }
+
+    /// Status text for humans, not emitted in JSON mode
+    pub fn note(&self, msg: &str) {
+        if !self.json && !self.quiet {
+            println!("{msg}");
+        }
+    }
+
+    /// Something risky the user asked for, printed to stderr even in JSON mode
+    pub fn warn(&self, msg: &str) {
+        if !self.quiet {
+            eprintln!("warning: {msg}");
+        }
+    }
+}
+
+/// Reports throughput and time remaining for one step, see [`Output::progress`]
+pub struct Progress {
+    out: Output,
+    op: &'static str,
+    total: u32,
+    started: Instant,
+    last: Option<Instant>,
+}
+
+impl Progress {
+    /// Report that `done` bytes are finished. Reports are rate limited, except
+    /// for the last one
+    pub fn update(&mut self, done: u32) {
+        let now = Instant::now();
+        let finished = done >= self.total;
+        if !finished && self.last.is_some_and(|l| now - l < PROGRESS_EVERY) {
+            return;
+        }
+        self.last = Some(now);
+
+        let secs = (now - self.started).as_secs_f64();
+        let rate = (secs > 0.0 && done > 0).then(|| done as f64 / secs);
+        self.out.emit(Record::Progress {
+            op: self.op,
+            done,
+            total: self.total,
+            bytes_per_sec: rate.map(|r| r as u32),
+            eta_secs: rate.map(|r| (self.total.saturating_sub(done) as f64 / r).ceil() as u32),
+        });
+    }
+}
+
+fn print_human(rec: Record<'_>) {
+    match rec {
+        Record::Image { path, len } => {
+            println!("Image '{path}': {:0.02}KiB", len as f32 / 1024.0);
+        }
+        Record::Bundle {
+            path,
+            manifest,
+            signed,
+        } => {
+            println!(
+                "Bundle '{path}': v{} for {}, bootloader >= {}, {:0.02}KiB{}",
+                manifest.image_version,
+                manifest.board,
+                manifest.min_bootloader,
+                manifest.len as f32 / 1024.0,
+                if signed { ", signed" } else { "" }
+            );
+        }
+        Record::Device { role, serial, action } => {
+            println!("{action} {role} device {serial:016X}");
+        }
+        Record::Reset {
+            strategy,
+            ok,
+            detail,
+        } => match (ok, detail) {
+            (true, _) => println!("Reset strategy '{strategy}' worked"),
+            (false, Some(d)) => println!("Reset strategy '{strategy}' failed: {d}"),
+            (false, None) => println!("Reset strategy '{strategy}' failed"),
+        },
+        Record::PartitionInfo(info) => {
+            println!(
+                "Partition {:08X}..{:08X}, erase {}B, write {}B, chunk {}B",
+                info.start,
+                info.start + info.len,
+                info.erase_sz,
+                info.write_sz,
+                info.transfer_chunk
+            );
+        }
+        Record::BootMessage(state) => match state {
+            BootState::AppPanicked { uptime, reason } => {
+                println!("App panicked after {uptime} ticks:");
+                println!("{}", reason.trim_end());
+            }
+            BootState::AppFaulted { uptime, regs } => {
+                println!("App HardFaulted after {uptime} ticks:");
+                println!("  PC={:08X} LR={:08X} CFSR={:08X}", regs.pc, regs.lr, regs.cfsr);
+            }
+            other => println!("Boot message: {other:?}"),
+        },
+        Record::Progress {
+            op,
+            done,
+            total,
+            bytes_per_sec,
+            eta_secs,
+        } => {
+            // Redraw the same line until the step is done
+            let mut line = format!(
+                "\r{op}: {:0.02}/{:0.02}KiB",
+                done as f32 / 1024.0,
+                total as f32 / 1024.0
+            );
+            if let Some(rate) = bytes_per_sec {
+                line += &format!(", {:0.02}KiB/s", rate as f32 / 1024.0);
+            }
+            match eta_secs {
+                Some(eta) if done < total => line += &format!(", {eta}s left   "),
+                _ => line += "          ",
+            }
+            let mut stdout = std::io::stdout().lock();
+            _ = write!(stdout, "{line}");
+            if done >= total {
+                _ = writeln!(stdout);
+            }
+            _ = stdout.flush();
+        }
+        Record::Delta(report) => report.print_summary(),
+        Record::Resume(report) => report.print_summary(),
+        Record::Log { msg, .. } => println!("{msg}"),
+        Record::FleetDevice(d) => match d.error.as_ref() {
+            None => println!("{}: pass", d.serial),
+            Some(e) => println!("{}: FAIL: {}", d.serial, e.message),
+        },
+        Record::FleetReport(report) => report.print_summary(),
+        Record::Ok { op, detail } => match detail {
+            Some(d) => println!("{d}"),
+            None => println!("{op}: ok"),
+        },
+        Record::Error(e) => eprintln!("Error: {}", e.message),
+    }
+}
+
+fn ser_serial<S: serde::Serializer>(serial: &u64, ser: S) -> Result<S::Ok, S::Error> {
+    ser.serialize_str(&format!("{serial:016X}"))
+}
@@ -0,0 +1,119 @@
+//! Creating and loading firmware bundles, see [`curacao_host::bundle`]
+
+use std::{fs, path::Path};
+
+use curacao_host::{
+    bundle::{self, Bundle, IcdKey, Manifest},
+    BootloaderClient, FirmwareImage,
+};
+
+use crate::{
+    error::Error,
+    output::{Output, Record},
+};
+
+/// What to record in a new bundle's manifest
+#[derive(Debug)]
+pub struct PackOpts {
+    pub board: String,
+    pub min_bootloader: String,
+    pub image_version: String,
+    /// File holding a hex ed25519 signing key
+    pub sign_key: Option<String>,
+}
+
+pub fn pack(elf_path: &str, out_path: &str, opts: PackOpts, out: Output) -> Result<(), Error> {
+    let elf = fs::read(elf_path)?;
+    let image = FirmwareImage::from_elf(&elf)?;
+    let min_bootloader = bundle::parse_version(&opts.min_bootloader).ok_or_else(|| {
+        Error::Usage(format!("invalid --min-bootloader '{}'", opts.min_bootloader))
+    })?;
+
+    let manifest = Manifest {
+        format: bundle::FORMAT,
+        board: opts.board,
+        min_bootloader,
+        image_version: opts.image_version,
+        // Filled in from the image
+        base: 0,
+        len: 0,
+        build_id: None,
+        icd_keys: IcdKey::flashing(),
+    };
+    let mut bundle = Bundle::new(manifest, &image)?;
+    if let Some(path) = opts.sign_key.as_deref() {
+        let key = bundle::signing_key(&fs::read_to_string(path)?)?;
+        bundle.sign(&key);
+    }
+
+    let file = fs::File::create(out_path)?;
+    bundle.write(file)?;
+    out.emit(Record::Bundle {
+        path: out_path,
+        manifest: &bundle.manifest,
+        signed: bundle.signature.is_some(),
+    });
+    Ok(())
+}
+
+/// Whether `path` names a bundle rather than an ELF
+pub fn is_bundle(path: &str) -> bool {
+    Path::new(path).extension().is_some_and(|e| e == "cura")
+}
+
+/// Load the image to flash from an ELF, or from a bundle if the path ends in `.cura`
+///
+/// For bundles, the manifest is returned so it can be checked against the
+/// device. If `trust_key` is given, bundles must be signed by it. A signed
+/// bundle is refused without one, unless `insecure` is set.
+pub fn load(
+    path: &str,
+    trust_key: Option<&str>,
+    insecure: bool,
+    out: Output,
+) -> Result<(FirmwareImage, Option<Manifest>), Error> {
+    if is_bundle(path) {
+        let bundle =
+            Bundle::read(fs::File::open(path)?).map_err(|e| Error::from(e).in_file(path))?;
+        match trust_key {
+            Some(key_path) => {
+                let key = bundle::verifying_key(&fs::read_to_string(key_path)?)?;
+                bundle.verify(&key).map_err(|e| Error::from(e).in_file(path))?;
+            }
+            None if bundle.signature.is_some() && !insecure => {
+                return Err(Error::Usage(format!(
+                    "'{path}' is signed, but no --trust-key was given to check it. \
+                     Pass --insecure to flash it unchecked"
+                )));
+            }
+            None if bundle.signature.is_some() => {
+                out.warn(&format!("'{path}' is signed, but its signature is not checked"));
+            }
+            None => {}
+        }
+        out.emit(Record::Bundle {
+            path,
+            manifest: &bundle.manifest,
+            signed: bundle.signature.is_some(),
+        });
+        Ok((bundle.firmware_image(), Some(bundle.manifest)))
+    } else {
+        if trust_key.is_some() {
+            return Err(Error::Usage("--trust-key needs a .cura bundle, not an ELF".into()));
+        }
+        let elf = fs::read(path)?;
+        let image = FirmwareImage::from_elf(&elf).map_err(|e| Error::from(e).in_file(path))?;
+        out.emit(Record::Image {
+            path,
+            len: image.data.len(),
+        });
+        Ok((image, None))
+    }
+}
+
+/// Refuse to flash a bundle meant for another board or bootloader
+pub async fn check_device(bl: &BootloaderClient, manifest: &Manifest) -> Result<(), Error> {
+    let info = bl.bootloader_info().await?;
+    let keys = bl.icd_keys().await?;
+    Ok(manifest.check(info.as_ref(), &keys)?)
+}
@@ -0,0 +1,159 @@
+//! Get a device from its app into the bootloader
+//!
+//! Strategies are tried in order until the bootloader serial shows up.
+
+use std::{str::FromStr, time::Duration};
+
+use curacao_host::{device_connected, wait_for_device};
+use postcard_rpc::endpoint;
+use poststation_sdk::SquadClient;
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::{
+    error::Error,
+    flash::Target,
+    output::{Output, Record},
+};
+
+// Served by every curacao app, see the `RebootToBootloader` in each ICD
+endpoint!(RebootToBootloader, (), (), "curacao/postboot/reset");
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Strategy {
+    /// Call the standard `RebootToBootloader` endpoint
+    Typed,
+    /// Call a custom endpoint with a JSON request
+    Endpoint,
+    /// Ask a human to press the button
+    Button,
+}
+
+impl Strategy {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Strategy::Typed => "typed",
+            Strategy::Endpoint => "endpoint",
+            Strategy::Button => "button",
+        }
+    }
+}
+
+impl FromStr for Strategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "typed" => Ok(Strategy::Typed),
+            "endpoint" => Ok(Strategy::Endpoint),
+            "button" => Ok(Strategy::Button),
+            other => Err(format!(
+                "unknown reset strategy '{other}', expected typed, endpoint or button"
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ResetPlan {
+    pub strategies: Vec<Strategy>,
+    /// Path and request for [`Strategy::Endpoint`]
+    pub path: String,
+    pub msg: Value,
+    /// How long to wait for the bootloader after an automatic reset
+    pub timeout: Duration,
+    /// How long to wait for someone to press the button
+    pub button_timeout: Duration,
+}
+
+impl ResetPlan {
+    /// The typed call, then the custom endpoint if one was given
+    pub fn default_strategies(custom_path: bool) -> Vec<Strategy> {
+        match custom_path {
+            true => vec![Strategy::Typed, Strategy::Endpoint],
+            false => vec![Strategy::Typed],
+        }
+    }
+}
+
+/// Get `target` into the bootloader, returning the strategy that worked, or
+/// `None` if it was already there
+pub async fn reset(
+    client: &SquadClient,
+    out: Output,
+    target: Target,
+    plan: &ResetPlan,
+) -> Result<Option<Strategy>, Error> {
+    let Target {
+        app_serial,
+        boot_serial,
+    } = target;
+
+    if device_connected(client, boot_serial).await? {
+        return Ok(None);
+    }
+
+    for strategy in plan.strategies.iter() {
+        let detail = match strategy {
+            Strategy::Typed | Strategy::Endpoint => {
+                if !device_connected(client, app_serial).await? {
+                    out.emit(Record::Reset {
+                        strategy: strategy.name(),
+                        ok: false,
+                        detail: Some(format!("app {app_serial:016X} is not connected")),
+                    });
+                    continue;
+                }
+                let res = match strategy {
+                    Strategy::Typed => client
+                        .proxy_endpoint::<RebootToBootloader>(app_serial, 0, &())
+                        .await
+                        .map(drop),
+                    _ => client
+                        .proxy_endpoint_json(app_serial, &plan.path, 0, plan.msg.clone())
+                        .await
+                        .map(drop),
+                };
+                // The app may reset before its reply gets out, so only the
+                // bootloader showing up counts
+                let found = wait_for_device(client, boot_serial, plan.timeout).await?;
+                match (found, res) {
+                    (true, _) => None,
+                    (false, Err(e)) => Some(e),
+                    (false, Ok(())) => {
+                        Some(format!("bootloader not found within {:?}", plan.timeout))
+                    }
+                }
+            }
+            Strategy::Button => {
+                eprintln!(
+                    "Press the button on device {app_serial:016X} to enter the bootloader..."
+                );
+                match wait_for_device(client, boot_serial, plan.button_timeout).await? {
+                    true => None,
+                    false => Some(format!("no button press within {:?}", plan.button_timeout)),
+                }
+            }
+        };
+        let ok = detail.is_none();
+        out.emit(Record::Reset {
+            strategy: strategy.name(),
+            ok,
+            detail,
+        });
+        if ok {
+            return Ok(Some(*strategy));
+        }
+    }
+
+    let tried = plan
+        .strategies
+        .iter()
+        .map(Strategy::name)
+        .collect::<Vec<_>>()
+        .join(", ");
+    Err(Error::DeviceNotFound(format!(
+        "bootloader {boot_serial:016X}, tried: {tried}"
+    )))
+}
@@ -0,0 +1,123 @@
+//! Pick up an interrupted transfer where it stopped
+//!
+//! A full flash erases the partition and then writes the image in order, so
+//! after an interruption the device holds some prefix of the new image. That
+//! prefix is found by comparing against the device, and only the rest is
+//! erased and written again.
+//!
+//! With the bootloader's CRC endpoint, the longest matching prefix is found
+//! with a binary search over whole-prefix CRCs. Without it, sectors are read
+//! back from the start until the first one that differs.
+
+use std::time::Instant;
+
+use bootloader_icd::{AppPartitionInfo, HashFlashEndpoint};
+use curacao_host::{crc32, BootloaderClient};
+use postcard_rpc::Endpoint;
+use serde::Serialize;
+
+use crate::{delta::Compare, error::Error, flash, output::Output};
+
+#[derive(Debug, Serialize)]
+pub struct ResumeReport {
+    pub compare: Compare,
+    pub sectors: u32,
+    /// Index of the first sector that had to be written again
+    pub resumed_at: u32,
+    /// Bytes of the image that were already on the device
+    pub bytes_skipped: u32,
+    pub millis: u64,
+}
+
+/// Write `data` at `info.start`, skipping the prefix already on the device
+///
+/// `data` must already be padded to whole erase sectors.
+pub async fn flash(
+    bl: &BootloaderClient,
+    out: Output,
+    info: &AppPartitionInfo,
+    data: &[u8],
+) -> Result<ResumeReport, Error> {
+    let started = Instant::now();
+    let compare = if bl.has_endpoint(HashFlashEndpoint::PATH).await? {
+        Compare::Crc32
+    } else {
+        Compare::Readback
+    };
+
+    let sector = info.erase_sz as usize;
+    let sectors = (data.len() / sector) as u32;
+    let resumed_at = match compare {
+        Compare::Crc32 => crc_prefix(bl, info, data, sectors).await?,
+        Compare::Readback => readback_prefix(bl, out, info, data).await?,
+    };
+
+    let skip = resumed_at as usize * sector;
+    if skip < data.len() {
+        flash::erase_and_write(bl, out, info.start + skip as u32, &data[skip..]).await?;
+    }
+
+    Ok(ResumeReport {
+        compare,
+        sectors,
+        resumed_at,
+        bytes_skipped: skip as u32,
+        millis: started.elapsed().as_millis() as u64,
+    })
+}
+
+/// Number of leading sectors that match, found by binary search. A matching
+/// prefix means every shorter prefix matches too, so this needs only
+/// `log2(sectors)` requests
+async fn crc_prefix(
+    bl: &BootloaderClient,
+    info: &AppPartitionInfo,
+    data: &[u8],
+    sectors: u32,
+) -> Result<u32, Error> {
+    let sector = info.erase_sz;
+    let (mut lo, mut hi) = (0, sectors);
+    while lo < hi {
+        let mid = (lo + hi + 1) / 2;
+        let len = mid * sector;
+        if bl.crc32(info.start, len).await? == crc32(&data[..len as usize]) {
+            lo = mid;
+        } else {
+            hi = mid - 1;
+        }
+    }
+    Ok(lo)
+}
+
+/// Number of leading sectors that match, reading back one sector at a time
+async fn readback_prefix(
+    bl: &BootloaderClient,
+    out: Output,
+    info: &AppPartitionInfo,
+    data: &[u8],
+) -> Result<u32, Error> {
+    let sector = info.erase_sz as usize;
+    let mut progress = out.progress("compare", data.len() as u32);
+    for (i, ch) in data.chunks(sector).enumerate() {
+        let addr = info.start + (i * sector) as u32;
+        if bl.read(addr, ch.len() as u32).await? != ch {
+            progress.update(data.len() as u32);
+            return Ok(i as u32);
+        }
+        progress.update(((i + 1) * sector) as u32);
+    }
+    Ok((data.len() / sector) as u32)
+}
+
+impl ResumeReport {
+    pub fn print_summary(&self) {
+        println!(
+            "Resume ({:?}): {}/{} sectors were already written, skipped {:0.02}KiB in {:0.02}s",
+            self.compare,
+            self.resumed_at,
+            self.sectors,
+            self.bytes_skipped as f32 / 1024.0,
+            self.millis as f32 / 1000.0,
+        );
+    }
+}
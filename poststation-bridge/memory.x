@@ -1,7 +1,8 @@
 MEMORY
 {
     /* NOTE 1 K = 1 KiBi = 1024 bytes */
-    FLASH   : ORIGIN = 0x00010000, LENGTH = 1024K - 64K
+    FLASH   : ORIGIN = 0x00010000, LENGTH = 1024K - 64K - 4K
+    ALLOW   : ORIGIN = 0x000FF000, LENGTH = 4K
     SCRATCH : ORIGIN = 0x20000000, LENGTH = 1K
     RAM     : ORIGIN = 0x20000400, LENGTH = 256K - 1K
 }
@@ -12,4 +13,10 @@ SECTIONS
     {
         KEEP(*(.scratch .scratch.*));
     } > SCRATCH
+
+    .allow (NOLOAD) : ALIGN(4)
+    {
+        KEEP(*(.allow .allow.*));
+        . = ALIGN(4);
+    } > ALLOW
 }
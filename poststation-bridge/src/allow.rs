@@ -0,0 +1,115 @@
+//! Which nodes may join, and the pairing window that enrolls new ones
+//!
+//! The list is kept in its own flash page, see [`crate::storage`].
+
+use bridge_icd::{AllowError, AllowList, AllowResult};
+use embassy_nrf::nvmc::Nvmc;
+use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex, signal::Signal};
+use embassy_time::{Duration, Instant};
+
+use crate::storage::{read_allowlist, write_allowlist};
+
+/// Raised when nodes enrolled while joining are waiting to be stored
+pub static UNSTORED: Signal<ThreadModeRawMutex, ()> = Signal::new();
+
+pub struct Allow {
+    list: AllowList,
+    nvmc: Nvmc<'static>,
+    /// Unknown nodes are enrolled until then
+    pairing_until: Option<Instant>,
+    /// The list has nodes that aren't in flash yet
+    dirty: bool,
+}
+
+impl Allow {
+    /// Load the list stored in flash
+    pub fn load(nvmc: Nvmc<'static>) -> Self {
+        let list = read_allowlist();
+        defmt::info!("{=usize} nodes allowed", list.serials.len());
+        Self {
+            list,
+            nvmc,
+            pairing_until: None,
+            dirty: false,
+        }
+    }
+
+    pub fn list(&self) -> &AllowList {
+        &self.list
+    }
+
+    /// Allow `serial` to join, storing the list. Adding a serial that's
+    /// already allowed does nothing
+    pub fn add(&mut self, serial: &[u8; 8]) -> AllowResult {
+        if self.list.serials.contains(serial) {
+            return Ok(());
+        }
+        let mut list = self.list.clone();
+        list.serials.push(*serial).map_err(|_| AllowError::Full)?;
+        self.store(list)
+    }
+
+    /// Stop allowing `serial` to join, storing the list
+    pub fn remove(&mut self, serial: &[u8; 8]) -> AllowResult {
+        let mut list = self.list.clone();
+        let Some(i) = list.serials.iter().position(|s| s == serial) else {
+            return Err(AllowError::NotFound);
+        };
+        list.serials.swap_remove(i);
+        self.store(list)
+    }
+
+    fn store(&mut self, list: AllowList) -> AllowResult {
+        write_allowlist(&mut self.nvmc, &list)?;
+        self.list = list;
+        self.dirty = false;
+        Ok(())
+    }
+
+    /// Store nodes enrolled by [`Allow::admit`]. This stalls the CPU while
+    /// the page is erased, so it is left to the `store_allowlist` task
+    pub fn flush(&mut self) -> AllowResult {
+        if !self.dirty {
+            return Ok(());
+        }
+        write_allowlist(&mut self.nvmc, &self.list)?;
+        self.dirty = false;
+        Ok(())
+    }
+
+    /// Enroll unknown nodes for `dur`, a zero duration closes the window
+    pub fn open_pairing(&mut self, dur: Duration) {
+        if dur.as_ticks() == 0 {
+            defmt::info!("Pairing closed");
+            self.pairing_until = None;
+        } else {
+            defmt::info!("Pairing for {=u64}s", dur.as_secs());
+            self.pairing_until = Some(Instant::now() + dur);
+        }
+    }
+
+    fn pairing(&self) -> bool {
+        self.pairing_until.is_some_and(|until| Instant::now() < until)
+    }
+
+    /// May `serial` join? While pairing, unknown nodes are enrolled
+    ///
+    /// This is called while a node joins, so new nodes are only stored in
+    /// flash later, see [`UNSTORED`].
+    pub fn admit(&mut self, serial: &[u8; 8]) -> bool {
+        if self.list.serials.contains(serial) {
+            return true;
+        }
+        if !self.pairing() {
+            return false;
+        }
+        if self.list.serials.push(*serial).is_err() {
+            defmt::warn!("Can't pair: {:?}", AllowError::Full);
+            return false;
+        }
+        defmt::info!("Paired {=[u8]:02X}", serial.as_slice());
+        self.dirty = true;
+        UNSTORED.signal(());
+        true
+    }
+}
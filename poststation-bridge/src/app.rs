@@ -1,14 +1,20 @@
 //! A basic postcard-rpc/poststation-compatible application
 
 use crate::{
+    allow::Allow,
     bridge::{self, SMutex},
-    handlers::{get_led, proxy_handler, set_led, sleep_handler, unique_id, reboot_bootloader},
+    groups::Groups,
+    handlers::{allow_list, allow_node, disallow_node, open_pairing, get_led, get_groups, group_proxy_handler, join_group, leave_group, link_stats, node_links, set_time, proxy_handler, set_led, sleep_handler, unique_id, reboot_bootloader},
     table::Table,
 };
 use bridge_icd::{
-    GetLedEndpoint, GetUniqueIdEndpoint, Host2BridgeEndpoint, SetLedEndpoint, SleepEndpoint, RebootToBootloader,
+    AllowNodeEndpoint, DisallowNodeEndpoint, GetAllowListEndpoint, GetLinkStatsEndpoint, OpenPairingEndpoint,
+    GetGroupsEndpoint, Host2GroupEndpoint, JoinGroupEndpoint, LeaveGroupEndpoint, SetTimeEndpoint,
+    GetLedEndpoint, GetNodeLinksEndpoint, GetUniqueIdEndpoint, Host2BridgeEndpoint, SetLedEndpoint, SleepEndpoint, RebootToBootloader,
+};
+use bridge_icd::{
+    link::NetworkKey, FragBuf, ENDPOINT_LIST, FRAG_BUF_SIZE, MAX_FRAG_SIZE, TOPICS_IN_LIST, TOPICS_OUT_LIST,
 };
-use bridge_icd::{ENDPOINT_LIST, TOPICS_IN_LIST, TOPICS_OUT_LIST};
 use embassy_nrf::{
     gpio::Output,
     peripherals::USBD,
@@ -34,6 +40,8 @@ pub struct Context {
     pub led: Output<'static>,
     pub esb_sender: bridge::Sender<1024>,
     pub table: SMutex<Table>,
+    pub allow: SMutex<Allow>,
+    pub groups: SMutex<Groups>,
 }
 
 impl SpawnContext for Context {
@@ -42,12 +50,18 @@ impl SpawnContext for Context {
     fn spawn_ctxt(&mut self) -> Self::SpawnCtxt {
         TaskContext {
             unique_id: self.unique_id,
+            esb_sender: self.esb_sender.clone(),
+            table: self.table,
+            groups: self.groups,
         }
     }
 }
 
 pub struct TaskContext {
     pub unique_id: u64,
+    pub esb_sender: bridge::Sender<1024>,
+    pub table: SMutex<Table>,
+    pub groups: SMutex<Groups>,
 }
 
 // Type Aliases
@@ -70,6 +84,15 @@ pub type AppStorage = WireStorage<ThreadModeRawMutex, AppDriver, 256, 256, 64, 2
 /// BufStorage is the space used for receiving and sending frames. These values
 /// control the largest frames we can send or receive.
 pub type BufStorage = PacketBuffers<1024, 1024>;
+/// Largest fragment payload we offer nodes, as much as fits in one ESB packet
+pub const FRAG_SIZE: usize = MAX_FRAG_SIZE;
+/// Largest message we can send to or receive from a node
+pub const FRAG_CAPACITY: usize = FRAG_BUF_SIZE;
+/// NodeFragBuf is where messages from each node are reassembled
+pub type NodeFragBuf = FragBuf<FRAG_SIZE, FRAG_CAPACITY>;
+/// Network key every node's link key is derived from, set at build time
+/// with `CURACAO_NETWORK_KEY`
+pub const NETWORK_KEY: NetworkKey = NetworkKey::from_build_env(option_env!("CURACAO_NETWORK_KEY"));
 /// AppTx is the type of our sender, which is how we send information to the client
 pub type AppTx = WireTxImpl<ThreadModeRawMutex, AppDriver>;
 /// AppRx is the type of our receiver, which is how we receive information from the client
@@ -123,6 +146,17 @@ define_dispatch! {
         | GetLedEndpoint            | blocking  | get_led                       |
         | RebootToBootloader        | spawn     | reboot_bootloader             |
         | Host2BridgeEndpoint       | async     | proxy_handler                 |
+        | GetNodeLinksEndpoint      | async     | node_links                    |
+        | AllowNodeEndpoint         | async     | allow_node                    |
+        | DisallowNodeEndpoint      | async     | disallow_node                 |
+        | GetAllowListEndpoint      | async     | allow_list                    |
+        | OpenPairingEndpoint       | async     | open_pairing                  |
+        | GetLinkStatsEndpoint      | async     | link_stats                    |
+        | Host2GroupEndpoint        | spawn     | group_proxy_handler           |
+        | JoinGroupEndpoint         | async     | join_group                    |
+        | LeaveGroupEndpoint        | async     | leave_group                   |
+        | GetGroupsEndpoint         | async     | get_groups                    |
+        | SetTimeEndpoint           | blocking  | set_time                      |
     };
 
     // Topics IN are messages we receive from the client, but that we do not reply
@@ -1,9 +1,14 @@
 use bridge_icd::{
-    extract_topic2, write_topic2, B2NTopic, Bridge2HostTopic, Bridge2Node, BridgeTable, BridgeTableTopic, FragBuf, N2BTopic, Node2Bridge, ProxyMessage, TopicExtract
+    codec::{encode_topic, take_topic, Frame},
+    link::{Hello, LinkKey, Welcome, CIPHER, CTR_LEN, IV_LEN},
+    B2NTopic, Bridge2HostTopic, Bridge2Node, BridgeTable,
+    BridgeTableTopic, Capabilities, FragAck, FragBuf, FragHeader, JoinParams,
+    JoinReject, JoinRefusal, JoinRejected, JoinRejectedTopic, LinkStatsTopic, N2BTopic, Node2Bridge, ProxyMessage, pkg_version, seal_join_reply, ESB_MAX_PAYLOAD, LINK_KEY, PROTOCOL_VERSION,
 };
 use embassy_futures::select::{select, Either};
-use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex, mutex::Mutex};
-use embassy_time::{Duration, Ticker};
+use embassy_nrf::{peripherals::RNG, rng::Rng};
+use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex, mutex::Mutex, signal::Signal};
+use embassy_time::{Duration, Instant, Ticker};
 use esb::{
     app::{EsbAppReceiver, EsbAppSender},
     payload::PayloadR,
@@ -13,8 +18,10 @@ use postcard_rpc::{header::VarSeq, server::Sender as PrpcSender};
 use static_cell::ConstStaticCell;
 
 use crate::{
-    app::AppTx,
-    table::{PipeAlloc, Table},
+    allow::Allow,
+    clock,
+    app::{AppTx, NodeFragBuf, NETWORK_KEY},
+    table::{Join, PipeAlloc, Table},
 };
 
 pub type SMutex<T> = &'static Mutex<ThreadModeRawMutex, T>;
@@ -25,29 +32,74 @@ pub struct Sender<const OUT: usize> {
     pub sender: SMutex<EsbAppSender<OUT>>,
 }
 
-const fn fbufs() -> [FragBuf; 7] {
-    [const { FragBuf::new() }; 7]
+const fn fbufs() -> [NodeFragBuf; 7] {
+    [const { NodeFragBuf::new() }; 7]
 }
 
-pub static FRAG_BUFS: ConstStaticCell<[FragBuf; 7]> = ConstStaticCell::new(fbufs());
+pub static FRAG_BUFS: ConstStaticCell<[NodeFragBuf; 7]> = ConstStaticCell::new(fbufs());
 
-pub struct Bridge<const OUT: usize, const IN: usize> {
+/// The latest ack from each pipe's node, for the proxy handler waiting on it
+pub static NODE_ACKS: [Signal<ThreadModeRawMutex, FragAck>; 7] = [const { Signal::new() }; 7];
+
+/// Held while a message is sent to each pipe's node, which reassembles one at
+/// a time
+pub static PIPE_SENDS: [Mutex<ThreadModeRawMutex, ()>; 7] = [const { Mutex::new(()) }; 7];
+
+/// What the bridge supports, negotiated against each node as it joins, for a
+/// `FragBuf<FRAG, CAP>` per node
+pub fn bridge_params<const FRAG: usize, const CAP: usize>() -> JoinParams {
+    JoinParams {
+        protocol: PROTOCOL_VERSION,
+        firmware: pkg_version!(),
+        max_frag: FragBuf::<FRAG, CAP>::MAX_FRAG,
+        frag_capacity: FragBuf::<FRAG, CAP>::CAPACITY,
+        caps: Capabilities::NONE,
+    }
+}
+
+pub struct Bridge<const OUT: usize, const IN: usize, const FRAG: usize, const CAP: usize> {
     pub table: SMutex<Table>,
     pub esb_sender: Sender<OUT>,
     pub recv: EsbAppReceiver<IN>,
     pub prpc_sender: PrpcSender<AppTx>,
     pub table_ctr: u16,
     pub proxy_ctr: u16,
-    pub frag_bufs: &'static mut [FragBuf; 7],
+    pub reject_ctr: u16,
+    pub stats_ctr: u16,
+    pub frag_bufs: &'static mut [FragBuf<FRAG, CAP>; 7],
+    pub rng: Rng<'static, RNG>,
+    pub allow: SMutex<Allow>,
 }
 
-impl<const OUT: usize, const IN: usize> Bridge<OUT, IN> {
+/// How a reply is sealed, see [`bridge_icd::link`]
+enum Seal<'a> {
+    /// A join reply, sealed with the node's link key
+    Join { link: &'a LinkKey, welcome: Welcome },
+    /// Anything else, sealed with the pipe's session
+    Session,
+}
+
+impl<const OUT: usize, const IN: usize, const FRAG: usize, const CAP: usize>
+    Bridge<OUT, IN, FRAG, CAP>
+{
     fn table_ctr(&mut self) -> u16 {
         let n = self.table_ctr;
         self.table_ctr = self.table_ctr.wrapping_add(1);
         n
     }
 
+    fn stats_ctr(&mut self) -> u16 {
+        let n = self.stats_ctr;
+        self.stats_ctr = self.stats_ctr.wrapping_add(1);
+        n
+    }
+
+    fn reject_ctr(&mut self) -> u16 {
+        let n = self.reject_ctr;
+        self.reject_ctr = self.reject_ctr.wrapping_add(1);
+        n
+    }
+
     fn proxy_ctr(&mut self) -> u16 {
         let n = self.proxy_ctr;
         self.proxy_ctr = self.proxy_ctr.wrapping_add(1);
@@ -60,11 +112,11 @@ impl<const OUT: usize, const IN: usize> Bridge<OUT, IN> {
             match select(table_ticker.next(), self.recv.wait_read_packet()).await {
                 Either::First(()) => self.table_tick().await,
                 Either::Second(msg) => {
-                    if let Some(pkt) = extract_topic2::<N2BTopic>(&msg) {
-                        self.handle(&pkt, &msg).await;
+                    if msg.pipe() == 0 {
+                        self.join(&msg).await;
                     } else {
-                        defmt::warn!("Bad message");
-                    };
+                        self.receive(&msg, Instant::now()).await;
+                    }
 
                     msg.release();
                 }
@@ -77,11 +129,13 @@ impl<const OUT: usize, const IN: usize> Bridge<OUT, IN> {
         let mut tout = BridgeTable {
             sers: heapless::Vec::new(),
         };
+        let mut stats = heapless::Vec::new();
         // Perform table ops in a single mutex lock
         {
             let mut guard = self.table.lock().await;
             guard.cull_older_than(TIMEOUT);
             guard.extract_table(&mut tout.sers);
+            guard.extract_stats(&mut stats);
         }
         // send it up
         let seq = VarSeq::Seq2(self.table_ctr());
@@ -89,36 +143,154 @@ impl<const OUT: usize, const IN: usize> Bridge<OUT, IN> {
             .prpc_sender
             .publish::<BridgeTableTopic>(seq, &tout)
             .await;
+        for node in stats.iter() {
+            let seq = VarSeq::Seq2(self.stats_ctr());
+            let _ = self.prpc_sender.publish::<LinkStatsTopic>(seq, node).await;
+        }
     }
 
-    /// Handle incoming messages after they have been deserialized
-    async fn handle(&mut self, extract: &TopicExtract<'_, Node2Bridge>, grant: &PayloadR<IN>) {
+    /// Handle incoming messages from joined nodes after they have been
+    /// opened and deserialized
+    async fn handle(
+        &mut self,
+        extract: &Frame<'_, Node2Bridge>,
+        grant: &PayloadR<IN>,
+        rx_at: Instant,
+    ) {
         let reset = || Some(Bridge2Node::Reset);
 
         let reply = match (grant.pipe(), &extract.msg) {
-            (0, Node2Bridge::Initialize { serial }) => self.init_serial(serial).await,
-            (0, _) => reset(),
+            // Joins only happen on pipe 0, see `join`
             (_, Node2Bridge::Initialize { .. }) => reset(),
-            (n, Node2Bridge::Keepalive { serial }) => {
-                let update_ok = { self.table.lock().await.update_time(n, serial) };
+            (n, Node2Bridge::Keepalive { serial, radio, seq }) => {
+                let update_ok = {
+                    let mut table = self.table.lock().await;
+                    let ok = table.update_time(n, serial);
+                    if let (true, Some(stats)) = (ok, table.stats_mut(n)) {
+                        stats.node = *radio;
+                    }
+                    ok
+                };
                 if update_ok {
                     // reply keepalive
                     defmt::info!("Pipe {=u8} is alive", n);
-                    Some(Bridge2Node::Keepalive { serial: *serial })
+                    Some(Bridge2Node::Keepalive {
+                        serial: *serial,
+                        seq: *seq,
+                        time_us: clock::at(rx_at),
+                    })
                 } else {
                     reset()
                 }
             }
-            (n, Node2Bridge::Proxy { part, ttl_parts }) => self.proxy(n, extract.remain, *part, *ttl_parts).await,
+            (n, Node2Bridge::Proxy { hdr }) => self.proxy(n, extract.remain, *hdr).await,
+            (n, Node2Bridge::ProxyAck { ack }) => {
+                if let Some(sig) = NODE_ACKS.get((n - 1) as usize) {
+                    sig.signal(*ack);
+                }
+                None
+            }
             // TODO: validate Nops?
             (_n, Node2Bridge::Nop) => None,
         };
 
-        let Some(reply) = reply else {
+        if let Some(reply) = reply {
+            self.send_reply(grant, &reply, Seal::Session).await;
+        }
+    }
+
+    /// Open a frame from a joined node, dropping anything that isn't sealed
+    /// with the pipe's session
+    async fn receive(&mut self, grant: &PayloadR<IN>, rx_at: Instant) {
+        let pipe = grant.pipe();
+        let mut buf = [0u8; ESB_MAX_PAYLOAD as usize];
+        let pkt = {
+            let mut table = self.table.lock().await;
+            let opened = table
+                .session_mut(pipe)
+                .map(|session| session.rx.open(&CIPHER, grant, &mut buf).map(|p| p.len()));
+            let pkt = match opened {
+                Some(Ok(len)) => match take_topic::<N2BTopic>(&buf[..len]) {
+                    Ok(pkt) => Some(pkt),
+                    Err(e) => {
+                        defmt::warn!("Bad message on pipe {=u8}: {:?}", pipe, e);
+                        None
+                    }
+                },
+                Some(Err(e)) => {
+                    defmt::warn!("Dropped frame on pipe {=u8}: {:?}", pipe, e);
+                    None
+                }
+                // Without a session there's nothing to answer with, the node
+                // notices the silence and joins again
+                None => return,
+            };
+            table.record_rssi(pipe, grant.rssi());
+            if let Some(stats) = table.stats_mut(pipe) {
+                if pkt.is_some() {
+                    stats.received += 1;
+                } else {
+                    stats.bad_frames += 1;
+                }
+            }
+            pkt
+        };
+        if let Some(pkt) = pkt {
+            self.handle(&pkt, grant, rx_at).await;
+        }
+    }
+
+    /// Open a join request on pipe 0 and answer it
+    async fn join(&mut self, grant: &PayloadR<IN>) {
+        let Some(hello) = Hello::peek(grant) else {
+            defmt::warn!("Short join request");
             return;
         };
+        let link = NETWORK_KEY.node_key(&hello.serial);
+        let mut buf = [0u8; ESB_MAX_PAYLOAD as usize];
+        let Ok(plain) = hello.open(&CIPHER, &link, grant, &mut buf) else {
+            defmt::warn!("Dropped join request that doesn't open");
+            self.report_rejected(hello.serial, JoinRefusal::BadSeal).await;
+            return;
+        };
+        let extract = match take_topic::<N2BTopic>(plain) {
+            Ok(extract) => extract,
+            Err(e) => {
+                defmt::warn!("Bad join request: {:?}", e);
+                return;
+            }
+        };
+        // The sealed serial must be the one the key was derived from
+        let Node2Bridge::Initialize { serial, params } = extract.msg else {
+            return;
+        };
+        if serial != hello.serial {
+            return;
+        }
+
+        let (reply, bridge_iv) = self.init_serial(&hello, &link, &params).await;
+        if let Bridge2Node::InitializeReject { reason, .. } = reply {
+            self.report_rejected(serial, JoinRefusal::Rejected(reason)).await;
+        }
+        let welcome = Welcome { bridge_iv };
+        self.send_reply(grant, &reply, Seal::Join { link: &link, welcome })
+            .await;
+    }
+
+    /// Tell the host a node was turned away
+    async fn report_rejected(&mut self, serial: [u8; 8], reason: JoinRefusal) {
+        let seq = VarSeq::Seq2(self.reject_ctr());
+        let _ = self
+            .prpc_sender
+            .publish::<JoinRejectedTopic>(seq, &JoinRejected { serial, reason })
+            .await;
+    }
 
-        let Ok(header) = EsbHeader::new(252, grant.pid(), grant.pipe(), true) else {
+    /// Queue `reply` to go out with the ack of the next packet on `grant`'s
+    /// pipe
+    async fn send_reply(&mut self, grant: &PayloadR<IN>, reply: &Bridge2Node, seal: Seal<'_>) {
+        let pipe = grant.pipe();
+        let Ok(header) = EsbHeader::new(ESB_MAX_PAYLOAD, grant.pid(), pipe, true) else {
             defmt::error!("Bad header?");
             return;
         };
@@ -128,79 +300,162 @@ impl<const OUT: usize, const IN: usize> Bridge<OUT, IN> {
             return;
         };
 
-        let res = write_topic2::<B2NTopic>(&reply, VarSeq::Seq2(self.proxy_ctr()), &mut wgr);
-
-        if let Some(used) = res {
-            wgr.commit(used);
+        let sealed = match seal {
+            // A repeated join gets the same bridge_iv, and so the same nonce,
+            // as the first. This keeps the reply the same too
+            Seal::Join { link, welcome } => {
+                seal_join_reply(&CIPHER, link, &welcome, reply, &mut wgr)
+            }
+            Seal::Session => {
+                let seq = VarSeq::Seq2(self.proxy_ctr());
+                let Ok(used) =
+                    encode_topic::<B2NTopic>(LINK_KEY, seq, reply, &mut wgr[CTR_LEN..])
+                else {
+                    return;
+                };
+                let mut table = self.table.lock().await;
+                table
+                    .session_mut(pipe)
+                    .and_then(|session| session.tx.seal(&CIPHER, &mut wgr, used).ok())
+            }
+        };
+        if let Some(len) = sealed {
+            wgr.commit(len);
         }
     }
 
-    /// Helper function for handling Initialize requests
-    async fn init_serial(&mut self, serial: &[u8; 8]) -> Option<Bridge2Node> {
-        let alloc_res = { self.table.lock().await.allocate_pipe(&serial) };
-        match alloc_res {
-            Some(PipeAlloc::New(pipe)) => {
+    fn new_iv(&mut self) -> [u8; IV_LEN] {
+        let mut iv = [0u8; IV_LEN];
+        self.rng.blocking_fill_bytes(&mut iv);
+        iv
+    }
+
+    /// Helper function for handling Initialize requests, returning the reply
+    /// and the `bridge_iv` to seal it with
+    async fn init_serial(
+        &mut self,
+        hello: &Hello,
+        link: &LinkKey,
+        node: &JoinParams,
+    ) -> (Bridge2Node, [u8; IV_LEN]) {
+        let serial = &hello.serial;
+        let node_iv = hello.node_iv;
+        // Every reject gets a fresh IV, it's sealed with the link key
+        let bridge_iv = self.new_iv();
+        let reject = |reason| {
+            let reply = Bridge2Node::InitializeReject {
+                serial: *serial,
+                reason,
+                node_iv,
+            };
+            (reply, bridge_iv)
+        };
+        if !self.allow.lock().await.admit(serial) {
+            defmt::warn!("Rejecting node that isn't allowed");
+            return reject(JoinReject::NotAllowed);
+        }
+        let params = match bridge_params::<FRAG, CAP>().negotiate(node) {
+            Ok(params) => params,
+            Err(reason) => {
+                defmt::warn!("Rejecting node running protocol {=u16}", node.protocol);
+                return reject(reason);
+            }
+        };
+
+        let join = Join {
+            link,
+            node_iv,
+            bridge_iv,
+        };
+        let alloc_res = {
+            self.table
+                .lock()
+                .await
+                .allocate_pipe(serial, node.firmware, params, join)
+        };
+        let Some((alloc, bridge_iv)) = alloc_res else {
+            return reject(JoinReject::TableFull);
+        };
+        let pipe = match alloc {
+            PipeAlloc::New(pipe) => {
                 defmt::info!("Allocating pipe {=u8}", pipe);
-                Some(Bridge2Node::InitializeAck {
-                    serial: *serial,
-                    use_pipe: pipe,
-                })
+                pipe
             }
-            Some(PipeAlloc::Existing(pipe)) => {
-                // Send init ack
-                Some(Bridge2Node::InitializeAck {
-                    serial: *serial,
-                    use_pipe: pipe,
-                })
+            PipeAlloc::Existing(pipe) | PipeAlloc::Repeated(pipe) => pipe,
+        };
+        // Whatever the node was sending before it (re)joined is gone, and a
+        // new node counts from zero. A repeated request is the same join,
+        // which may already be sending
+        let frag_buf = &mut self.frag_bufs[(pipe - 1) as usize];
+        match alloc {
+            PipeAlloc::New(_) => {
+                frag_buf.reset_frag();
+                frag_buf.clear_stats();
             }
-            None => Some(Bridge2Node::Reset),
+            PipeAlloc::Existing(_) => frag_buf.reset_frag(),
+            PipeAlloc::Repeated(_) => {}
         }
+        let reply = Bridge2Node::InitializeAck {
+            serial: *serial,
+            use_pipe: pipe,
+            params,
+            node_iv,
+        };
+        (reply, bridge_iv)
     }
 
     /// Helper function for handling Proxy requests
+    ///
+    /// Every fragment is answered with an ack of everything received so far,
+    /// so the node can resend just the parts that went missing
     async fn proxy(
         &mut self,
         pipe: u8,
         remain: &[u8],
-        part: u8,
-        ttl_parts: u8
+        hdr: FragHeader,
     ) -> Option<Bridge2Node> {
-        let ser_for_pipe = { self.table.lock().await.serial_for_pipe(pipe) };
-        if let Some(ser) = ser_for_pipe {
-            if ttl_parts == 0 {
-                defmt::warn!("Malformed frag?");
-                return None;
-            }
-            let Bridge { prpc_sender, frag_bufs, proxy_ctr, .. } = self;
-
-            let to_fwd = if part == 0 && ttl_parts == 1 {
-                Some(remain)
-            } else {
-                frag_bufs[(pipe - 1) as usize].handle_frag(part, ttl_parts, remain)
-            };
+        let pipe_info = {
+            let guard = self.table.lock().await;
+            guard
+                .serial_for_pipe(pipe)
+                .and_then(|ser| Some((ser, guard.params_for_pipe(pipe)?)))
+        };
+        let Some((ser, params)) = pipe_info else {
+            return Some(Bridge2Node::Reset);
+        };
+        let Bridge { prpc_sender, frag_bufs, proxy_ctr, table, .. } = self;
+        let frag_buf = &mut frag_bufs[(pipe - 1) as usize];
 
-            if let Some(to_fwd) = to_fwd {
-                let seq = VarSeq::Seq2({
-                    let n = *proxy_ctr;
-                    *proxy_ctr = proxy_ctr.wrapping_add(1);
-                    n
-                });
-                // todo: validate `remain` has valid contents for a postcard-rpc message?
-                let _ = prpc_sender
-                    .publish::<Bridge2HostTopic>(
-                        seq,
-                        &ProxyMessage {
-                            serial: ser,
-                            msg: to_fwd,
-                        },
-                    )
-                    .await;
+        let to_fwd = match frag_buf.handle_frag(hdr, params.max_frag as usize, remain) {
+            Ok(to_fwd) => to_fwd,
+            Err(e) => {
+                defmt::warn!("Dropped frag from pipe {=u8}: {:?}", pipe, e);
+                None
             }
+        };
+        if let Some(to_fwd) = to_fwd {
+            let seq = VarSeq::Seq2({
+                let n = *proxy_ctr;
+                *proxy_ctr = proxy_ctr.wrapping_add(1);
+                n
+            });
+            // todo: validate `remain` has valid contents for a postcard-rpc message?
+            let _ = prpc_sender
+                .publish::<Bridge2HostTopic>(
+                    seq,
+                    &ProxyMessage {
+                        serial: ser,
+                        msg: to_fwd,
+                    },
+                )
+                .await;
+        }
 
-            // TODO: ack proxy requests?
-            None
-        } else {
-            Some(Bridge2Node::Reset)
+        if let Some(stats) = table.lock().await.stats_mut(pipe) {
+            stats.frags = frag_buf.stats();
         }
+        Some(Bridge2Node::ProxyAck {
+            ack: frag_buf.ack(),
+        })
     }
 }
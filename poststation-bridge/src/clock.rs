@@ -0,0 +1,21 @@
+//! The wall clock, as last set by the host
+
+use core::cell::Cell;
+
+use embassy_sync::blocking_mutex::{raw::ThreadModeRawMutex, Mutex};
+use embassy_time::Instant;
+
+/// Wall clock minus uptime, in microseconds
+static OFFSET: Mutex<ThreadModeRawMutex, Cell<Option<u64>>> = Mutex::new(Cell::new(None));
+
+pub fn set(unix_us: u64) {
+    let offset = unix_us.wrapping_sub(Instant::now().as_micros());
+    OFFSET.lock(|o| o.set(Some(offset)));
+}
+
+/// The wall clock at `at`, in microseconds since the Unix epoch, once the
+/// host has set it
+pub fn at(at: Instant) -> Option<u64> {
+    let offset = OFFSET.lock(|o| o.get())?;
+    Some(offset.wrapping_add(at.as_micros()))
+}
@@ -0,0 +1,68 @@
+//! Named groups of nodes the host can send to at once
+
+use bridge_icd::{Group, GroupError, GroupList, GroupName, GroupResult, MAX_GROUPS};
+
+#[derive(Default)]
+pub struct Groups {
+    groups: heapless::Vec<Group, MAX_GROUPS>,
+}
+
+impl Groups {
+    pub const fn new() -> Self {
+        Self {
+            groups: heapless::Vec::new(),
+        }
+    }
+
+    pub fn members(&self, name: &GroupName) -> Option<&[[u8; 8]]> {
+        let group = self.groups.iter().find(|g| g.name == *name)?;
+        Some(&group.members)
+    }
+
+    /// Add `serial` to the group, creating it if it's new. Joining a group
+    /// twice does nothing
+    pub fn join(&mut self, name: &GroupName, serial: &[u8; 8]) -> GroupResult {
+        let group = match self.groups.iter().position(|g| g.name == *name) {
+            Some(i) => &mut self.groups[i],
+            None => {
+                let group = Group {
+                    name: name.clone(),
+                    members: heapless::Vec::new(),
+                };
+                self.groups
+                    .push(group)
+                    .map_err(|_| GroupError::TooManyGroups)?;
+                self.groups.last_mut().unwrap()
+            }
+        };
+        if group.members.contains(serial) {
+            return Ok(());
+        }
+        group
+            .members
+            .push(*serial)
+            .map_err(|_| GroupError::GroupFull)
+    }
+
+    /// Take `serial` out of the group, dropping the group once it's empty
+    pub fn leave(&mut self, name: &GroupName, serial: &[u8; 8]) -> GroupResult {
+        let Some(gi) = self.groups.iter().position(|g| g.name == *name) else {
+            return Err(GroupError::NotFound);
+        };
+        let group = &mut self.groups[gi];
+        let Some(mi) = group.members.iter().position(|s| s == serial) else {
+            return Err(GroupError::NotFound);
+        };
+        group.members.swap_remove(mi);
+        if group.members.is_empty() {
+            self.groups.swap_remove(gi);
+        }
+        Ok(())
+    }
+
+    pub fn list(&self) -> GroupList {
+        GroupList {
+            groups: self.groups.clone(),
+        }
+    }
+}
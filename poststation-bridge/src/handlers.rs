@@ -1,16 +1,65 @@
+use core::sync::atomic::{AtomicU16, Ordering};
+
 use bootloader_icd::scratch::BootMessage;
 use bridge_icd::{
-    write_topic2, B2NTopic, Bridge2Node, LedState, ProxyError, ProxyMessage, ProxyResult, RebootToBootloader, SleepEndpoint, SleepMillis, SleptMillis
+    codec::encode_topic,
+    link::{CIPHER, CTR_LEN},
+    frag_mask, AllowList, AllowResult, Delivery, DeliveryError, DeliveryResult, GroupDelivery, GroupList, GroupMember, GroupMessage, GroupResult, LinkStats, PairingWindow, Recipients, WallTime, MAX_GROUP_MEMBERS, B2NTopic, FragHeader, Bridge2Node, LedState, NodeLinks, Host2GroupEndpoint, ProxyError, ProxyMessage, ProxyOutcome, ProxyOutcomeTopic, ProxyResult, RebootToBootloader, SleepEndpoint, SleepMillis, SleptMillis, ESB_MAX_PAYLOAD, LINK_KEY, PROXY_ACK_TIMEOUT_MS, PROXY_CRC, PROXY_RETRIES, FRAG_BUF_SIZE, TooLarge
 };
 use cortex_m::peripheral::SCB;
-use embassy_time::{Instant, Timer};
+use embassy_futures::join::join_array;
+use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex, channel::Channel};
+use embassy_time::{Duration, Instant, Timer, WithTimeout};
 use esb::EsbHeader;
 use postcard_rpc::{
     header::{VarHeader, VarSeq},
     server::Sender,
 };
 
-use crate::{app::{AppTx, Context, TaskContext}, storage::write_message};
+use crate::{app::{AppTx, Context, TaskContext}, clock, bridge::{NODE_ACKS, PIPE_SENDS}, storage::write_message};
+
+/// ID for the next message proxied to a node
+static PROXY_MSG_ID: AtomicU16 = AtomicU16::new(0);
+
+/// Report every joined node and the parameters it negotiated
+pub async fn node_links(context: &mut Context, _header: VarHeader, _arg: ()) -> NodeLinks {
+    let mut out = NodeLinks {
+        nodes: heapless::Vec::new(),
+    };
+    context.table.lock().await.extract_links(&mut out.nodes);
+    out
+}
+
+/// Set the wall clock handed out to nodes
+pub fn set_time(_context: &mut Context, _header: VarHeader, arg: WallTime) {
+    clock::set(arg.unix_us);
+}
+
+/// Link statistics of a joined node
+pub async fn link_stats(context: &mut Context, _header: VarHeader, arg: [u8; 8]) -> Option<LinkStats> {
+    context.table.lock().await.stats_for_serial(&arg)
+}
+
+/// Allow a node to join from now on
+pub async fn allow_node(context: &mut Context, _header: VarHeader, arg: [u8; 8]) -> AllowResult {
+    context.allow.lock().await.add(&arg)
+}
+
+/// Stop allowing a node to join, dropping it if it's joined now
+pub async fn disallow_node(context: &mut Context, _header: VarHeader, arg: [u8; 8]) -> AllowResult {
+    context.allow.lock().await.remove(&arg)?;
+    context.table.lock().await.remove(&arg);
+    Ok(())
+}
+
+pub async fn allow_list(context: &mut Context, _header: VarHeader, _arg: ()) -> AllowList {
+    context.allow.lock().await.list().clone()
+}
+
+pub async fn open_pairing(context: &mut Context, _header: VarHeader, arg: PairingWindow) {
+    let dur = Duration::from_secs(arg.secs.into());
+    context.allow.lock().await.open_pairing(dur);
+}
 
 /// This is an example of a BLOCKING handler.
 pub fn unique_id(context: &mut Context, _header: VarHeader, _arg: ()) -> u64 {
@@ -32,48 +81,242 @@ pub fn get_led(context: &mut Context, _header: VarHeader, _arg: ()) -> LedState
     }
 }
 
-pub async fn proxy_handler(
-    context: &mut Context,
-    _header: VarHeader,
-    arg: ProxyMessage<'_>,
-) -> ProxyResult {
-    let pipe = {
+/// How many proxied messages [`proxy_worker`]s deliver at once
+pub const PROXY_WORKERS: usize = 4;
+
+/// A message from the host, waiting for a [`proxy_worker`] to deliver it
+///
+/// `msg` is the [`TooLarge`] rejection instead if the node can't take it,
+/// which the worker reports without sending anything
+pub struct ProxyJob {
+    seq_no: VarSeq,
+    serial: [u8; 8],
+    msg: Result<heapless::Vec<u8, FRAG_BUF_SIZE>, TooLarge>,
+}
+
+/// Messages taken by [`proxy_handler`], for the [`proxy_worker`]s
+static PROXY_JOBS: Channel<ThreadModeRawMutex, ProxyJob, PROXY_WORKERS> = Channel::new();
+
+/// Take a message for a node, replying once it's queued for delivery
+///
+/// Delivering it can take a few seconds of resends, so that's left to the
+/// [`proxy_worker`]s, which report how it went on [`ProxyOutcomeTopic`].
+/// The message is borrowed from the receive buffer, so it's copied out first.
+/// With every worker busy and [`PROXY_WORKERS`] more messages queued, this
+/// waits for room, holding up other requests until then
+pub async fn proxy_handler(context: &mut Context, header: VarHeader, arg: ProxyMessage<'_>) -> ProxyResult {
+    let params = {
         let guard = context.table.lock().await;
-        guard.pipe_for_serial(&arg.serial)
+        guard
+            .pipe_for_serial(&arg.serial)
+            .and_then(|pipe| guard.params_for_pipe(pipe))
     };
-    let Some(pipe) = pipe else {
+    let Some(params) = params else {
         return Err(ProxyError::UnknownDevice);
     };
+    // poststation's `ProxyError` can't say the message is too large, so the
+    // rejection goes on `ProxyOutcomeTopic` like any other delivery outcome
+    let msg = params.frag_count(arg.msg.len()).and_then(|_| {
+        heapless::Vec::from_slice(arg.msg).map_err(|_| TooLarge {
+            len: arg.msg.len(),
+            max: FRAG_BUF_SIZE,
+        })
+    });
+    PROXY_JOBS
+        .send(ProxyJob {
+            seq_no: header.seq_no,
+            serial: arg.serial,
+            msg,
+        })
+        .await;
+    Ok(())
+}
 
-    let chunks = (arg.msg.len() + 127) / 128;
-    for (i, ch) in arg.msg.chunks(128).enumerate() {
-        let Ok(header) = EsbHeader::new(252, 0, pipe, false) else {
-            defmt::error!("Bad header?");
-            return Err(ProxyError::UnknownDevice);
+/// Deliver messages queued by [`proxy_handler`], one at a time
+///
+/// Fragments the node doesn't acknowledge within [`PROXY_ACK_TIMEOUT_MS`] are
+/// sent again, up to [`PROXY_RETRIES`] times
+#[embassy_executor::task(pool_size = PROXY_WORKERS)]
+pub async fn proxy_worker(context: TaskContext, sender: Sender<AppTx>) {
+    loop {
+        let job = PROXY_JOBS.receive().await;
+        let result = match &job.msg {
+            Ok(msg) => proxy_to(&context, &job.serial, msg).await,
+            Err(e) => Err(DeliveryError::TooLarge(*e)),
         };
-
-        let mut guard = context.esb_sender.sender.lock().await;
-        let Ok(mut wgr) = guard.wait_grant_packet(header).await else {
-            panic!();
+        let seq_no = match job.seq_no {
+            VarSeq::Seq1(n) => n.into(),
+            VarSeq::Seq2(n) => n.into(),
+            VarSeq::Seq4(n) => n,
+        };
+        let outcome = ProxyOutcome {
+            serial: job.serial,
+            seq_no,
+            result,
         };
+        let _ = sender
+            .publish::<ProxyOutcomeTopic>(job.seq_no, &outcome)
+            .await;
+    }
+}
 
-        let res = write_topic2::<B2NTopic>(
-            &Bridge2Node::Proxy {
-                part: i as u8,
-                ttl_parts: chunks as u8,
-            },
-            VarSeq::Seq2(0),
-            &mut wgr,
-        )
-        .unwrap();
-        let (_used, remain) = wgr.split_at_mut(res);
-        remain[..ch.len()].copy_from_slice(ch);
-        let ttl = res + ch.len();
-        wgr.commit(ttl);
-        defmt::info!("Proxied to pipe {=u8}", pipe);
+/// Send the same message to every node a [`GroupMessage`] is addressed to,
+/// replying with how each delivery went
+///
+/// Every node is sent to at once, so this takes as long as the slowest of
+/// them: bounded by that node's delivery, with up to [`PROXY_RETRIES`]
+/// resends, plus the airtime of the others' fragments sharing the radio.
+/// That can be seconds, so this is a SPAWN handler
+#[embassy_executor::task(pool_size = 2)]
+pub async fn group_proxy_handler(
+    context: TaskContext,
+    header: VarHeader,
+    arg: GroupMessage,
+    sender: Sender<AppTx>,
+) {
+    let mut serials = heapless::Vec::<[u8; 8], MAX_GROUP_MEMBERS>::new();
+    match &arg.to {
+        Recipients::All => {
+            let mut joined = heapless::Vec::new();
+            context.table.lock().await.extract_table(&mut joined);
+            let _ = serials.extend_from_slice(&joined);
+        }
+        Recipients::Group(name) => {
+            if let Some(members) = context.groups.lock().await.members(name) {
+                let _ = serials.extend_from_slice(members);
+            }
+        }
     }
 
-    Ok(())
+    // Each recipient's delivery waits on its own acks, but every fragment
+    // goes out through the one ESB TX queue, so the members still share the
+    // radio. A member that stops acking holds up only its own delivery, for
+    // at most PROXY_RETRIES rounds of PROXY_ACK_TIMEOUT_MS
+    let deliveries: [_; MAX_GROUP_MEMBERS] = core::array::from_fn(|i| {
+        let serial = serials.get(i).copied();
+        let (context, msg) = (&context, arg.msg.as_slice());
+        async move {
+            let serial = serial?;
+            let result = proxy_to(context, &serial, msg).await;
+            Some(Delivery { serial, result })
+        }
+    });
+    let out = GroupDelivery {
+        nodes: join_array(deliveries).await.into_iter().flatten().collect(),
+    };
+    let _ = sender
+        .reply::<Host2GroupEndpoint>(header.seq_no, &out)
+        .await;
+}
+
+async fn proxy_to(context: &TaskContext, serial: &[u8; 8], msg: &[u8]) -> DeliveryResult {
+    let pipe_params = {
+        let guard = context.table.lock().await;
+        guard
+            .pipe_for_serial(serial)
+            .and_then(|pipe| Some((pipe, guard.params_for_pipe(pipe)?)))
+    };
+    let Some((pipe, params)) = pipe_params else {
+        return Err(DeliveryError::UnknownDevice);
+    };
+
+    let frag = params.max_frag as usize;
+    let chunks = match params.frag_count(msg.len()) {
+        Ok(chunks) => chunks,
+        Err(e) => {
+            // The node would never be able to reassemble this, so don't send
+            // any of it
+            defmt::warn!(
+                "Not proxying {=usize} bytes to pipe {=u8}, the node takes at most {=usize}",
+                e.len,
+                pipe,
+                e.max
+            );
+            return Err(DeliveryError::TooLarge(e));
+        }
+    };
+    // One message at a time per node, or the acks of one would be taken for
+    // the other's
+    let _sending = PIPE_SENDS[(pipe - 1) as usize].lock().await;
+    let msg_id = PROXY_MSG_ID.fetch_add(1, Ordering::Relaxed);
+    let crc = PROXY_CRC.checksum(msg);
+    let acks = &NODE_ACKS[(pipe - 1) as usize];
+    acks.reset();
+
+    let mut missing = frag_mask(chunks);
+    for _attempt in 0..=PROXY_RETRIES {
+        for (i, ch) in msg.chunks(frag).enumerate() {
+            if missing & (1 << i) == 0 {
+                continue;
+            }
+            let Ok(header) = EsbHeader::new(ESB_MAX_PAYLOAD, 0, pipe, false) else {
+                defmt::error!("Bad header?");
+                return Err(DeliveryError::UnknownDevice);
+            };
+
+            let mut guard = context.esb_sender.sender.lock().await;
+            let Ok(mut wgr) = guard.wait_grant_packet(header).await else {
+                panic!();
+            };
+
+            let res = encode_topic::<B2NTopic>(
+                LINK_KEY,
+                VarSeq::Seq2(0),
+                &Bridge2Node::Proxy {
+                    hdr: FragHeader {
+                        msg_id,
+                        part: i as u8,
+                        ttl_parts: chunks,
+                        crc,
+                    },
+                },
+                &mut wgr[CTR_LEN..],
+            )
+            .unwrap();
+            wgr[CTR_LEN + res..][..ch.len()].copy_from_slice(ch);
+            let len = res + ch.len();
+
+            // The node may have rejoined or been culled since we looked it up
+            let sealed = {
+                let mut table = context.table.lock().await;
+                table
+                    .session_mut(pipe)
+                    .map(|session| session.tx.seal(&CIPHER, &mut wgr, len))
+            };
+            let Some(Ok(ttl)) = sealed else {
+                return Err(DeliveryError::UnknownDevice);
+            };
+            wgr.commit(ttl);
+        }
+
+        // Keep waiting as long as acks keep arriving
+        let tout = Duration::from_millis(PROXY_ACK_TIMEOUT_MS);
+        while let Ok(ack) = acks.wait().with_timeout(tout).await {
+            missing = ack.missing(msg_id, chunks);
+            if missing == 0 {
+                defmt::info!("Proxied to pipe {=u8}", pipe);
+                return Ok(());
+            }
+        }
+        defmt::warn!("Resending {=u32} frags to pipe {=u8}", missing.count_ones(), pipe);
+    }
+
+    defmt::warn!("Gave up proxying to pipe {=u8}", pipe);
+    Err(DeliveryError::NoAck)
+}
+
+/// Add a node to a group, creating the group if it's new
+pub async fn join_group(context: &mut Context, _header: VarHeader, arg: GroupMember) -> GroupResult {
+    context.groups.lock().await.join(&arg.group, &arg.serial)
+}
+
+/// Take a node out of a group, dropping the group once it's empty
+pub async fn leave_group(context: &mut Context, _header: VarHeader, arg: GroupMember) -> GroupResult {
+    context.groups.lock().await.leave(&arg.group, &arg.serial)
+}
+
+pub async fn get_groups(context: &mut Context, _header: VarHeader, _arg: ()) -> GroupList {
+    context.groups.lock().await.list()
 }
 
 /// This is a SPAWN handler
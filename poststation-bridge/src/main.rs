@@ -1,8 +1,12 @@
 #![no_std]
 #![no_main]
 
-use app::AppTx;
+use allow::Allow;
+use app::{AppTx, FRAG_CAPACITY, FRAG_SIZE, NETWORK_KEY};
 use bridge::{Bridge, SMutex, FRAG_BUFS};
+use groups::Groups;
+use handlers::{proxy_worker, PROXY_WORKERS};
+use bridge_icd::{BUTTON_PAIRING_SECS, ESB_MAX_PAYLOAD};
 use core::{
     ptr::null_mut,
     sync::atomic::{AtomicBool, AtomicPtr, Ordering},
@@ -14,10 +18,12 @@ use embassy_executor::Spawner;
 use embassy_nrf::{
     bind_interrupts,
     config::{Config as NrfConfig, HfclkSource, LfclkSource},
-    gpio::{Level, Output, OutputDrive},
+    gpio::{Input, Level, Output, OutputDrive, Pull},
     interrupt::{self, Priority},
+    nvmc::Nvmc,
     pac::{Interrupt, FICR},
-    peripherals::USBD,
+    peripherals::{RNG, USBD},
+    rng::{self, Rng},
     usb::{self, vbus_detect::HardwareVbusDetect},
 };
 use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
@@ -34,7 +40,7 @@ use esb::{
 use mutex::{raw_impls::cs::CriticalSectionRawMutex, BlockingMutex};
 use postcard_rpc::{
     sender_fmt,
-    server::{Dispatch, Sender, Server},
+    server::{Dispatch, Sender, Server, SpawnContext},
 };
 use static_cell::{ConstStaticCell, StaticCell};
 use table::Table;
@@ -43,9 +49,10 @@ use {defmt_rtt as _, panic_probe as _};
 bind_interrupts!(pub struct Irqs {
     USBD => usb::InterruptHandler<USBD>;
     CLOCK_POWER => usb::vbus_detect::InterruptHandler;
+    RNG => rng::InterruptHandler<RNG>;
 });
 
-const MAX_PAYLOAD_SIZE: u8 = 252;
+const MAX_PAYLOAD_SIZE: u8 = ESB_MAX_PAYLOAD;
 
 type IrqStorage = BlockingMutex<CriticalSectionRawMutex, EsbIrq<1024, 1024, Timer0, StatePRX>>;
 static ESB_IRQ: StaticCell<IrqStorage> = StaticCell::new();
@@ -55,8 +62,11 @@ type TimerStorage = BlockingMutex<CriticalSectionRawMutex, IrqTimer<Timer0>>;
 static TIM_IRQ: StaticCell<TimerStorage> = StaticCell::new();
 static TIM_PTR: AtomicPtr<TimerStorage> = AtomicPtr::new(null_mut());
 
+pub mod allow;
 pub mod app;
 pub mod bridge;
+pub mod clock;
+pub mod groups;
 pub mod handlers;
 pub mod table;
 pub mod storage;
@@ -88,6 +98,10 @@ async fn main(spawner: Spawner) {
     config.lfclk_source = LfclkSource::ExternalXtal;
     let p = embassy_nrf::init(config);
 
+    if NETWORK_KEY.is_development() {
+        defmt::warn!("Using the development network key, set CURACAO_NETWORK_KEY when building");
+    }
+
     // Obtain the device ID
     let unique_id = get_unique_id();
 
@@ -164,6 +178,10 @@ async fn main(spawner: Spawner) {
         sender: ESB_SENDER.init(Mutex::new(tx)),
     };
     let table = TABLE.take();
+    static ALLOW: StaticCell<Mutex<ThreadModeRawMutex, Allow>> = StaticCell::new();
+    let allow = ALLOW.init(Mutex::new(Allow::load(Nvmc::new(p.NVMC))));
+    static GROUPS: ConstStaticCell<Mutex<ThreadModeRawMutex, Groups>> =
+        ConstStaticCell::new(Mutex::new(Groups::new()));
 
     // ///////////
     // USB/RPC INIT
@@ -173,13 +191,17 @@ async fn main(spawner: Spawner) {
     let config = usb_config(ser_buf);
     let led = Output::new(p.P0_13, Level::Low, OutputDrive::Standard);
 
-    let context = app::Context {
+    let mut context = app::Context {
         unique_id,
         led,
         esb_sender: esb_sender.clone(),
         table,
+        allow,
+        groups: GROUPS.take(),
     };
 
+    let workers: [_; PROXY_WORKERS] = core::array::from_fn(|_| context.spawn_ctxt());
+
     let (device, tx_impl, rx_impl) =
         app::STORAGE.init_poststation(driver, config, pbufs.tx_buf.as_mut_slice());
     let dispatcher = app::MyApp::new(context, spawner.into());
@@ -195,8 +217,15 @@ async fn main(spawner: Spawner) {
 
     // We need to spawn the USB task so that USB messages are handled by
     // embassy-usb
-    spawner.must_spawn(radio_prx(sender.clone(), esb_sender, table, rx));
+    let rng = Rng::new(p.RNG, Irqs);
+    let button = Input::new(p.P0_11, Pull::Up);
+    spawner.must_spawn(radio_prx(sender.clone(), esb_sender, table, allow, rx, rng));
+    spawner.must_spawn(pairing_button(button, allow));
+    spawner.must_spawn(store_allowlist(allow));
     spawner.must_spawn(usb_task(device));
+    for worker in workers {
+        spawner.must_spawn(proxy_worker(worker, sender.clone()));
+    }
     spawner.must_spawn(logging_task(sender));
 
     // Begin running!
@@ -226,6 +255,32 @@ pub async fn logging_task(sender: Sender<AppTx>) {
     }
 }
 
+/// Pressing the button opens the pairing window
+#[embassy_executor::task]
+pub async fn pairing_button(mut button: Input<'static>, allow: SMutex<Allow>) {
+    let window = Duration::from_secs(BUTTON_PAIRING_SECS.into());
+    loop {
+        button.wait_for_falling_edge().await;
+        allow.lock().await.open_pairing(window);
+        // Debounce, and wait for the release before the next press
+        Timer::after_millis(50).await;
+        button.wait_for_high().await;
+    }
+}
+
+/// Stores nodes paired over the radio, outside of the join that paired them
+#[embassy_executor::task]
+pub async fn store_allowlist(allow: SMutex<Allow>) {
+    loop {
+        allow::UNSTORED.wait().await;
+        // Let the join, and any others that came with it, finish first
+        Timer::after_millis(500).await;
+        if let Err(e) = allow.lock().await.flush() {
+            defmt::warn!("Can't store the allowlist: {:?}", e);
+        }
+    }
+}
+
 fn get_unique_id() -> u64 {
     let lower = FICR.deviceid(0).read() as u64;
     let upper = FICR.deviceid(1).read() as u64;
@@ -237,16 +292,22 @@ async fn radio_prx(
     sender: Sender<AppTx>,
     esb_sender: bridge::Sender<1024>,
     table: SMutex<Table>,
+    allow: SMutex<Allow>,
     recv: EsbAppReceiver<1024>,
+    rng: Rng<'static, RNG>,
 ) {
-    let mut bridge: Bridge<1024, 1024> = Bridge {
+    let mut bridge: Bridge<1024, 1024, FRAG_SIZE, FRAG_CAPACITY> = Bridge {
         table,
         esb_sender,
         recv,
         prpc_sender: sender,
         table_ctr: 0,
         proxy_ctr: 0,
+        reject_ctr: 0,
+        stats_ctr: 0,
         frag_bufs: FRAG_BUFS.take(),
+        rng,
+        allow,
     };
     bridge.run().await;
 }
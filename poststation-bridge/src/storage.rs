@@ -1,6 +1,9 @@
 use core::{slice, sync::atomic::{compiler_fence, Ordering}};
 
 use bootloader_icd::scratch::{BootMessage, BOOT_KEY};
+use bridge_icd::{AllowError, AllowList, MAX_ALLOWED};
+use embassy_nrf::nvmc::Nvmc;
+use embedded_storage::nor_flash::NorFlash;
 use grounded::uninit::GroundedArrayCell;
 use postcard_rpc::Key;
 
@@ -8,6 +11,18 @@ pub const MEM_SCRATCH_SIZE: usize = 1024;
 pub const TTL_FLASH: usize = 1024 * 1024;
 pub const BOOT_FLASH_SIZE: usize = 64 * 1024;
 pub const APP_FLASH_SIZE: usize = TTL_FLASH - BOOT_FLASH_SIZE;
+pub const ALLOW_FLASH_SIZE: usize = 4 * 1024;
+
+/// Marks a page holding an allowlist, anything else reads as an empty list
+const ALLOW_MAGIC: [u8; 4] = *b"CAL1";
+/// The magic, then the largest list: a varint length and the serials,
+/// rounded up to whole flash words
+const ALLOW_BUF_SIZE: usize = (ALLOW_MAGIC.len() + 1 + MAX_ALLOWED * 8).next_multiple_of(4);
+
+#[no_mangle]
+#[used]
+#[link_section = ".allow.ALLOW_FLASH"]
+pub static ALLOW_FLASH: GroundedArrayCell<u8, ALLOW_FLASH_SIZE> = GroundedArrayCell::uninit();
 
 #[no_mangle]
 #[used]
@@ -62,3 +77,33 @@ pub fn write_message(msg: &BootMessage<'_>) -> bool {
         true
     }
 }
+
+pub fn read_allowlist() -> AllowList {
+    let (ptr, _len) = ALLOW_FLASH.get_ptr_len();
+    let sli = unsafe {
+        compiler_fence(Ordering::SeqCst);
+        slice::from_raw_parts(ptr, ALLOW_BUF_SIZE)
+    };
+    let empty = || AllowList {
+        serials: heapless::Vec::new(),
+    };
+    let Some(body) = sli.strip_prefix(&ALLOW_MAGIC) else {
+        return empty();
+    };
+    postcard::from_bytes(body).unwrap_or_else(|_| empty())
+}
+
+/// Replace the stored allowlist. The CPU stalls while the page is erased
+pub fn write_allowlist(nvmc: &mut Nvmc<'_>, list: &AllowList) -> Result<(), AllowError> {
+    let mut buf = [0xFF; ALLOW_BUF_SIZE];
+    let (magic, body) = buf.split_at_mut(ALLOW_MAGIC.len());
+    magic.copy_from_slice(&ALLOW_MAGIC);
+    postcard::to_slice(list, body).map_err(|_| AllowError::Storage)?;
+
+    let (ptr, len) = ALLOW_FLASH.get_ptr_len();
+    let start = ptr as u32;
+    nvmc.erase(start, start + len as u32).map_err(|_| AllowError::Storage)?;
+    nvmc.write(start, &buf).map_err(|_| AllowError::Storage)?;
+    compiler_fence(Ordering::SeqCst);
+    Ok(())
+}
@@ -1,3 +1,7 @@
+use bridge_icd::{
+    link::{LinkKey, Role, Session, IV_LEN},
+    FirmwareVersion, FragStats, LinkParams, LinkStats, NodeLink, NodeRadioStats, RssiStats,
+};
 use embassy_time::{Duration, Instant};
 
 #[derive(Default)]
@@ -8,11 +12,27 @@ pub struct Table {
 pub struct Element {
     serial: [u8; 8],
     last_msg: Instant,
+    firmware: FirmwareVersion,
+    params: LinkParams,
+    node_iv: [u8; IV_LEN],
+    bridge_iv: [u8; IV_LEN],
+    session: Session,
+    stats: LinkStats,
 }
 
 pub enum PipeAlloc {
     New(u8),
     Existing(u8),
+    /// The node asked again with the same `node_iv`, and keeps its session
+    Repeated(u8),
+}
+
+/// The join a node asked for, see [`bridge_icd::link`]
+pub struct Join<'a> {
+    pub link: &'a LinkKey,
+    pub node_iv: [u8; IV_LEN],
+    /// Used for a new session, ignored when the join is repeated
+    pub bridge_iv: [u8; IV_LEN],
 }
 
 impl Table {
@@ -22,12 +42,35 @@ impl Table {
         }
     }
 
-    pub fn allocate_pipe(&mut self, serial: &[u8; 8]) -> Option<PipeAlloc> {
+    /// Find or allocate a pipe for `serial`, recording the parameters it
+    /// joined with. A node that rejoins may have new firmware, so these
+    /// replace whatever was recorded before
+    ///
+    /// Returns the `bridge_iv` of the pipe's session, which is the one from
+    /// before if the join repeats the same `node_iv`
+    pub fn allocate_pipe(
+        &mut self,
+        serial: &[u8; 8],
+        firmware: FirmwareVersion,
+        params: LinkParams,
+        join: Join<'_>,
+    ) -> Option<(PipeAlloc, [u8; IV_LEN])> {
+        let session = || Session::new(join.link, &join.node_iv, &join.bridge_iv, Role::Bridge);
         let mut first_empty = None;
         for (i, s) in self.addr_allocs.iter_mut().enumerate() {
-            if let Some(s) = s.as_ref() {
+            if let Some(s) = s.as_mut() {
                 if s.serial == *serial {
-                    return Some(PipeAlloc::Existing((i as u8) + 1));
+                    s.firmware = firmware;
+                    s.params = params;
+                    let pipe = (i as u8) + 1;
+                    if s.node_iv == join.node_iv {
+                        return Some((PipeAlloc::Repeated(pipe), s.bridge_iv));
+                    }
+                    s.node_iv = join.node_iv;
+                    s.bridge_iv = join.bridge_iv;
+                    s.session = session();
+                    s.stats.joins += 1;
+                    return Some((PipeAlloc::Existing(pipe), join.bridge_iv));
                 }
             } else if first_empty.is_none() {
                 first_empty = Some(i);
@@ -37,13 +80,35 @@ impl Table {
             self.addr_allocs[s] = Some(Element {
                 serial: *serial,
                 last_msg: Instant::now(),
+                firmware,
+                params,
+                node_iv: join.node_iv,
+                bridge_iv: join.bridge_iv,
+                session: session(),
+                stats: LinkStats {
+                    serial: *serial,
+                    pipe: (s as u8) + 1,
+                    rssi: None,
+                    received: 0,
+                    bad_frames: 0,
+                    joins: 1,
+                    frags: FragStats::default(),
+                    node: NodeRadioStats::default(),
+                },
             });
-            Some(PipeAlloc::New((s as u8) + 1))
+            Some((PipeAlloc::New((s as u8) + 1), join.bridge_iv))
         } else {
             None
         }
     }
 
+    /// Drop `serial`'s pipe and session, so it has to join again
+    pub fn remove(&mut self, serial: &[u8; 8]) -> Option<u8> {
+        let pipe = self.pipe_for_serial(serial)?;
+        self.addr_allocs[(pipe - 1) as usize] = None;
+        Some(pipe)
+    }
+
     pub fn pipe_for_serial(&self, serial: &[u8; 8]) -> Option<u8> {
         for (i, s) in self.addr_allocs.iter().enumerate() {
             if let Some(s) = s.as_ref() {
@@ -75,6 +140,51 @@ impl Table {
         Some(slot.serial)
     }
 
+    pub fn params_for_pipe(&self, pipe: u8) -> Option<LinkParams> {
+        if pipe == 0 {
+            return None;
+        }
+        let pipe = pipe - 1;
+        let slot = self.addr_allocs.get(pipe as usize)?;
+        let slot = slot.as_ref()?;
+        Some(slot.params)
+    }
+
+    pub fn session_mut(&mut self, pipe: u8) -> Option<&mut Session> {
+        if pipe == 0 {
+            return None;
+        }
+        let pipe = pipe - 1;
+        let slot = self.addr_allocs.get_mut(pipe as usize)?;
+        let slot = slot.as_mut()?;
+        Some(&mut slot.session)
+    }
+
+    pub fn stats_mut(&mut self, pipe: u8) -> Option<&mut LinkStats> {
+        if pipe == 0 {
+            return None;
+        }
+        let pipe = pipe - 1;
+        let slot = self.addr_allocs.get_mut(pipe as usize)?;
+        let slot = slot.as_mut()?;
+        Some(&mut slot.stats)
+    }
+
+    /// Record the RSSI the radio measured for a packet on `pipe`
+    pub fn record_rssi(&mut self, pipe: u8, rssi: u8) {
+        if let Some(stats) = self.stats_mut(pipe) {
+            stats.rssi = Some(next_rssi(stats.rssi, rssi));
+        }
+    }
+
+    pub fn stats_for_serial(&self, serial: &[u8; 8]) -> Option<LinkStats> {
+        self.addr_allocs
+            .iter()
+            .flatten()
+            .find(|s| s.serial == *serial)
+            .map(|s| s.stats)
+    }
+
     pub fn update_time(&mut self, pipe: u8, serial: &[u8; 8]) -> bool {
         if pipe == 0 {
             return false;
@@ -115,4 +225,46 @@ impl Table {
             }
         }
     }
+
+    pub fn extract_stats(&self, out: &mut heapless::Vec<LinkStats, 7>) {
+        out.clear();
+        for sr in self.addr_allocs.iter().flatten() {
+            let _ = out.push(sr.stats);
+        }
+    }
+
+    pub fn extract_links(&self, out: &mut heapless::Vec<NodeLink, 7>) {
+        out.clear();
+        for (i, s) in self.addr_allocs.iter().enumerate() {
+            if let Some(sr) = s.as_ref() {
+                let _ = out.push(NodeLink {
+                    serial: sr.serial,
+                    pipe: (i as u8) + 1,
+                    firmware: sr.firmware,
+                    params: sr.params,
+                });
+            }
+        }
+    }
+}
+
+/// Fold in the RSSI of a packet, as the radio reports it: the signal
+/// strength in -dBm
+fn next_rssi(old: Option<RssiStats>, rssi: u8) -> RssiStats {
+    let dbm = -(rssi.min(i8::MAX as u8) as i8);
+    match old {
+        None => RssiStats {
+            last: dbm,
+            avg: dbm,
+            worst: dbm,
+        },
+        Some(old) => {
+            let avg = old.avg as i16 + (dbm as i16 - old.avg as i16) / 8;
+            RssiStats {
+                last: dbm,
+                avg: avg as i8,
+                worst: old.worst.min(dbm),
+            }
+        }
+    }
 }
@@ -1,6 +1,5 @@
 use crate::{
     app::{AppTx, Context, TaskContext},
-    impls::EsbTx,
     smartled::smartled,
     storage::write_message,
 };
@@ -66,7 +65,7 @@ pub fn handle_dummy(
     _context: &mut Context,
     _header: VarHeader,
     _arg: Dummy,
-    _sender: &Sender<EsbTx>,
+    _sender: &Sender<AppTx>,
 ) {
     // defmt::info!("Handled dummy via postcard-rpc dispatch {:?}", arg.data)
 }
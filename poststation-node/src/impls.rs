@@ -1,90 +1,266 @@
+use core::{
+    cell::Cell,
+    sync::atomic::{AtomicBool, AtomicU32, Ordering},
+};
+
+use bootloader_icd::scratch::BootMessage;
 use bridge_icd::{
-    extract_topic2,
+    codec::{encode_topic, take_topic},
+    link::{Opener, Sealer, CIPHER, CTR_LEN},
+    time::{ClockSync, Sample},
     postcard_rpc::{
-        header::{VarHeader, VarKeyKind, VarSeq},
+        header::{VarHeader, VarKey, VarKeyKind, VarSeq},
         server::{
             AsWireRxErrorKind, AsWireTxErrorKind, WireRx, WireRxErrorKind, WireTx, WireTxErrorKind,
         },
+        standard_icd::LoggingTopic,
+        Topic,
     },
-    write_topic2, B2NTopic, Bridge2Node, FragBuf, N2BTopic, Node2Bridge,
+    frag_mask, pkg_version, B2NTopic, Bridge2Node, Capabilities, FragAck,
+    FragBuf, FragHeader, JoinParams, LinkParams, N2BTopic, Node2Bridge, NodeRadioStats, TooLarge,
+    ESB_MAX_PAYLOAD, LINK_KEY, PROTOCOL_VERSION, PROXY_ACK_TIMEOUT_MS, PROXY_CRC, PROXY_RETRIES,
 };
-use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex, mutex::Mutex};
+use cortex_m::peripheral::SCB;
+use embassy_sync::{
+    blocking_mutex::{raw::ThreadModeRawMutex, Mutex as BlockingMutex},
+    channel::Channel,
+    mutex::Mutex,
+    signal::Signal,
+};
+use embassy_time::{Duration, Instant, WithTimeout};
 use esb::{
     app::{EsbAppReceiver, EsbAppSender},
     EsbHeader,
 };
 use serde::Serialize;
-use static_cell::{ConstStaticCell, StaticCell};
+use static_cell::StaticCell;
+
+use crate::storage::write_message;
+
+/// What this node supports, offered to the bridge when joining, for a
+/// `FragBuf<FRAG, CAP>`
+pub fn node_params<const FRAG: usize, const CAP: usize>() -> JoinParams {
+    JoinParams {
+        protocol: PROTOCOL_VERSION,
+        firmware: pkg_version!(),
+        max_frag: FragBuf::<FRAG, CAP>::MAX_FRAG,
+        frag_capacity: FragBuf::<FRAG, CAP>::CAPACITY,
+        caps: Capabilities::NONE,
+    }
+}
+
+/// Set whenever an authenticated packet arrives from the bridge
+pub static HEARD_BRIDGE: AtomicBool = AtomicBool::new(false);
+
+/// Network time, from the bridge's keepalive replies
+static CLOCK: BlockingMutex<ThreadModeRawMutex, Cell<ClockSync>> =
+    BlockingMutex::new(Cell::new(ClockSync::new()));
+
+/// The wall clock, in microseconds since the Unix epoch, once the bridge has
+/// passed on the host's time. Use this to timestamp published data
+pub fn now_us() -> Option<u64> {
+    CLOCK.lock(|c| c.get().now(Instant::now().as_micros()))
+}
+
+/// Start over with a new session
+///
+/// The session is held by both radio tasks, so this reboots to join again.
+/// The bootloader is told to boot straight back into the app, as this isn't
+/// a crash
+pub fn rejoin() -> ! {
+    write_message(&BootMessage::JustBoot);
+    SCB::sys_reset();
+}
+
+/// Counted by the RADIO interrupt, and reported in keepalives
+pub static RETRANSMITS: AtomicU32 = AtomicU32::new(0);
+pub static MAX_ATTEMPTS: AtomicU32 = AtomicU32::new(0);
+
+/// Sequence number of the next log message
+static LOG_SEQ: AtomicU32 = AtomicU32::new(0);
+
+/// The latest ack from the bridge for the message we are sending
+static BRIDGE_ACKS: Signal<ThreadModeRawMutex, FragAck> = Signal::new();
+
+/// Held while sending a whole message, so fragments of two messages never mix
+static SEND_LOCK: Mutex<ThreadModeRawMutex, ()> = Mutex::new(());
+
+/// A reassembled message from the bridge, waiting for the server
+pub struct Inbound<const CAP: usize> {
+    data: [u8; CAP],
+    len: usize,
+}
+
+/// Hands messages from [`RadioRx`] to [`EsbRx`]
+pub type Inbox<const CAP: usize> = Channel<ThreadModeRawMutex, Inbound<CAP>, 1>;
 
 struct EsbTxInner {
     sender: EsbAppSender<1024>,
+    sealer: Sealer,
     ctr: u16,
+    msg_id: u16,
     pipe: u8,
     pid: u8,
+    /// The latest keepalive's `seq`, and when it went out
+    keepalive: (u8, Instant),
 }
 
+/// Sends to the bridge, serializing messages of up to `CAP` bytes
 #[derive(Clone)]
-pub struct EsbTx {
+pub struct EsbTx<const CAP: usize> {
     inner: &'static Mutex<ThreadModeRawMutex, EsbTxInner>,
     serial: u64,
+    pipe: u8,
+    params: LinkParams,
 }
 
-impl EsbTx {
-    pub fn new(sender: EsbAppSender<1024>, serial: u64, pipe: u8) -> Self {
+impl<const CAP: usize> EsbTx<CAP> {
+    pub fn new(
+        sender: EsbAppSender<1024>,
+        serial: u64,
+        pipe: u8,
+        params: LinkParams,
+        sealer: Sealer,
+    ) -> Self {
         static SENDER: StaticCell<Mutex<ThreadModeRawMutex, EsbTxInner>> = StaticCell::new();
         let inner = SENDER.init(Mutex::new(EsbTxInner {
             sender,
+            sealer,
             ctr: 0,
+            msg_id: 0,
             pipe,
             pid: 0,
+            keepalive: (0, Instant::MIN),
         }));
-        EsbTx { inner, serial }
+        EsbTx {
+            inner,
+            serial,
+            pipe,
+            params,
+        }
     }
 
-    pub async fn send_keepalive(&self) {
-        let mut guard = self.inner.lock().await;
-        let pid = guard.pid();
-        let pipe = guard.pipe;
-        let header = EsbHeader::new(252, pid, pipe, false).unwrap();
-        let mut grant = guard.sender.wait_grant_packet(header).await.unwrap();
-
-        // First write the proxy header
-        let seq_no = guard.ctr();
-        let Some(used) = write_topic2::<N2BTopic>(
-            &Node2Bridge::Keepalive {
-                serial: self.serial.to_le_bytes(),
+    pub async fn send_keepalive(&self) -> Result<(), EsbTxError> {
+        let seq = {
+            let guard = self.inner.lock().await;
+            guard.keepalive.0.wrapping_add(1)
+        };
+        let msg = Node2Bridge::Keepalive {
+            serial: self.serial.to_le_bytes(),
+            radio: NodeRadioStats {
+                retransmits: RETRANSMITS.load(Ordering::Relaxed),
+                max_attempts: MAX_ATTEMPTS.load(Ordering::Relaxed),
             },
-            seq_no,
-            &mut grant,
-        ) else {
-            panic!();
+            seq,
         };
-        grant.commit(used);
-        guard.sender.start_tx();
+        self.send_packet(&msg, &[]).await?;
+        // The reply rides on the ack of a later packet, so this is recorded
+        // well before it can arrive
+        self.inner.lock().await.keepalive = (seq, Instant::now());
+        Ok(())
+    }
+
+    pub async fn send_nop(&self) -> Result<(), EsbTxError> {
+        self.send_packet(&Node2Bridge::Nop, &[]).await
+    }
+
+    pub async fn send_ack(&self, ack: FragAck) -> Result<(), EsbTxError> {
+        self.send_packet(&Node2Bridge::ProxyAck { ack }, &[]).await
     }
 
-    pub async fn send_nop(&self) {
+    /// Seal and queue one packet: `msg`, then `tail` for proxy fragments
+    async fn send_packet(&self, msg: &Node2Bridge, tail: &[u8]) -> Result<(), EsbTxError> {
         let mut guard = self.inner.lock().await;
         let pid = guard.pid();
         let pipe = guard.pipe;
-        let header = EsbHeader::new(252, pid, pipe, false).unwrap();
+        let header = EsbHeader::new(ESB_MAX_PAYLOAD, pid, pipe, false).unwrap();
         let mut grant = guard.sender.wait_grant_packet(header).await.unwrap();
 
-        // First write the proxy header
         let seq_no = guard.ctr();
-        let Some(used) = write_topic2::<N2BTopic>(&Node2Bridge::Nop, seq_no, &mut grant) else {
-            panic!();
+        let Ok(used) = encode_topic::<N2BTopic>(LINK_KEY, seq_no, msg, &mut grant[CTR_LEN..]) else {
+            return Err(EsbTxError::Serialize);
         };
-        grant.commit(used);
+        let len = used + tail.len();
+        let Some(dest) = grant.get_mut(CTR_LEN + used..).and_then(|g| g.get_mut(..tail.len())) else {
+            return Err(EsbTxError::Serialize);
+        };
+        dest.copy_from_slice(tail);
+
+        // The counter lasts 2^32 packets, a long time at one every 100ms.
+        // Should it run out, start over with a new session
+        let Ok(ttl) = guard.sealer.seal(&CIPHER, &mut grant, len) else {
+            rejoin();
+        };
+        grant.commit(ttl);
         guard.sender.start_tx();
+        Ok(())
+    }
+
+    /// Send a message as fragments of the negotiated size, resending any the
+    /// bridge doesn't acknowledge
+    async fn send_frags(&self, msg: &[u8]) -> Result<(), EsbTxError> {
+        let _sending = SEND_LOCK.lock().await;
+        let chunks = self.params.frag_count(msg.len()).map_err(EsbTxError::TooLarge)?;
+        let msg_id = self.inner.lock().await.msg_id();
+        let crc = PROXY_CRC.checksum(msg);
+        BRIDGE_ACKS.reset();
+
+        let mut missing = frag_mask(chunks);
+        for _attempt in 0..=PROXY_RETRIES {
+            self.send_parts(msg, msg_id, chunks, crc, missing).await?;
+
+            // Keep waiting as long as acks keep arriving
+            let tout = Duration::from_millis(PROXY_ACK_TIMEOUT_MS);
+            while let Ok(ack) = BRIDGE_ACKS.wait().with_timeout(tout).await {
+                missing = ack.missing(msg_id, chunks);
+                if missing == 0 {
+                    return Ok(());
+                }
+            }
+        }
+        Err(EsbTxError::Undelivered)
+    }
+
+    async fn send_parts(
+        &self,
+        msg: &[u8],
+        msg_id: u16,
+        chunks: u8,
+        crc: u32,
+        parts: u64,
+    ) -> Result<(), EsbTxError> {
+        let frag = self.params.max_frag as usize;
+        for (i, ch) in msg.chunks(frag).enumerate() {
+            if parts & (1 << i) == 0 {
+                continue;
+            }
+            let hdr = FragHeader {
+                msg_id,
+                part: i as u8,
+                ttl_parts: chunks,
+                crc,
+            };
+            self.send_packet(&Node2Bridge::Proxy { hdr }, ch).await?;
+        }
+        Ok(())
     }
 }
 
-pub enum EsbTxError {}
+pub enum EsbTxError {
+    /// The bridge didn't acknowledge every fragment within the retry budget
+    Undelivered,
+    /// The message is longer than the bridge can reassemble
+    TooLarge(TooLarge),
+    /// The message didn't fit in the `CAP` byte send buffer, or a packet
+    Serialize,
+}
 
 impl AsWireTxErrorKind for EsbTxError {
     fn as_kind(&self) -> WireTxErrorKind {
-        WireTxErrorKind::Other
+        match self {
+            EsbTxError::Undelivered => WireTxErrorKind::Timeout,
+            EsbTxError::TooLarge(_) | EsbTxError::Serialize => WireTxErrorKind::Other,
+        }
     }
 }
 
@@ -95,6 +271,12 @@ impl EsbTxInner {
         VarSeq::Seq2(n)
     }
 
+    fn msg_id(&mut self) -> u16 {
+        let n = self.msg_id;
+        self.msg_id = self.msg_id.wrapping_add(1);
+        n
+    }
+
     fn pid(&mut self) -> u8 {
         let n = self.pid;
         self.pid = self.pid.wrapping_add(1);
@@ -102,7 +284,7 @@ impl EsbTxInner {
     }
 }
 
-impl WireTx for EsbTx {
+impl<const CAP: usize> WireTx for EsbTx<CAP> {
     type Error = EsbTxError;
 
     async fn send<T: Serialize + ?Sized>(
@@ -110,94 +292,74 @@ impl WireTx for EsbTx {
         hdr: VarHeader,
         msg: &T,
     ) -> Result<(), Self::Error> {
-        // todo: bad
-        let mut buf = [0u8; 1024];
+        let mut buf = [0u8; CAP];
         let Some((hdrb, remain)) = hdr.write_to_slice(&mut buf) else {
-            panic!()
+            return Err(EsbTxError::Serialize);
         };
         let Ok(used) = postcard::to_slice(msg, remain) else {
-            // defmt::warn!("skipping message badser");
-            return Ok(());
+            return Err(EsbTxError::Serialize);
         };
         let ttl = hdrb.len() + used.len();
         let used = &buf[..ttl];
 
-        let chunks = (used.len() + 127) / 128;
-        for (i, ch) in used.chunks(128).enumerate() {
-            let mut guard = self.inner.lock().await;
-            let pid = guard.pid();
-            let pipe = guard.pipe;
-            let header = EsbHeader::new(252, pid, pipe, false).unwrap();
-            let mut grant = guard.sender.wait_grant_packet(header).await.unwrap();
-
-            // First write the proxy header
-            let seq_no = guard.ctr();
-            let Some(used) = write_topic2::<N2BTopic>(
-                &Node2Bridge::Proxy {
-                    part: i as u8,
-                    ttl_parts: chunks as u8,
-                },
-                seq_no,
-                &mut grant,
-            ) else {
-                panic!()
-            };
-            let (prefix, remain) = grant.split_at_mut(used);
-
-            remain[..ch.len()].copy_from_slice(ch);
-
-            let ttl = prefix.len() + ch.len();
-            grant.commit(ttl);
-            guard.sender.start_tx();
-        }
-
-        Ok(())
+        self.send_frags(used).await
     }
 
     async fn send_raw(&self, buf: &[u8]) -> Result<(), Self::Error> {
-        let chunks = (buf.len() + 127) / 128;
-        for (i, ch) in buf.chunks(128).enumerate() {
-            let mut guard = self.inner.lock().await;
-            let pid = guard.pid();
-            let pipe = guard.pipe;
-            let header = EsbHeader::new(252, pid, pipe, false).unwrap();
-            let mut grant = guard.sender.wait_grant_packet(header).await.unwrap();
-
-            // First write the proxy header
-            let seq_no = guard.ctr();
-            let Some(used) = write_topic2::<N2BTopic>(
-                &Node2Bridge::Proxy {
-                    part: i as u8,
-                    ttl_parts: chunks as u8,
-                },
-                seq_no,
-                &mut grant,
-            ) else {
-                panic!()
-            };
-            let (prefix, remain) = grant.split_at_mut(used);
-
-            remain[..ch.len()].copy_from_slice(ch);
-
-            let ttl = prefix.len() + ch.len();
-            grant.commit(ttl);
-            guard.sender.start_tx();
-        }
-
-        Ok(())
+        self.send_frags(buf).await
     }
 
-    async fn send_log_str(&self, _kkind: VarKeyKind, _s: &str) -> Result<(), Self::Error> {
-        // defmt::error!("Not implemented");
-        Ok(())
+    /// Logs are sent like any other message on [`LoggingTopic`], and the
+    /// bridge passes them on to the host like the rest, so they show up in
+    /// poststation's log of this node
+    async fn send_log_str(&self, kkind: VarKeyKind, s: &str) -> Result<(), Self::Error> {
+        let key = match kkind {
+            VarKeyKind::Key1 => VarKey::Key1(LoggingTopic::TOPIC_KEY1),
+            VarKeyKind::Key2 => VarKey::Key2(LoggingTopic::TOPIC_KEY2),
+            VarKeyKind::Key4 => VarKey::Key4(LoggingTopic::TOPIC_KEY4),
+            VarKeyKind::Key8 => VarKey::Key8(LoggingTopic::TOPIC_KEY),
+        };
+        let seq_no = VarSeq::Seq4(LOG_SEQ.fetch_add(1, Ordering::Relaxed));
+        self.send(VarHeader { key, seq_no }, s).await
     }
 
+    /// Formatted into a `CAP` byte buffer first, anything past that is cut off
     async fn send_log_fmt<'a>(
         &self,
-        _kkind: VarKeyKind,
-        _a: core::fmt::Arguments<'a>,
+        kkind: VarKeyKind,
+        a: core::fmt::Arguments<'a>,
     ) -> Result<(), Self::Error> {
-        // defmt::error!("Not implemented");
+        let mut buf = [0u8; CAP];
+        let mut w = LogWriter {
+            buf: &mut buf,
+            used: 0,
+        };
+        let _ = core::fmt::write(&mut w, a);
+        let used = w.used;
+        // `LogWriter` only ever stops at a char boundary
+        let s = core::str::from_utf8(&buf[..used]).unwrap_or_default();
+        self.send_log_str(kkind, s).await
+    }
+}
+
+/// Formats into a fixed buffer, dropping whatever doesn't fit
+struct LogWriter<'a> {
+    buf: &'a mut [u8],
+    used: usize,
+}
+
+impl core::fmt::Write for LogWriter<'_> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let room = self.buf.len() - self.used;
+        let mut take = s.len().min(room);
+        while !s.is_char_boundary(take) {
+            take -= 1;
+        }
+        self.buf[self.used..][..take].copy_from_slice(&s.as_bytes()[..take]);
+        self.used += take;
+        if take < s.len() {
+            return Err(core::fmt::Error);
+        }
         Ok(())
     }
 }
@@ -210,79 +372,127 @@ impl AsWireRxErrorKind for EsbRxError {
     }
 }
 
-pub struct EsbRx {
-    inner: EsbAppReceiver<1024>,
-    serial: u64,
-    pipe: u8,
-    frag_buf: &'static mut FragBuf,
+/// Receives messages reassembled by [`RadioRx`]
+pub struct EsbRx<const CAP: usize> {
+    inbox: &'static Inbox<CAP>,
 }
 
-static FRAG_BUF: ConstStaticCell<FragBuf> = ConstStaticCell::new(FragBuf::new());
-
-impl EsbRx {
-    pub fn new(inner: EsbAppReceiver<1024>, serial: u64, pipe: u8) -> Self {
-        Self {
-            inner,
-            serial,
-            pipe,
-            frag_buf: FRAG_BUF.take(),
-        }
+impl<const CAP: usize> EsbRx<CAP> {
+    pub fn new(inbox: &'static Inbox<CAP>) -> Self {
+        Self { inbox }
     }
 }
 
-impl WireRx for EsbRx {
+impl<const CAP: usize> WireRx for EsbRx<CAP> {
     type Error = EsbRxError;
 
     async fn receive<'a>(&mut self, buf: &'a mut [u8]) -> Result<&'a mut [u8], Self::Error> {
+        loop {
+            let msg = self.inbox.receive().await;
+            let Some(used) = buf.get_mut(..msg.len) else {
+                continue;
+            };
+            used.copy_from_slice(&msg.data[..msg.len]);
+            return Ok(used);
+        }
+    }
+}
+
+/// Reads every packet from the bridge
+///
+/// This runs in its own task rather than in [`EsbRx`], so acks for messages
+/// we are sending still arrive while the server is busy handling a request.
+pub struct RadioRx<const FRAG: usize, const CAP: usize> {
+    inner: EsbAppReceiver<1024>,
+    tx: EsbTx<CAP>,
+    opener: Opener,
+    frag_buf: &'static mut FragBuf<FRAG, CAP>,
+    inbox: &'static Inbox<CAP>,
+}
+
+impl<const FRAG: usize, const CAP: usize> RadioRx<FRAG, CAP> {
+    pub fn new(
+        inner: EsbAppReceiver<1024>,
+        tx: EsbTx<CAP>,
+        opener: Opener,
+        frag_buf: &'static mut FragBuf<FRAG, CAP>,
+        inbox: &'static Inbox<CAP>,
+    ) -> Self {
+        Self {
+            inner,
+            tx,
+            opener,
+            frag_buf,
+            inbox,
+        }
+    }
+
+    pub async fn run(&mut self) -> ! {
+        let serial = self.tx.serial.to_le_bytes();
         loop {
             let grant = self.inner.wait_read_packet().await;
-            if grant.pipe() != self.pipe {
+            if grant.pipe() != self.tx.pipe || grant.is_empty() {
                 grant.release();
                 continue;
             }
-            if grant.is_empty() {
-                grant.release();
+            // Anything that doesn't open was forged, replayed, or meant for
+            // an earlier session, and is dropped
+            let mut buf = [0u8; ESB_MAX_PAYLOAD as usize];
+            let opened = self.opener.open(&CIPHER, &grant, &mut buf);
+            grant.release();
+            let Ok(plain) = opened else {
                 continue;
-            }
-            let Some(e) = extract_topic2::<B2NTopic>(&grant) else {
-                // defmt::warn!("WHAT THE HELL {=usize}", grant.len());
-                grant.release();
+            };
+            HEARD_BRIDGE.store(true, Ordering::Relaxed);
+
+            let Ok(e) = take_topic::<B2NTopic>(plain) else {
                 continue;
             };
             match e.msg {
-                Bridge2Node::InitializeAck { serial, use_pipe } => {
-                    if serial != self.serial.to_le_bytes() {
+                Bridge2Node::InitializeAck { .. } => {}
+                Bridge2Node::Keepalive {
+                    serial: s,
+                    seq,
+                    time_us,
+                } => {
+                    if s != serial {
                         panic!();
                     }
-                    if use_pipe != self.pipe {
-                        panic!();
+                    let (sent_seq, sent_at) = self.tx.inner.lock().await.keepalive;
+                    if let (Some(remote_us), true) = (time_us, seq == sent_seq) {
+                        let sample = Sample {
+                            local_us: sent_at.as_micros(),
+                            remote_us,
+                        };
+                        CLOCK.lock(|c| {
+                            let mut clock = c.get();
+                            clock.update(sample);
+                            c.set(clock);
+                        });
                     }
                 }
-                Bridge2Node::Keepalive { serial } => {
-                    if serial != self.serial.to_le_bytes() {
-                        panic!();
-                    }
-                }
-                Bridge2Node::Proxy { part, ttl_parts } => {
-                    if ttl_parts == 0 {
-                        // defmt::warn!("Bad frag {=u8}, {=u8}", part, ttl_parts);
+                Bridge2Node::Proxy { hdr } => {
+                    // If the server hasn't taken the last message yet, drop
+                    // this fragment unacknowledged and let the bridge resend it
+                    if self.inbox.is_full() {
                         continue;
                     }
-                    let to_fwd = if part == 0 && ttl_parts == 1 {
-                        Some(e.remain)
-                    } else {
-                        self.frag_buf.handle_frag(part, ttl_parts, e.remain)
-                    };
-                    if let Some(to_fwd) = to_fwd {
-                        let used = &mut buf[..to_fwd.len()];
-                        used.copy_from_slice(to_fwd);
-                        grant.release();
-                        return Ok(used);
+                    let frag = self.tx.params.max_frag as usize;
+                    if let Ok(Some(msg)) = self.frag_buf.handle_frag(hdr, frag, e.remain) {
+                        let mut inbound = Inbound {
+                            data: [0u8; CAP],
+                            len: msg.len(),
+                        };
+                        inbound.data[..msg.len()].copy_from_slice(msg);
+                        let _ = self.inbox.try_send(inbound);
                     }
+                    let ack = self.frag_buf.ack();
+                    let _ = self.tx.send_ack(ack).await;
                 }
-                Bridge2Node::Reset => panic!(),
+                Bridge2Node::ProxyAck { ack } => BRIDGE_ACKS.signal(ack),
+                Bridge2Node::InitializeReject { .. } => {}
+                Bridge2Node::Reset => rejoin(),
             }
-            grant.release();
         }
     }
 }
@@ -13,18 +13,24 @@ use core::{
     sync::atomic::{AtomicBool, AtomicPtr, Ordering},
 };
 
-use bootloader_icd::scratch::BootMessage;
+use app::{AppFragBuf, AppRx, AppTx, FRAG_CAPACITY, FRAG_SIZE, NETWORK_KEY};
+use bootloader_icd::scratch::{BootMessage, FaultRegs};
 use bridge_icd::{
-    extract_topic2, postcard_rpc::header::VarSeq, write_topic2, B2NTopic, Bridge2Node, N2BTopic,
-    Node2Bridge,
+    codec::take_topic,
+    link::{Hello, Role, Session, Welcome, CIPHER, IV_LEN},
+    seal_join_request, B2NTopic, Bridge2Node, JoinReject, LinkParams, Node2Bridge,
+    ESB_MAX_PAYLOAD,
 };
 use cortex_m::peripheral::NVIC;
 use embassy_executor::Spawner;
 use embassy_nrf::{
+    bind_interrupts,
     config::{Config, HfclkSource},
     interrupt,
     pac::{Interrupt, FICR},
+    peripherals::{self, RNG},
     pwm::{self, Prescaler, SequenceLoad, SequencePwm},
+    rng::{self, Rng},
 };
 use embassy_nrf::{
     gpio::{Level, Output, OutputDrive},
@@ -37,7 +43,7 @@ use esb::{
     peripherals::{PtrTimer, Timer0},
     Addresses, ConfigBuilder, Error, EsbApp, EsbBuffer, EsbHeader, EsbIrq, IrqTimer,
 };
-use impls::{EsbRx, EsbTx};
+use impls::{node_params, rejoin, Inbox, RadioRx, HEARD_BRIDGE, MAX_ATTEMPTS, RETRANSMITS};
 use mutex::{raw_impls::cs::CriticalSectionRawMutex, BlockingMutex};
 use node_icd::RGB8;
 use postcard_rpc::server::{Dispatch, Server};
@@ -47,6 +53,10 @@ use storage::write_message;
 
 const MAX_PAYLOAD_SIZE: u8 = 64;
 
+bind_interrupts!(pub struct Irqs {
+    RNG => rng::InterruptHandler<peripherals::RNG>;
+});
+
 type IrqStorage = BlockingMutex<CriticalSectionRawMutex, EsbIrq<1024, 1024, Timer0, StatePTX>>;
 static ESB_IRQ: StaticCell<IrqStorage> = StaticCell::new();
 static IRQ_PTR: AtomicPtr<IrqStorage> = AtomicPtr::new(null_mut());
@@ -82,7 +92,7 @@ async fn main(spawner: Spawner) {
         .maximum_transmit_attempts(32)
         .retransmit_delay(10_000)
         .wait_for_ack_timeout(1500)
-        .max_payload_size(252)
+        .max_payload_size(ESB_MAX_PAYLOAD)
         .check()
         .unwrap();
     let (mut esb_app, esb_irq, esb_timer) = BUFFER
@@ -106,13 +116,23 @@ async fn main(spawner: Spawner) {
     }
     let serial = get_unique_id();
     // defmt::info!("Getting addr pipe");
-    let pipe = get_pipe(&mut esb_app, serial).await;
+    let mut rng = Rng::new(p.RNG, Irqs);
+    let (pipe, params, session) = get_pipe(&mut esb_app, serial, &mut rng).await;
     // defmt::info!("Got pipe addr {=u8}", pipe);
 
     let (tx, rx) = esb_app.split();
-    let esb_tx = EsbTx::new(tx, serial, pipe);
-    let esb_rx = EsbRx::new(rx, serial, pipe);
-
+    static INBOX: Inbox<FRAG_CAPACITY> = Inbox::<FRAG_CAPACITY>::new();
+    static FRAG_BUF: ConstStaticCell<AppFragBuf> = ConstStaticCell::new(AppFragBuf::new());
+    let esb_tx = AppTx::new(tx, serial, pipe, params, session.tx);
+    let esb_rx = AppRx::new(&INBOX);
+
+    spawner.must_spawn(radio_rx(RadioRx::new(
+        rx,
+        esb_tx.clone(),
+        session.rx,
+        FRAG_BUF.take(),
+        &INBOX,
+    )));
     spawner.must_spawn(keepalive(esb_tx.clone()));
 
     let mut config = pwm::Config::default();
@@ -179,27 +199,61 @@ async fn main(spawner: Spawner) {
 }
 
 #[embassy_executor::task]
-async fn keepalive(esb_tx: EsbTx) {
+async fn radio_rx(mut radio: RadioRx<FRAG_SIZE, FRAG_CAPACITY>) {
+    radio.run().await
+}
+
+/// Keepalive periods without hearing from the bridge before we join again
+const MISSED_KEEPALIVES: u8 = 5;
+
+#[embassy_executor::task]
+async fn keepalive(esb_tx: AppTx) {
     // TODO: some kind of jitter?
     let mut ticker = Ticker::every(Duration::from_millis(100));
     let mut last_ka = Instant::now();
+    let mut missed = 0;
     loop {
         ticker.next().await;
         if last_ka.elapsed() >= Duration::from_secs(3) {
-            esb_tx.send_keepalive().await;
+            // The bridge answers every keepalive. If it has forgotten us, or
+            // restarted, nothing it sends opens any more, so start over
+            if HEARD_BRIDGE.swap(false, Ordering::Relaxed) {
+                missed = 0;
+            } else {
+                missed += 1;
+                if missed >= MISSED_KEEPALIVES {
+                    rejoin();
+                }
+            }
+            let _ = esb_tx.send_keepalive().await;
             last_ka = Instant::now();
         } else {
-            esb_tx.send_nop().await;
+            let _ = esb_tx.send_nop().await;
         }
     }
 }
 
-async fn get_pipe(esb_app: &mut EsbApp<1024, 1024>, unique_id: u64) -> u8 {
-    let mut ctr = 0u16;
+/// Join the bridge, returning our pipe, the negotiated link parameters, and
+/// the session everything else is sealed with
+async fn get_pipe(
+    esb_app: &mut EsbApp<1024, 1024>,
+    unique_id: u64,
+    rng: &mut Rng<'static, RNG>,
+) -> (u8, LinkParams, Session) {
+    let serial = unique_id.to_le_bytes();
+    let key = NETWORK_KEY.node_key(&serial);
+    let new_hello = |rng: &mut Rng<'static, RNG>| {
+        let mut node_iv = [0u8; IV_LEN];
+        rng.blocking_fill_bytes(&mut node_iv);
+        Hello { serial, node_iv }
+    };
+    // Keep the same node_iv until the bridge answers, as its reply arrives
+    // with the ack of our next attempt. Every attempt with one node_iv is
+    // sealed under the same nonce, see `seal_join_request`
+    let mut hello = new_hello(rng);
     let mut pids = (0..4).cycle();
     loop {
         let pid = pids.next().unwrap();
-        ctr = ctr.wrapping_add(1);
 
         let esb_header = EsbHeader::build()
             .max_payload(MAX_PAYLOAD_SIZE)
@@ -213,32 +267,61 @@ async fn get_pipe(esb_app: &mut EsbApp<1024, 1024>, unique_id: u64) -> u8 {
 
         let mut packet = esb_app.grant_packet(esb_header).unwrap();
         let msg = Node2Bridge::Initialize {
-            serial: unique_id.to_le_bytes(),
+            serial,
+            params: node_params::<FRAG_SIZE, FRAG_CAPACITY>(),
         };
-        let used = write_topic2::<N2BTopic>(&msg, VarSeq::Seq2(ctr), &mut packet).unwrap();
-        packet.commit(used);
+        let ttl = seal_join_request(&CIPHER, &key, &hello, &msg, &mut packet).unwrap();
+        packet.commit(ttl);
         esb_app.start_tx();
 
         // Did we receive any packet ?
         let fut = esb_app.wait_read_packet();
         let tofut = fut.with_timeout(Duration::from_secs(1));
-        if let Ok(response) = tofut.await {
-            if let Some(extract) = extract_topic2::<B2NTopic>(&response) {
-                match extract.msg {
-                    Bridge2Node::InitializeAck { serial, use_pipe } => {
-                        if serial == unique_id.to_le_bytes() {
-                            response.release();
-                            return use_pipe;
-                        }
-                    }
-                    Bridge2Node::Keepalive { .. } => {}
-                    Bridge2Node::Proxy { .. } => {}
-                    Bridge2Node::Reset => {}
+        let Ok(response) = tofut.await else {
+            continue;
+        };
+        let mut buf = [0u8; ESB_MAX_PAYLOAD as usize];
+        let welcome = Welcome::peek(&response);
+        let plain = match welcome {
+            Some(w) => w.open(&CIPHER, &key, &response, &mut buf).ok(),
+            None => None,
+        };
+        response.release();
+
+        // Replies that don't open, or echo another node_iv, aren't for this attempt
+        let extract = plain.and_then(|plain| take_topic::<B2NTopic>(plain).ok());
+        if let (Some(welcome), Some(extract)) = (welcome, extract) {
+            match extract.msg {
+                Bridge2Node::InitializeAck {
+                    serial: s,
+                    use_pipe,
+                    params,
+                    node_iv,
+                } if s == serial && node_iv == hello.node_iv => {
+                    let session =
+                        Session::new(&key, &hello.node_iv, &welcome.bridge_iv, Role::Node);
+                    return (use_pipe, params, session);
+                }
+                Bridge2Node::InitializeReject {
+                    serial: s,
+                    reason,
+                    node_iv,
+                } if s == serial && node_iv == hello.node_iv => {
+                    // A full table may free up, but an incompatible
+                    // bridge won't change until one side is reflashed,
+                    // so back off before asking again
+                    let wait = match reason {
+                        JoinReject::TableFull => 1,
+                        _ => 30,
+                    };
+                    Timer::after_secs(wait).await;
+                    hello = new_hello(rng);
+                    continue;
                 }
+                _ => {}
             }
-            Timer::after_millis(250).await;
-            response.release();
         }
+        Timer::after_millis(250).await;
     }
 }
 
@@ -248,9 +331,16 @@ fn RADIO() {
     let r = unsafe { ptr.as_ref() }.unwrap();
     let s = r.with_lock(|state| match state.radio_interrupt() {
         Ok(s) => Some(s),
-        Err(Error::MaximumAttempts) => None,
+        Err(Error::MaximumAttempts) => {
+            MAX_ATTEMPTS.fetch_add(1, Ordering::Relaxed);
+            None
+        }
         Err(_e) => panic!(),
     });
+    // Waiting to retransmit means the last attempt went unacked
+    if let Some(StatePTX::TransmitterWaitRetransmit) = s {
+        RETRANSMITS.fetch_add(1, Ordering::Relaxed);
+    }
     if let Some(_s) = s {
         // match s {
         //     StatePTX::IdleTx => defmt::info!("IdleTx"),
@@ -291,7 +381,7 @@ fn panic_handler(info: &PanicInfo<'_>) -> ! {
         };
         writeln!(&mut writer, "{info}").ok();
         let len = writer.written;
-        write_message(&BootMessage::BootPanicked {
+        write_message(&BootMessage::AppPanicked {
             uptime: Instant::now().as_ticks(),
             reason: &buf[..len],
         });
@@ -301,6 +391,27 @@ fn panic_handler(info: &PanicInfo<'_>) -> ! {
     unreachable!()
 }
 
+/// Record the faulting registers so the host can symbolize the crash
+#[cortex_m_rt::exception]
+unsafe fn HardFault(ef: &cortex_m_rt::ExceptionFrame) -> ! {
+    let scb = &*cortex_m::peripheral::SCB::PTR;
+    let regs = FaultRegs {
+        pc: ef.pc(),
+        lr: ef.lr(),
+        sp: ef as *const cortex_m_rt::ExceptionFrame as u32,
+        xpsr: ef.xpsr(),
+        cfsr: scb.cfsr.read(),
+        hfsr: scb.hfsr.read(),
+        mmfar: scb.mmfar.read(),
+        bfar: scb.bfar.read(),
+    };
+    write_message(&BootMessage::AppFaulted {
+        uptime: Instant::now().as_ticks(),
+        regs,
+    });
+    cortex_m::peripheral::SCB::sys_reset();
+}
+
 struct SliWrite<'a> {
     remain: &'a mut [u8],
     written: usize,
@@ -26,6 +26,9 @@ pub struct Scd41Reading {
     pub temp_c: f32,
     pub humi_pct: f32,
     pub co2_ppm: u16,
+    /// When this was measured, in microseconds since the Unix epoch, if the
+    /// node knew the time yet
+    pub timestamp_us: Option<u64>,
 }
 
 // ---
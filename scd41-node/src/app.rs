@@ -4,6 +4,7 @@ use embassy_nrf::gpio::Output;
 use scd41_node_icd::{
     GetUniqueIdEndpoint, RebootToBootloader, ENDPOINT_LIST, TOPICS_IN_LIST, TOPICS_OUT_LIST,
 };
+use bridge_icd::{link::NetworkKey, FragBuf, FRAG_BUF_SIZE, MAX_FRAG_SIZE};
 use postcard_rpc::{
     define_dispatch,
     server::{
@@ -59,10 +60,19 @@ pub struct TaskContext {
 /// BufStorage is the space used for receiving and sending frames. These values
 /// control the largest frames we can send or receive.
 pub type BufStorage = PacketBuffers<1024, 1024>;
+/// Largest fragment payload we offer the bridge, as much as fits in one ESB packet
+pub const FRAG_SIZE: usize = MAX_FRAG_SIZE;
+/// Largest message we can send or receive through the bridge
+pub const FRAG_CAPACITY: usize = FRAG_BUF_SIZE;
+/// FragBuf is where messages from the bridge are reassembled
+pub type AppFragBuf = FragBuf<FRAG_SIZE, FRAG_CAPACITY>;
+/// Network key our link key is derived from, set at build time with
+/// `CURACAO_NETWORK_KEY`
+pub const NETWORK_KEY: NetworkKey = NetworkKey::from_build_env(option_env!("CURACAO_NETWORK_KEY"));
 /// AppTx is the type of our sender, which is how we send information to the client
-pub type AppTx = EsbTx;
+pub type AppTx = EsbTx<FRAG_CAPACITY>;
 /// AppRx is the type of our receiver, which is how we receive information from the client
-pub type AppRx = EsbRx;
+pub type AppRx = EsbRx<FRAG_CAPACITY>;
 /// AppServer is the type of the postcard-rpc server we are using
 pub type AppServer = Server<AppTx, AppRx, WireRxBuf, MyApp>;
 